@@ -0,0 +1,165 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Deserialize;
+
+use super::auth::Principal;
+use super::er::Er;
+use super::metadata::TopicMetadata;
+
+/*
+ * Who may do what to which topics, given as [[acls]] in the credentials file :
+ *
+ *   [[acls]]
+ *   principal = "billing"              # a principal, ANON, or * for anyone
+ *   topic = "orders-*"                 # a topic, a prefix ending in *, or * for every topic
+ *   operations = ["produce", "consume"]
+ *
+ * produce is sending records. consume is following, starting from an offset, joining
+ * groups and committing. admin allows both. With a credentials file anything no rule
+ * allows is denied, without one every connection may do anything.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Produce,
+    Consume,
+    Admin,
+}
+impl Operation {
+    fn includes(self, op : Operation) -> bool {
+        self == op || self == Operation::Admin
+    }
+}
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operation::Produce => "produce",
+            Operation::Consume => "consume",
+            Operation::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub principal : String,
+    pub topic : String,
+    pub operations : Vec<Operation>,
+}
+
+pub struct Acl {
+    rules : Vec<Rule>,
+    open : bool, /* no credentials file, so nothing is checked */
+}
+impl Acl {
+    pub fn allow_all() -> Acl {
+        Acl { rules : Vec::new(), open : true }
+    }
+
+    pub fn new(rules : Vec<Rule>) -> Result<Acl, Er> {
+        for rule in &rules {
+            for pattern in [&rule.principal, &rule.topic].iter() {
+                if pattern.is_empty() || pattern.trim_end_matches('*').contains('*') {
+                    return Err(Er::BadConfig(format!("acl pattern {:?} should be a name, a prefix ending in * or *", pattern)));
+                }
+            }
+            if rule.operations.is_empty() {
+                return Err(Er::BadConfig(format!("acl for {} on {} allows no operations", rule.principal, rule.topic)));
+            }
+        }
+        Ok(Acl { rules, open : false })
+    }
+
+    pub fn allows(&self, principal : &Principal, topic : &str, op : Operation) -> bool {
+        let principal = principal.to_string();
+        self.open || self.rules.iter().any(|rule| {
+            matches(&rule.principal, &principal)
+                && matches(&rule.topic, topic)
+                && rule.operations.iter().any(|allowed| allowed.includes(op))
+        })
+    }
+
+    pub fn check(&self, principal : &Principal, topic : &str, op : Operation) -> Result<(), Er> {
+        match self.allows(principal, topic, op) {
+            true => Ok(()),
+            false => Err(Er::AccessDenied(principal.to_string(), op, String::from(topic))),
+        }
+    }
+
+    /*
+     * the topics of a metadata answer the principal may do something with. Topics asked for
+     * by name are denied, when listing every topic the others are left out
+     */
+    pub fn visible(&self, principal : &Principal, topics : Vec<TopicMetadata>, named : bool) -> Result<Vec<TopicMetadata>, Er> {
+        let mut visible = Vec::with_capacity(topics.len());
+        for topic in topics {
+            if self.allows(principal, &topic.name, Operation::Produce) || self.allows(principal, &topic.name, Operation::Consume) {
+                visible.push(topic);
+            } else if named {
+                return Err(Er::AccessDenied(principal.to_string(), Operation::Consume, topic.name))
+            }
+        }
+        Ok(visible)
+    }
+}
+
+/* exact, prefix* or * */
+fn matches(pattern : &str, value : &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => pattern == value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(principal : &str, topic : &str, operations : &[Operation]) -> Rule {
+        Rule { principal : String::from(principal), topic : String::from(topic), operations : operations.to_vec() }
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::new(vec![
+            rule("billing", "orders", &[Operation::Produce]),
+            rule("*", "public-*", &[Operation::Consume]),
+            rule("ops", "*", &[Operation::Admin]),
+        ]).unwrap();
+        let billing = Principal::Named(String::from("billing"));
+
+        assert!(acl.allows(&billing, "orders", Operation::Produce));
+        assert!(!acl.allows(&billing, "orders", Operation::Consume), "only what the rule lists");
+        assert!(!acl.allows(&billing, "orders-eu", Operation::Produce), "exact names are not prefixes");
+        assert!(acl.allows(&billing, "public-prices", Operation::Consume));
+        assert!(acl.allows(&Principal::Anonymous, "public-prices", Operation::Consume), "* is anyone");
+        assert!(!acl.allows(&Principal::Anonymous, "public-prices", Operation::Produce));
+        assert!(acl.allows(&Principal::Named(String::from("ops")), "orders", Operation::Produce), "admin is everything");
+
+        match acl.check(&Principal::Anonymous, "orders", Operation::Produce) {
+            Err(Er::AccessDenied(principal, op, topic)) => assert_eq!((principal.as_str(), op, topic.as_str()), ("ANON", Operation::Produce, "orders")),
+            x => assert!(false, "should be denied, got {:?}", x),
+        }
+        assert!(Acl::allow_all().allows(&Principal::Anonymous, "orders", Operation::Admin), "open without a credentials file");
+    }
+
+    #[test]
+    fn test_visible() {
+        let acl = Acl::new(vec![rule("billing", "orders", &[Operation::Produce])]).unwrap();
+        let billing = Principal::Named(String::from("billing"));
+        let topics = vec![
+            TopicMetadata { topic_id : 1, partitions : 1, name : String::from("orders") },
+            TopicMetadata { topic_id : 2, partitions : 1, name : String::from("payroll") },
+        ];
+
+        assert_eq!(acl.visible(&billing, topics.clone(), false).unwrap(), topics[..1].to_vec(), "others left out of a listing");
+        assert!(acl.visible(&billing, topics, true).is_err(), "asked for by name");
+    }
+
+    #[test]
+    fn test_bad_rules() {
+        assert!(Acl::new(vec![rule("bill*ing", "orders", &[Operation::Produce])]).is_err(), "* only at the end");
+        assert!(Acl::new(vec![rule("billing", "", &[Operation::Produce])]).is_err());
+        assert!(Acl::new(vec![rule("billing", "orders", &[])]).is_err());
+    }
+}
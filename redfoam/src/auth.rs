@@ -1,37 +1,310 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::str;
 
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
 use super::buff::Buff;
+use super::acl::{Acl, Rule};
+use super::config::Config;
 use super::er::Er;
+use super::scram;
+use super::trace;
+
+/*
+ * An Auth record names the topic and says who is connecting :
+ *
+ *   topic ; ANON
+ *   topic ; principal:secret
+ *
+ * ANON connects anonymously. It is all there is without a credentials file, and with one
+ * it is only accepted if the file allows it. Named principals are checked against the
+ * keys derived from their secrets in the file. They should prove they know their secret
+ * with the exchange in scram.rs, a secret sent in an Auth record is only accepted if
+ * the file allows plaintext :
+ *
+ *   allow_anonymous = false
+ *   allow_plaintext = false
+ *   [[principals]]
+ *   name = "billing"
+ *   secret = "scram-sha-256$10000$<salt hex>$<stored key hex>$<server key hex>"
+ *
+ * The keys are those of RFC 5802, which let the server check a proof but not make one,
+ * so whoever reads the file still can't pass as the principal.
+ *
+ * `redfoam hash-secret` reads a secret on stdin and prints the value for secret. On a
+ * TLS listener that verifies client certificates, ANON from a client that presented one
+ * is the principal its certificate subject names, see tls.rs. What principals may then
+ * do is given by [[acls]] rules in the same file, see acl.rs
+ */
+pub const ANONYMOUS: &str = "ANON";
+pub const HASH_SCHEME: &str = "scram-sha-256";
+pub const DEFAULT_ITERATIONS: u32 = 10_000;
+pub const SALT_BYTES: usize = 16;
+const HASH_BYTES: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Principal {
+    Anonymous,
+    Named(String),
+}
+impl Display for Principal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Principal::Anonymous => f.write_str(ANONYMOUS),
+            Principal::Named(name) => f.write_str(name),
+        }
+    }
+}
 
 pub struct Auth {
-    //client_id : u32,
+    pub topic : String, /* as named in the record, clients say which topic they want elsewhere */
+    pub principal : Principal,
 }
 impl Auth {
-    pub fn new(buff : &Buff) -> Result<Option<Self>, Er> {
+    /* certified is the principal a verified TLS client certificate names, if there was one */
+    pub fn new(buff : &Buff, credentials : &Credentials, certified : Option<String>) -> Result<Option<Self>, Er> {
 
         if buff.is_end_of_record() {
+            let (topic, token) = parse(buff.data())?;
+            let principal = match (token, certified) {
+                // the certificate has already proved who this is
+                (ANONYMOUS, Some(name)) => Principal::Named(name),
+                _ => credentials.authenticate(token)?,
+            };
+            trace!("authenticated {} for topic {}", principal, topic);
+
+            Ok(Some(Auth { topic : String::from(topic), principal }))
+        } else {
+            Ok(None)
+        }
+    }
+}
 
-            let content = str::from_utf8(buff.data()).unwrap();
-            println!("content : {} ", content);
+/* splits an Auth record into its topic and token, the token is everything after the first ; */
+fn parse(content : &[u8]) -> Result<(&str, &str), Er> {
+    let content = str::from_utf8(content).map_err(|_| Er::BadAuth)?;
+    let (topic, token) = content.split_once(';').ok_or(Er::BadAuth)?;
 
-            let mut authpart = content.split(";");
+    if token.is_empty() {
+        return Err(Er::BadAuth)
+    }
+    Ok((topic, token))
+}
 
-            let topic_name = String::from(authpart.next().unwrap());
-            println!("topic_name : {}", topic_name);
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    allow_anonymous : bool,
+    #[serde(default)]
+    allow_plaintext : bool,
+    #[serde(default)]
+    principals : Vec<CredentialEntry>,
+    #[serde(default)]
+    acls : Vec<Rule>,
+}
 
+#[derive(Deserialize)]
+struct CredentialEntry {
+    name : String,
+    secret : String,
+}
 
-            let auth_token = authpart.next().unwrap();
-            println!("auth_token : {}", auth_token);
+pub struct Credentials {
+    allow_anonymous : bool,
+    allow_plaintext : bool,
+    principals : HashMap<String, SecretHash>,
+    decoy_key : [u8; HASH_BYTES], /* makes salts for names that don't exist */
+    decoy_iterations : u32, /* and challenges them with the count the principals have */
+    pub acl : Acl,
+}
+impl Credentials {
+    /* without a credentials file every connection is anonymous */
+    pub fn anonymous() -> Credentials {
+        Credentials {
+            allow_anonymous : true,
+            allow_plaintext : false,
+            principals : HashMap::new(),
+            decoy_key : [0; HASH_BYTES], // there are no names to hide
+            decoy_iterations : DEFAULT_ITERATIONS,
+            acl : Acl::allow_all(),
+        }
+    }
 
-            if auth_token == "ANON" {
-                Ok(Some(Auth {}))
-            } else {
-                Err(Er::BadAuth)
+    pub fn from_config(config : &Config) -> Result<Credentials, Er> {
+        match &config.credentials_file {
+            Some(path) => Credentials::load(path),
+            None => Ok(Credentials::anonymous()),
+        }
+    }
+
+    pub fn load(path : &str) -> Result<Credentials, Er> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Er::BadConfig(format!("can't read credentials file {} : {}", path, e)))?;
+        Credentials::parse(&text)
+            .map_err(|e| Er::BadConfig(format!("{} : {}", path, e)))
+    }
+
+    pub fn parse(text : &str) -> Result<Credentials, Er> {
+        let file : CredentialsFile = toml::from_str(text)
+            .map_err(|e| Er::BadConfig(e.to_string()))?;
+
+        let mut principals = HashMap::new();
+        for entry in file.principals {
+            if entry.name.is_empty() || entry.name.contains(':') || entry.name == ANONYMOUS {
+                return Err(Er::BadConfig(format!("principal name {:?} should not be empty, {}, or have a ':'", entry.name, ANONYMOUS)));
             }
-        } else {
-            Ok(None)
+            let secret = SecretHash::parse(&entry.secret)
+                .map_err(|e| Er::BadConfig(format!("principal {} : {}", entry.name, e)))?;
+            if principals.insert(entry.name.clone(), secret).is_some() {
+                return Err(Er::BadConfig(format!("principal {} is listed more than once", entry.name)));
+            }
+        }
+
+        let mut decoy_key = [0u8; HASH_BYTES];
+        random_bytes(&mut decoy_key)
+            .map_err(|e| Er::BadConfig(format!("can't make a key : {}", e)))?;
+
+        // the count most principals were hashed with, so an unknown name looks like one of them
+        let mut counts = HashMap::new();
+        for secret in principals.values() {
+            *counts.entry(secret.iterations).or_insert(0) += 1;
         }
+        let decoy_iterations = counts.into_iter()
+            .max_by_key(|(iterations, n)| (*n, *iterations))
+            .map(|(iterations, _)| iterations)
+            .unwrap_or(DEFAULT_ITERATIONS);
+
+        Ok(Credentials {
+            allow_anonymous : file.allow_anonymous,
+            allow_plaintext : file.allow_plaintext,
+            principals,
+            decoy_key,
+            decoy_iterations,
+            acl : Acl::new(file.acls)?,
+        })
     }
+
+    pub fn authenticate(&self, token : &str) -> Result<Principal, Er> {
+        if token == ANONYMOUS {
+            return if self.allow_anonymous { Ok(Principal::Anonymous) } else { Err(Er::BadAuth) }
+        }
+
+        let (name, secret) = token.split_once(':').ok_or(Er::BadAuth)?;
+        if !self.allow_plaintext {
+            return Err(Er::BadAuth)
+        }
+        match self.principals.get(name) {
+            Some(hash) if hash.matches(secret.as_bytes()) => Ok(Principal::Named(String::from(name))),
+            Some(_) => Err(Er::BadAuth),
+            None => {
+                // as long as a wrong secret takes, so failures don't say which principals exist
+                SecretHash::new(secret.as_bytes(), &[0; SALT_BYTES], self.decoy_iterations);
+                Err(Er::BadAuth)
+            },
+        }
+    }
+
+    pub fn secret(&self, name : &str) -> Option<&SecretHash> {
+        self.principals.get(name)
+    }
+
+    /* the same salt every time for a name with no secret, as if it had one */
+    pub fn decoy_salt(&self, name : &str) -> Vec<u8> {
+        Sha256::new().chain_update(self.decoy_key).chain_update(name.as_bytes()).finalize()[..SALT_BYTES].to_vec()
+    }
+
+    pub fn decoy_iterations(&self) -> u32 {
+        self.decoy_iterations
+    }
+}
+
+/* a secret as it is kept in the credentials file, the keys SCRAM checks proofs with and never the secret itself */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretHash {
+    iterations : u32,
+    salt : Vec<u8>,
+    stored_key : [u8; HASH_BYTES],
+    server_key : [u8; HASH_BYTES],
+}
+impl SecretHash {
+    pub fn new(secret : &[u8], salt : &[u8], iterations : u32) -> SecretHash {
+        let (stored_key, server_key) = scram::keys(&salted(secret, salt, iterations));
+        SecretHash { iterations, salt : salt.to_vec(), stored_key, server_key }
+    }
+
+    /* reads scheme$iterations$salt$stored_key$server_key, as written by Display */
+    pub fn parse(text : &str) -> Result<SecretHash, Er> {
+        let bad = || Er::BadConfig(format!("secret should look like {}$<iterations>$<salt hex>$<stored key hex>$<server key hex>", HASH_SCHEME));
+
+        let parts : Vec<&str> = text.split('$').collect();
+        if parts.len() != 5 || parts[0] != HASH_SCHEME {
+            return Err(bad())
+        }
+        let iterations = parts[1].parse().map_err(|_| bad())?;
+        let salt = from_hex(parts[2]).ok_or_else(bad)?;
+        let stored_key = from_hex(parts[3]).and_then(|key| key.try_into().ok()).ok_or_else(bad)?;
+        let server_key = from_hex(parts[4]).and_then(|key| key.try_into().ok()).ok_or_else(bad)?;
+
+        if iterations == 0 || salt.is_empty() {
+            return Err(bad())
+        }
+        Ok(SecretHash { iterations, salt, stored_key, server_key })
+    }
+
+    pub fn salt(&self) -> &[u8] { &self.salt }
+    pub fn iterations(&self) -> u32 { self.iterations }
+    pub fn stored_key(&self) -> [u8; HASH_BYTES] { self.stored_key }
+    pub fn server_key(&self) -> [u8; HASH_BYTES] { self.server_key }
+
+    /* for secrets sent in the clear, which go through the same steps as a client's proof */
+    pub fn matches(&self, secret : &[u8]) -> bool {
+        constant_time_eq(&SecretHash::new(secret, &self.salt, self.iterations).stored_key, &self.stored_key)
+    }
+}
+impl Display for SecretHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}${}${}${}${}", HASH_SCHEME, self.iterations, to_hex(&self.salt), to_hex(&self.stored_key), to_hex(&self.server_key))
+    }
+}
+
+/* the secret hashed with its salt, which only clients work from */
+pub fn salted(secret : &[u8], salt : &[u8], iterations : u32) -> [u8; HASH_BYTES] {
+    let mut salted = [0u8; HASH_BYTES];
+    pbkdf2::pbkdf2_hmac::<Sha256>(secret, salt, iterations, &mut salted);
+    salted
+}
+
+pub fn random_salt() -> io::Result<[u8; SALT_BYTES]> {
+    let mut salt = [0u8; SALT_BYTES];
+    random_bytes(&mut salt)?;
+    Ok(salt)
+}
+
+pub fn random_bytes(bytes : &mut [u8]) -> io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(bytes)
+}
+
+/* looks at every byte whatever the contents, so the time taken says nothing about where they differ */
+pub fn constant_time_eq(a : &[u8], b : &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn to_hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text : &str) -> Option<Vec<u8>> {
+    text.as_bytes().chunks(2)
+        .map(|pair| match str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -40,7 +313,7 @@ mod tests {
 
     #[test]
     fn test_auth() {
-        let mut bstr : &[u8] = b"\x10\x00\x00\x00mytopic;ANON"; 
+        let mut bstr : &[u8] = b"\x10\x00\x00\x00mytopic;ANON";
         let mut b = Buff::new();
 
         match b.read_data(&mut bstr) {
@@ -50,18 +323,72 @@ mod tests {
 
         let result1 = b.read_u32();
         assert!(result1.is_some(), "should be able to read u32");
-        assert_eq!(result1, Some(16), "should be size of data (16 or x10)"); 
+        assert_eq!(result1, Some(16), "should be size of data (16 or x10)");
 
         b.rec_size=result1;
 
-        match Auth::new(&b) {
+        match Auth::new(&b, &Credentials::anonymous(), None) {
             Err(e) => assert!(false, "got error {}", e),
-            Ok(x) => assert!(x.is_some(), "check auth object exists"),
+            Ok(x) => {
+                assert!(x.is_some(), "check auth object exists");
+                let auth = x.unwrap();
+                assert_eq!((auth.topic.as_str(), auth.principal), ("mytopic", Principal::Anonymous));
+            },
         }
 
+        let credentials = Credentials::parse("allow_anonymous = false\n").unwrap();
+        assert!(Auth::new(&b, &credentials, None).is_err(), "anonymous is refused");
+        let auth = Auth::new(&b, &credentials, Some(String::from("billing"))).unwrap().unwrap();
+        assert_eq!(auth.principal, Principal::Named(String::from("billing")), "a client certificate names the principal");
+
 
     }
-}
 
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(b"orders;billing:pa;ss").unwrap(), ("orders", "billing:pa;ss"), "secrets can have a ;");
+        assert!(parse(b"orders").is_err(), "no token");
+        assert!(parse(b"orders;").is_err());
+        assert!(parse(b"orders;\xff").is_err(), "not utf-8");
+    }
+
+    #[test]
+    fn test_credentials() {
+        let hash = SecretHash::new(b"hunter2", b"0123456789abcdef", 1000);
+        assert_eq!(SecretHash::parse(&hash.to_string()).unwrap(), hash, "written the way it is read");
+        assert!(!hash.to_string().contains(&to_hex(&salted(b"hunter2", b"0123456789abcdef", 1000))), "clients work from the salted secret, so it isn't kept");
+
+        let entry = |name : &str, secret : &str| format!("[[principals]]\nname = \"{}\"\nsecret = \"{}\"\n", name, secret);
+        let credentials = Credentials::parse(&format!("allow_plaintext = true\n{}", entry("billing", &hash.to_string()))).unwrap();
+        assert_eq!(credentials.authenticate("billing:hunter2").unwrap(), Principal::Named(String::from("billing")));
+        assert!(credentials.authenticate("billing:hunter3").is_err());
+        assert!(credentials.authenticate("audit:hunter2").is_err(), "unknown principal");
+        assert!(credentials.authenticate("billing").is_err(), "no secret");
+        assert!(credentials.authenticate(ANONYMOUS).is_err(), "anonymous only when the file allows it");
+
+        let open = Credentials::parse("allow_anonymous = true\n").unwrap();
+        assert_eq!(open.authenticate(ANONYMOUS).unwrap(), Principal::Anonymous);
 
+        let challenged = Credentials::parse(&entry("billing", &hash.to_string())).unwrap();
+        assert!(challenged.authenticate("billing:hunter2").is_err(), "secrets in the clear only when the file allows them");
+        assert_eq!(challenged.secret("billing"), Some(&hash));
+        assert_eq!(challenged.decoy_salt("nobody"), challenged.decoy_salt("nobody"));
+        assert_ne!(challenged.decoy_salt("nobody"), challenged.decoy_salt("somebody"));
+        assert_eq!(challenged.decoy_iterations(), 1000, "unknown names are challenged like the principals");
+        assert_eq!(open.decoy_iterations(), DEFAULT_ITERATIONS);
 
+        assert!(Credentials::parse(&entry("billing", "hunter2")).is_err(), "secrets are only kept hashed");
+        assert!(Credentials::parse(&entry("bill:ing", &hash.to_string())).is_err());
+        assert!(Credentials::parse(&format!("{}{}", entry("billing", &hash.to_string()), entry("billing", &hash.to_string()))).is_err(), "duplicates");
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert_eq!(from_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("0ff"), None);
+    }
+}
@@ -9,6 +9,12 @@ use super::trace;
 
 pub const BUFF_SIZE:usize = 1024;
 
+// a buffer grows up to this, so a whole frame or record this size can be read in one piece
+pub const MAX_BUFF_SIZE:usize = 16 * 1024 * 1024;
+
+// servers only let a connection's buffer grow past this once it has authenticated
+pub const MAX_PRE_AUTH_SIZE:usize = 4 * 1024;
+
 macro_rules! make_read_fn {
     ($fn_name: ident, $fn_type:ty) => {
 
@@ -32,7 +38,8 @@ macro_rules! make_read_fn {
 
 pub struct Buff {
     pub  rec_size:Option<u32>,
-    pub buffer:[u8 ; BUFF_SIZE],
+    pub buffer:Vec<u8>,
+    max_size:usize,
     buff_pos:u32,
     rec_pos:u32,
     rec_upto:u32,
@@ -41,9 +48,14 @@ pub struct Buff {
 }
 impl Buff {
     pub fn new() -> Buff {
+        Buff::with_max(MAX_BUFF_SIZE)
+    }
+
+    pub fn with_max(max_size : usize) -> Buff {
         Buff {
             rec_size : None, // size of record excluding 4 byte size and any other fixed size headers
-            buffer : [0; BUFF_SIZE], // actual buffer containing data
+            buffer : vec![0; BUFF_SIZE], // actual buffer containing data, starts at BUFF_SIZE and grows to max_size
+            max_size : max_size.max(BUFF_SIZE),
             buff_pos : 0, // position of end of buffer (read from tcp) 
             rec_pos : 0,  // position of last byte processed in buffer
             rec_upto : 0, // no of bytes processed so far in record
@@ -66,8 +78,16 @@ impl Buff {
         }
     }
 
+    pub fn set_max(&mut self, max_size : usize) {
+        self.max_size = max_size.max(BUFF_SIZE);
+    }
+
     pub fn read_data(&mut self, stream: &mut impl Read) -> Result<usize, Er>  {
-        match stream.read(&mut self.buffer[self.buff_pos as usize..BUFF_SIZE]) {
+        if self.buff_pos as usize == self.buffer.len() {
+            self.make_room()?;
+        }
+
+        match stream.read(&mut self.buffer[self.buff_pos as usize..]) {
             Ok(size) => {
                 self.buff_pos += size as u32;
                 Ok(size)
@@ -81,6 +101,31 @@ impl Buff {
         }
     }
 
+    /* 
+     * a full buffer is compacted if some of it has been read, otherwise the record 
+     * being read does not fit and the buffer grows, up to max_size
+     */
+    fn make_room(&mut self) -> Result<(), Er> {
+        if self.rec_pos > 0 {
+            self.compact();
+        } else if self.buffer.len() < self.max_size {
+            let size = (self.buffer.len() * 2).min(self.max_size);
+            trace!("growing buffer to {} bytes", size);
+            self.buffer.resize(size, 0);
+        } else {
+            let rec_size = self.rec_size.map_or(self.buffer.len() as u64, |s| s as u64);
+            return Err(Er::RecordTooLarge(rec_size, self.max_size as u64))
+        }
+        Ok(())
+    }
+
+    /* moves what is left to be read to the start of the buffer */
+    fn compact(&mut self) {
+        self.buffer.copy_within(self.rec_pos as usize..self.buff_pos as usize, 0);
+        self.buff_pos -= self.rec_pos;
+        self.rec_pos = 0;
+    }
+
     pub fn check_seq(&mut self) -> Result<(),Er> {
         if !self.seq_checked {
 
@@ -99,6 +144,16 @@ impl Buff {
         }
     }
 
+    /* changes whenever anything is read from or added to the buffer */
+    pub fn position(&self) -> (u32, u32, u32) {
+        (self.buff_pos, self.rec_pos, self.rec_upto)
+    }
+
+    /* bytes of the current record not yet read, whether buffered or still to arrive */
+    pub fn remaining(&self) -> u32 {
+        self.rec_size.map_or(0, |s| s - self.rec_upto)
+    }
+
     pub fn has_data(&self) -> bool {
         self.read_to() as u32 - self.rec_pos > 0
     }
@@ -116,6 +171,15 @@ impl Buff {
         }
     }
 
+    /* next n bytes of the current record, without consuming them */
+    pub fn peek(&self, n : usize) -> Option<&[u8]> {
+        if self.read_to() - self.rec_pos as usize >= n {
+            Some(&self.buffer[self.rec_pos as usize .. self.rec_pos as usize + n])
+        } else {
+            None
+        }
+    }
+
     pub fn data(&self) -> &[u8] {
         trace!("[data()] {:?}", self);
         trace!("[read_to()] {}", self.read_to());
@@ -145,12 +209,20 @@ impl Buff {
         self.rec_pos += size;
         self.rec_upto += size;
 
-        // if all buffer is read reset to start 
+        // if all buffer is read reset to start, otherwise move what is left to the start
+        // so a record that runs past the end of the buffer has room to complete
         if self.buff_pos == self.rec_pos {
             self.buff_pos = 0;
             self.rec_pos = 0;
+            // a buffer grown for one large record goes back to its usual size once empty
+            if self.buffer.len() > BUFF_SIZE {
+                self.buffer.truncate(BUFF_SIZE);
+                self.buffer.shrink_to_fit();
+            }
         } else if self.buff_pos < self.rec_pos {
             panic!("record beyond end of buffer");
+        } else if self.rec_pos > 0 {
+            self.compact();
         }
 
         // if record is completely written reset
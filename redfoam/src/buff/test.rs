@@ -139,3 +139,72 @@ fn test_multirec() {
     assert_eq!(b.seq, 2, "sequence should be 2");
     
 }
+
+#[test]
+fn test_compact() {
+    let mut bstr : &[u8] = b"\x12\x00\x00\x00Eat My Shorts!\x28\x00\x00"; 
+    let mut b = Buff::new();
+
+    b.read_data(&mut bstr).expect("read data failure");
+    b.rec_size = b.read_u32();
+    assert_eq!(b.data(), b"Eat My Shorts!", "should be first message"); 
+    b.reset();
+
+    assert_eq!(b.buff_pos, 3, "partial size of second record should be moved to start of buffer");
+    assert_eq!(b.rec_pos, 0, "should be reading from start of buffer");
+    assert_eq!(b.read_u32(), None, "size is not complete yet");
+
+    let mut rest : &[u8] = b"\x00I have bumble bees in my back garden"; 
+    b.read_data(&mut rest).expect("read data failure");
+    assert_eq!(b.read_u32(), Some(40), "should be size of second record");
+}
+
+#[test]
+fn test_peek() {
+    let mut bstr : &[u8] = b"\x0e\x00\x00\x00\x03\x00keyvalue";
+    let mut b = Buff::new();
+    b.read_data(&mut bstr).unwrap();
+    b.rec_size = b.read_u32();
+
+    let key_len = b.read_u16().unwrap() as usize;
+    assert_eq!(b.peek(key_len), Some(&b"key"[..]));
+    assert_eq!(b.peek(20), None, "cannot peek past the end of the record");
+    assert_eq!(b.data(), b"keyvalue", "peek does not consume");
+}
+
+#[test]
+fn test_grow() {
+    let mut record = (3000u32 + 4).to_le_bytes().to_vec();
+    record.extend_from_slice(&[b'x'; 3000]);
+    let mut bstr : &[u8] = &record;
+    let mut b = Buff::new();
+
+    b.read_data(&mut bstr).expect("read data failure");
+    b.rec_size = b.read_u32();
+    while !b.is_end_of_record() {
+        b.read_data(&mut bstr).expect("read data failure");
+    }
+    assert!(b.buffer.len() >= 3004, "buffer should grow to hold the whole record");
+    assert_eq!(b.data(), &[b'x'; 3000][..]);
+
+    b.reset();
+    assert_eq!(b.buffer.len(), BUFF_SIZE, "buffer shrinks back once the record is read");
+}
+
+#[test]
+fn test_too_large() {
+    let mut record = (5000u32 + 4).to_le_bytes().to_vec();
+    record.extend_from_slice(&[b'x'; 5000]);
+    let mut bstr : &[u8] = &record;
+    let mut b = Buff::with_max(2048);
+
+    b.read_data(&mut bstr).expect("read data failure");
+    b.rec_size = b.read_u32();
+    b.read_data(&mut bstr).expect("compacting makes room for more of the record");
+    b.read_data(&mut bstr).expect("buffer grows to its maximum");
+
+    match b.read_data(&mut bstr) {
+        Err(Er::RecordTooLarge(5004, 2048)) => {},
+        x => assert!(false, "record over the maximum should be rejected, got {:?}", x.map_err(|e| e.to_string())),
+    }
+}
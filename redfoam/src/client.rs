@@ -3,10 +3,18 @@ use std::net::TcpStream;
 use std::collections::VecDeque;
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use super::buff::Buff;
-use super::tcp::{RecordType};
+use super::tcp::{RecordType, Start, ALL_PARTITIONS};
 use super::er::Er;
+use super::record;
+use super::record::Message;
+use super::metadata;
+use super::metadata::TopicMetadata;
+use super::scram::ScramClient;
+use super::tls::Stream;
+use super::hello::{Hello, FEATURE_KEYS, FEATURE_HEADERS, FEATURE_TAGGED_FEEDS};
 use super::trace;
 
 pub struct ReadClient {
@@ -62,6 +70,15 @@ impl ReadClient {
                     }
                 },
 
+                Some(RecordType::SegmentStart) if self.tcp_buff.read_u64().is_some() => {
+                    if let Some(mgs) = &mut messages {
+                        (*mgs).push_segment();
+                        record_type = None;
+                    } else {
+                        return Err(Er::NoConsumerStart)
+                    }
+                },
+
                 Some(RecordType::ConsumerStart) => {
                     if self.tcp_buff.is_end_of_record() {
                         let index_start;
@@ -85,6 +102,10 @@ impl ReadClient {
 
                 },
 
+                Some(RecordType::Error) if self.tcp_buff.is_end_of_record() => {
+                    return Err(server_error(&mut self.tcp_buff))
+                },
+
                 None => { break;},
                 _ => {},
             }
@@ -92,7 +113,7 @@ impl ReadClient {
             // write any new whole messages to output
             if let Some(mess) = &mut messages {
                 for m in &mut *mess {
-                    if let Err(e) = self.out.send(m) {
+                    if let Err(e) = self.out.send(m?.value) {
                         return Err(Er::FailedToReturnMessage(e))
                     }
                 }
@@ -158,30 +179,138 @@ pub fn get_reciever (_topics : String, url : String, auth : String) -> std::io::
 }
 */
 
+/* a record Listener::next() returned, with the topic and partition it is from */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+    pub topic_id : u32,
+    pub partition : u32,
+    pub message : Message,
+}
+
 pub struct Listener {
     client : Client,
-    messages : Messages,
+    logs : Vec<((u32, u32), Messages)>, /* records so far from each topic and partition followed */
+    next_log : usize,
+    tagged : bool, /* feed frames say which log they are from, otherwise there is only one */
+    in_group : bool,
+    member : Option<(u32, u32)>, /* generation and member id commits are sent for, when reading for a GroupListener */
+    auto_commit : Option<Duration>, /* commits what next() has returned at most this often, None to only commit when asked */
+    last_commit : Instant,
+    returned : Option<u64>,  /* index of the last record next() returned */
+    committed : Option<u64>, /* index of the last record committed */
 }
 
 impl Listener {
     pub fn new (topic : String, url : String, auth : String) -> Result<Listener, Er> {
-        trace!("creating listener client");
-        let mut client = Client::new(topic, url, auth).expect("cant create client");
-        let messages;
+        Listener::for_partition(topic, 0, url, auth)
+    }
+
+    /* listens to one partition of a partitioned topic, partition 0 is the whole of any other topic */
+    pub fn for_partition (topic : String, partition : u32, url : String, auth : String) -> Result<Listener, Er> {
+        let client = Client::connect(topic, url, auth)?;
+        let log = (client.topic_id(), partition);
+        Listener::following(client, &[log])
+    }
+
+    /* listens to every partition of each topic named, on the one connection */
+    pub fn for_topics (topics : &[&str], url : String, auth : String) -> Result<Listener, Er> {
+        let first = topics.first().ok_or(Er::TopicNotFound)?;
+        let mut client = Client::connect(String::from(*first), url, auth)?;
+        let logs : Vec<(u32, u32)> = client.metadata(topics)?.iter()
+            .map(|t| (t.topic_id, ALL_PARTITIONS))
+            .collect();
+        Listener::following(client, &logs)
+    }
+
+    /* listens to each (topic_id, partition) on the one connection, from the next record produced */
+    pub fn follow (logs : &[(u32, u32)], topic : String, url : String, auth : String) -> Result<Listener, Er> {
+        Listener::following(Client::connect(topic, url, auth)?, logs)
+    }
+
+    fn following (mut client : Client, logs : &[(u32, u32)]) -> Result<Listener, Er> {
+        trace!("creating listener client for {:?}", logs);
         client.set_blocking(false);
-        client.follow_topic(1)
+        client.follow(logs)
             .map_err(|e| Er::ClientTcpWrite(e))?;
 
-        client.set_blocking(true);
+        let first = logs.first().ok_or(Er::TopicNotFound)?;
+        Listener::wait_for_start(client, RecordType::ConsumerFollowTopics, *first)
+    }
 
+    /* listens to a partition from start, which may be records already in the topic */
+    pub fn starting_at (topic : String, partition : u32, start : Start, url : String, auth : String) -> Result<Listener, Er> {
+        trace!("creating listener client from {:?}", start);
+        let mut client = Client::connect(topic, url, auth)?;
+        let log = (client.topic_id(), partition);
+        client.set_blocking(false);
+        client.start(log.0, partition, start, None)
+            .map_err(Er::ClientTcpWrite)?;
+
+        Listener::wait_for_start(client, RecordType::ConsumerStart, log)
+    }
+
+    /* 
+     * listens to a partition as a member of group, carrying on after the group's last commit.
+     * start is only used by a group that has never committed
+     */
+    pub fn in_group (topic : String, partition : u32, group : &str, start : Start, url : String, auth : String) -> Result<Listener, Er> {
+        trace!("creating listener client in group {}", group);
+        let mut client = Client::connect(topic, url, auth)?;
+        let log = (client.topic_id(), partition);
+        client.set_blocking(false);
+        client.start(log.0, partition, start, Some(group))
+            .map_err(Er::ClientTcpWrite)?;
+
+        let mut listener = Listener::wait_for_start(client, RecordType::ConsumerStart, log)?;
+        listener.in_group = true;
+        Ok(listener)
+    }
+
+    /* commits every record next() has returned so far, so the group carries on after them */
+    pub fn commit(&mut self) -> Result<(), Er> {
+        if !self.in_group {
+            return Err(Er::NotInGroup)
+        }
+
+        if let Some(record_index) = self.returned.filter(|r| Some(*r) != self.committed) {
+            // acknowledged in the feed, which next() reads past, a failed commit comes back as an error from next()
+            self.client.commit(record_index, self.member)
+                .map_err(Er::ClientTcpWrite)?;
+            self.committed = Some(record_index);
+        }
+        self.last_commit = Instant::now();
+        Ok(())
+    }
+
+    /* commits from next() as records are returned, at most once per interval. None turns it off */
+    pub fn set_auto_commit(&mut self, interval : Option<Duration>) {
+        self.auto_commit = interval;
+    }
+
+    /* 
+     * the reply to a follow or start gives where in the topic the records sent after it begin.
+     * tagged follow replies give it for each log followed, otherwise it is for requested
+     */
+    fn wait_for_start (mut client : Client, reply_type : RecordType, requested : (u32, u32)) -> Result<Listener, Er> {
+        let mut logs = Vec::new();
+        client.set_blocking(true);
 
         loop {
             match client.next()? {
-                Some(RecordType::ConsumerFollowTopics) => {
-                    let index_offset = client.tcp_buff.read_u64().unwrap();
-                    let data_offset = client.tcp_buff.read_u64().unwrap();
-                    messages = Messages::new(index_offset, data_offset);
-                    trace!("message list created at index : {}, data : {} from buffer {:?}", index_offset, data_offset, client.tcp_buff);
+                Some(record_type) if record_type as u8 == reply_type as u8 => {
+                    let listed = reply_type as u8 == RecordType::ConsumerFollowTopics as u8 && client.feeds_tagged();
+                    loop {
+                        let log = match listed {
+                            true => (client.tcp_buff.read_u32().ok_or(Er::FailedToReadDataStart)?, client.tcp_buff.read_u32().ok_or(Er::FailedToReadDataStart)?),
+                            false => requested,
+                        };
+                        let index_offset = client.tcp_buff.read_u64().ok_or(Er::FailedToReadDataStart)?;
+                        let data_offset = client.tcp_buff.read_u64().ok_or(Er::FailedToReadDataStart)?;
+                        trace!("message list for {:?} created at index : {}, data : {}", log, index_offset, data_offset);
+                        logs.push((log, Messages::new(index_offset, data_offset)));
+
+                        if !listed || !client.tcp_buff.has_data() { break; }
+                    }
                     client.reset();
                     break;
                 }, 
@@ -190,72 +319,447 @@ impl Listener {
             }
         }
         client.set_blocking(false);
-        Ok(Listener { client, messages })
+        Ok(Listener {
+            tagged : client.feeds_tagged(),
+            client,
+            logs,
+            next_log : 0,
+            in_group : false,
+            member : None,
+            auto_commit : None,
+            last_commit : Instant::now(),
+            returned : None,
+            committed : None,
+        })
+    }
+
+    /* topics and partitions this listener follows */
+    pub fn logs(&self) -> Vec<(u32, u32)> {
+        self.logs.iter().map(|(log, _)| *log).collect()
+    }
+
+    /* which log's records the feed frame being read holds */
+    fn feed_log(&mut self) -> Result<usize, Er> {
+        match self.tagged {
+            true => {
+                let topic_id = self.client.tcp_buff.read_u32().ok_or(Er::IsNone)?;
+                let partition = self.client.tcp_buff.read_u32().ok_or(Er::IsNone)?;
+                self.logs.iter().position(|(log, _)| *log == (topic_id, partition))
+                    .ok_or(Er::PartitionNotFound(topic_id, partition))
+            },
+            false => Ok(0),
+        }
     }
 
-    pub fn next(&mut self) -> Option<Vec<u8>> {
+    /* next whole record from any log, logs take turns so a busy one can't starve the others */
+    fn next_message(&mut self) -> Result<Option<Received>, Er> {
+        let count = self.logs.len();
+        for n in 0..count {
+            let i = (self.next_log + n) % count;
+            let ((topic_id, partition), messages) = &mut self.logs[i];
+            if let Some(message) = messages.next().transpose()? {
+                self.next_log = i + 1;
+                self.returned = Some(messages.index_offset - 1);
+                return Ok(Some(Received { topic_id : *topic_id, partition : *partition, message }))
+            }
+        }
+        Ok(None)
+    }
+
+    // named like Client::next, it can fail so it isn't an Iterator
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Received>, Er> {
         loop {
-            match self.client.next().expect("cant read next from client") {
+            match self.client.next()? {
                     Some(RecordType::DataFeed) => { 
-                        self.messages.push_data(self.client.data()); 
+                        let i = self.feed_log()?;
+                        self.logs[i].1.push_data(self.client.data()); 
                         trace!("pushed data {} bytes", self.client.data().len());
                         self.client.reset();
                     },
                     Some(RecordType::IndexFeed) => { 
-                        let idx = self.client.tcp_buff.read_u64();
-                        self.messages.push_index(idx.unwrap()); 
-                        trace!("pushed index {:?}", idx);
+                        let i = self.feed_log()?;
+                        while let Some(idx) = self.client.tcp_buff.read_u64() {
+                            self.logs[i].1.push_index(idx); 
+                            trace!("pushed index {:?}", idx);
+                        }
+                        self.client.reset();
+                    },
+                    Some(RecordType::Ack) => {
+                        trace!("commit acknowledged {:?}", self.client.tcp_buff.read_u64());
+                        self.client.reset();
+                    },
+                    Some(RecordType::SegmentStart) => { 
+                        let i = self.feed_log()?;
+                        self.logs[i].1.push_segment(); 
+                        trace!("pushed segment start {:?}", self.client.tcp_buff.read_u64());
                         self.client.reset();
                     },
                     Some(record_type) =>  {
                         trace!("client unexpectedly got record_type {}", record_type as u8);
-                        unimplemented!();
+                        return Err(Er::UnexpectedRecordType)
                     },
                     None => { break;}
             }
         }
 
-        self.messages.next()
+        let message = self.next_message()?;
+
+        if let Some(interval) = self.auto_commit {
+            if self.in_group && self.last_commit.elapsed() >= interval {
+                self.commit()?;
+            }
+        }
+        Ok(message)
+    }
+}
+
+
+/* 
+ * a member of a consumer group, reading whichever of the topic's partitions the consumer
+ * server assigns it. Each partition is read by a Listener in the group, so is picked up
+ * after the group's last commit, and partitions taken away are committed before they go
+ */
+pub struct GroupListener {
+    coordinator : Client, /* connection the group membership is kept on */
+    topic : String,
+    group : String,
+    start : Start,
+    url : String,
+    auth : String,
+    generation : u32,
+    member : u32, /* id the server knows this member by */
+    listeners : Vec<(u32, Listener)>,
+    next_listener : usize,
+    auto_commit : Option<Duration>,
+    heartbeat : Duration,
+    last_heartbeat : Instant,
+}
+
+impl GroupListener {
+    /* joins group, returning once the first assignment has arrived. start is for partitions the group has never committed */
+    pub fn join (topic : String, group : &str, start : Start, session_timeout : Duration, url : String, auth : String) -> Result<GroupListener, Er> {
+        trace!("joining group {}", group);
+        let mut coordinator = Client::connect(topic.clone(), url.clone(), auth.clone())?;
+        let topic_id = coordinator.topic_id();
+        coordinator.join_group(topic_id, group, session_timeout)
+            .map_err(Er::ClientTcpWrite)?;
+
+        let mut member = GroupListener {
+            coordinator,
+            topic,
+            group : String::from(group),
+            start,
+            url,
+            auth,
+            generation : 0,
+            member : 0,
+            listeners : Vec::new(),
+            next_listener : 0,
+            auto_commit : None,
+            heartbeat : session_timeout / 3, // a couple can go missing before the server gives up on us
+            last_heartbeat : Instant::now(),
+        };
+
+        member.coordinator.set_blocking(true);
+        while !member.read_assignment()? {}
+        member.coordinator.set_blocking(false);
+        Ok(member)
+    }
+
+    /* counts up each time the group's partitions are divided up again */
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /* partitions this member is reading */
+    pub fn assignment(&self) -> Vec<u32> {
+        self.listeners.iter().map(|(p, _)| *p).collect()
+    }
+
+    /* next record from any assigned partition */
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Received>, Er> {
+        while self.read_assignment()? {}
+
+        if self.last_heartbeat.elapsed() >= self.heartbeat {
+            self.coordinator.heartbeat()
+                .map_err(Er::ClientTcpWrite)?;
+            self.last_heartbeat = Instant::now();
+        }
+
+        // partitions take turns, so a busy one can't starve the others
+        let count = self.listeners.len();
+        for n in 0..count {
+            let i = (self.next_listener + n) % count;
+            if let Some(received) = self.listeners[i].1.next()? {
+                self.next_listener = i + 1;
+                return Ok(Some(received))
+            }
+        }
+        Ok(None)
+    }
+
+    /* commits every record next() has returned so far, on all assigned partitions */
+    pub fn commit(&mut self) -> Result<(), Er> {
+        for (_, listener) in self.listeners.iter_mut() {
+            listener.commit()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_auto_commit(&mut self, interval : Option<Duration>) {
+        self.auto_commit = interval;
+        for (_, listener) in self.listeners.iter_mut() {
+            listener.set_auto_commit(interval);
+        }
+    }
+
+    /* handles an Assignment if one has arrived, returning whether it did */
+    fn read_assignment(&mut self) -> Result<bool, Er> {
+        match self.coordinator.next()? {
+            Some(RecordType::Assignment) => {
+                let generation = self.coordinator.tcp_buff.read_u32().ok_or(Er::IsNone)?;
+                self.member = self.coordinator.tcp_buff.read_u32().ok_or(Er::IsNone)?;
+                let mut partitions = Vec::new();
+                while let Some(p) = self.coordinator.tcp_buff.read_u32() {
+                    partitions.push(p);
+                }
+                self.coordinator.reset();
+                trace!("group {} generation {} assigns {:?}", self.group, generation, partitions);
+                self.assign(generation, partitions)?;
+                Ok(true)
+            },
+            Some(record_type) => {
+                trace!("group member unexpectedly got record_type {}", record_type as u8);
+                Err(Er::UnexpectedRecordType)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /*
+     * stops reading partitions taken away and starts on those given, then acknowledges the
+     * assignment. Partitions taken away are only handed on once the acknowledgement has
+     * committed what was read of them here, which whoever takes over carries on from
+     */
+    fn assign(&mut self, generation : u32, partitions : Vec<u32>) -> Result<(), Er> {
+        self.generation = generation;
+
+        let given_up : Vec<(u32, u64)> = self.listeners.iter()
+            .filter(|(p, _)| !partitions.contains(p))
+            .filter_map(|(p, listener)| listener.returned.map(|r| (*p, r)))
+            .collect();
+        self.listeners.retain(|(p, _)| partitions.contains(p));
+
+        for p in partitions {
+            if !self.listeners.iter().any(|(assigned, _)| *assigned == p) {
+                let mut listener = Listener::in_group(self.topic.clone(), p, &self.group, self.start, self.url.clone(), self.auth.clone())?;
+                listener.member = Some((generation, self.member));
+                listener.set_auto_commit(self.auto_commit);
+                self.listeners.push((p, listener));
+            }
+        }
+
+        self.coordinator.assignment_ack(generation, &given_up)
+            .map_err(Er::ClientTcpWrite)
     }
 }
 
 
 pub struct Client {
-    io : TcpStream,
+    io : Stream,
     seq : u8,
     pub tcp_buff : Buff,
+    hello : Option<Hello>, /* server's answer to our Hello, once it has been read */
+    topic_id : u32, /* of the topic named when connecting */
+    blocking : bool,
 }
 impl Client {
     pub fn new (topic : String, url : String, auth : String) -> std::io::Result<Client> {
+        Client::connect(topic, url, auth).map_err(|e| match e {
+            Er::ClientTcpWrite(e) | Er::ClientTcpRead(e) => e,
+            Er::ServerError(code, message) if code == Er::BadAuth.code() => std::io::Error::new(std::io::ErrorKind::PermissionDenied, message),
+            Er::BadAuth => std::io::Error::new(std::io::ErrorKind::PermissionDenied, Er::BadAuth.to_string()),
+            Er::TlsError(message) => std::io::Error::new(std::io::ErrorKind::InvalidInput, message),
+            e => std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()),
+        })
+    }
+
+    /* 
+     * connects and looks up topic, which records are sent to and followed from. url is
+     * host:port, or tls://host:port?ca=<pem file> for a TLS listener, see tls.rs
+     */
+    pub fn connect (topic : String, url : String, auth : String) -> Result<Client, Er> {
+        let mut client = Client::open(&topic, url, auth)?;
+
+        client.topic_id = client.metadata(&[&topic])?
+            .first().map(|t| t.topic_id)
+            .ok_or_else(|| Er::UnknownTopic(topic.clone()))?;
+        trace!("topic {} has id {}", topic, client.topic_id);
+        Ok(client)
+    }
+
+    /* auth is ANON, or principal:secret, which is proved with a challenge rather than sent */
+    fn open (topic : &str, url : String, auth : String) -> Result<Client, Er> {
+
+        let mut stream = Stream::connect(&url)?;
 
-        let mut stream = TcpStream::connect(url)?;
-        let message = format!("{};{}",topic, auth);
+        // the server answers the hello, which is read along with whatever follows it
+        let seq : u8 = 0;
+        stream.write_all(&Hello::local(0).request_frame(seq))
+            .map_err(Er::ClientTcpWrite)?;
+
+        let mut client = Client { io : stream, seq : seq.wrapping_add(1), tcp_buff : Buff::new(), hello : None, topic_id : 0, blocking : true };
+        match auth.split_once(':') {
+            Some((name, secret)) => client.scram(topic, name, secret)?,
+            None => client.send_auth(topic, &auth)
+                .map_err(Er::ClientTcpWrite)?,
+        }
+        Ok(client)
+    }
+
+    fn send_auth (&mut self, topic : &str, token : &str) -> std::io::Result<()> {
+        let message = format!("{};{}",topic, token);
         
         let size = 4 + 1 + 1 + message.len() as u32;
         let mess_type : u8 = 1; // 1 = auth
-        let seq : u8 = 0;
 
-        stream.write(&size.to_le_bytes())?;
-        stream.write(&[seq])?;
-        stream.write(&[mess_type])?;
-        stream.write(message.as_bytes())?;
+        self.io.write_all(&size.to_le_bytes())?;
+        self.io.write_all(&[self.seq])?;
+        self.io.write_all(&[mess_type])?;
+        self.io.write_all(message.as_bytes())?;
 
-        Ok (Client { io : stream, seq : seq + 1 , tcp_buff : Buff::new() })
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /* proves this client knows the secret, and that the server does too. Waits for the exchange to finish */
+    fn scram (&mut self, topic : &str, name : &str, secret : &str) -> Result<(), Er> {
+        let (mut scram, start) = ScramClient::start(self.seq, topic, name, secret)?;
+        self.io.write_all(&start)
+            .map_err(Er::ClientTcpWrite)?;
+        self.seq = self.seq.wrapping_add(1);
+
+        loop {
+            match self.next()? {
+                Some(RecordType::ScramChallenge) => {
+                    let proof = scram.proof(self.seq, &mut self.tcp_buff)?;
+                    self.reset();
+                    self.io.write_all(&proof)
+                        .map_err(Er::ClientTcpWrite)?;
+                    self.seq = self.seq.wrapping_add(1);
+                },
+                Some(RecordType::ScramOutcome) => {
+                    let outcome = scram.check_outcome(&mut self.tcp_buff);
+                    self.reset();
+                    return outcome
+                },
+                Some(_) => return Err(Er::UnexpectedRecordType),
+                None => (),
+            }
+        }
+    }
+
+    pub fn topic_id(&self) -> u32 {
+        self.topic_id
+    }
+
+    /* ids and partition counts of the topics named, or of every topic if none are. Waits for the answer */
+    pub fn metadata(&mut self, names : &[&str]) -> Result<Vec<TopicMetadata>, Er> {
+        self.io.write_all(&metadata::request_frame(self.seq, names))
+            .map_err(Er::ClientTcpWrite)?;
+        self.seq = self.seq.wrapping_add(1);
+
+        loop {
+            match self.next()? {
+                Some(RecordType::Metadata) => {
+                    let topics = metadata::from_reply(&mut self.tcp_buff)?;
+                    self.reset();
+                    return Ok(topics)
+                },
+                Some(_) => return Err(Er::UnexpectedRecordType),
+                None => (),
+            }
+        }
+    }
+
+    /* version, features and record size limit agreed with the server */
+    pub fn server_hello(&self) -> Option<&Hello> {
+        self.hello.as_ref()
+    }
+
+    /* unlike other features, feeds are only tagged once the server has said so */
+    fn feeds_tagged(&self) -> bool {
+        match &self.hello {
+            Some(hello) => hello.supports(FEATURE_TAGGED_FEEDS),
+            None => false,
+        }
+    }
+
+    /* features are assumed until the server says otherwise */
+    fn server_supports(&self, feature : u32) -> bool {
+        match &self.hello {
+            Some(hello) => hello.supports(feature),
+            None => true,
+        }
+    }
+
+    fn check_size(&self, size : usize) -> std::io::Result<()> {
+        match &self.hello {
+            Some(hello) if size as u64 > hello.max_record_bytes => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                Er::RecordTooLarge(size as u64, hello.max_record_bytes).to_string())),
+            _ => Ok(()),
+        }
     }
 
     pub fn send(&mut self, content : String) -> std::io::Result<()> {
 
+        self.check_size(content.len())?;
+
         let len : u32 = 4 + 1 + 1 + 4 + content.len() as u32;
         let mess_type : u8 = 2; // 1 = producer
-        let topic_id : u32 = 1;
+        let topic_id : u32 = self.topic_id;
 
-        self.io.write(&len.to_le_bytes())?;
-        self.io.write(&[self.seq])?;
-        self.io.write(&[mess_type])?;
-        self.io.write(&topic_id.to_le_bytes())?;
+        self.io.write_all(&len.to_le_bytes())?;
+        self.io.write_all(&[self.seq])?;
+        self.io.write_all(&[mess_type])?;
+        self.io.write_all(&topic_id.to_le_bytes())?;
+
+        self.io.write_all(content.as_bytes())?;
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /* keyed records let compacted topics keep just the latest value for each key, an empty content deletes the key */
+    pub fn send_keyed(&mut self, key : &[u8], content : &[u8]) -> std::io::Result<()> {
+        self.send_message(&Message::new(content).with_key(key))
+    }
+
+    /* sends a record with its key and headers, consumers get it back whole from Listener::next() */
+    pub fn send_message(&mut self, message : &Message) -> std::io::Result<()> {
+
+        if (message.key.is_some() && !self.server_supports(FEATURE_KEYS)) || (!message.headers.is_empty() && !self.server_supports(FEATURE_HEADERS)) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "server does not support keys or headers"))
+        }
+
+        let body = message.body()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        self.check_size(body.len())?;
+
+        let len : u32 = 4 + 1 + 1 + 4 + 1 + body.len() as u32;
+        let mess_type : u8 = RecordType::ProducerRecord as u8;
+        let topic_id : u32 = self.topic_id;
 
-        self.io.write(content.as_bytes())?;
-        self.seq = self.seq % 255 + 1;
+        self.io.write_all(&len.to_le_bytes())?;
+        self.io.write_all(&[self.seq])?;
+        self.io.write_all(&[mess_type])?;
+        self.io.write_all(&topic_id.to_le_bytes())?;
+        self.io.write_all(&[message.flags()])?;
+
+        self.io.write_all(&body)?;
+        self.seq = self.seq.wrapping_add(1);
 
         Ok(())
     }
@@ -270,31 +774,198 @@ impl Client {
         self.io.write(&[mess_type])?;
         self.io.write(&topic_id.to_le_bytes())?;
 
-        self.seq = self.seq % 255 + 1;
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    pub fn follow_partition(&mut self, topic_id : u32, partition : u32) -> std::io::Result<()> {
+        self.follow(&[(topic_id, partition)])
+    }
+
+    /* 
+     * follows each (topic_id, partition), ALL_PARTITIONS for every partition of a topic.
+     * following more than one log needs the server to have agreed to tagged feeds
+     */
+    pub fn follow(&mut self, logs : &[(u32, u32)]) -> std::io::Result<()> {
+        let len : u32 = 4 + 1 + 1 + 8 * logs.len() as u32;
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.seq);
+        frame.push(RecordType::ConsumerFollowTopics as u8);
+        for (topic_id, partition) in logs {
+            frame.extend_from_slice(&topic_id.to_le_bytes());
+            frame.extend_from_slice(&partition.to_le_bytes());
+        }
+        self.io.write_all(&frame)?;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /* 
+     * asks a consumer server for a partition from start, the reply is a ConsumerStart record.
+     * members of a group start after the group's last commit instead, if it has one
+     */
+    pub fn start(&mut self, topic_id : u32, partition : u32, start : Start, group : Option<&str>) -> std::io::Result<()> {
+        let (mode, value) = start.to_wire();
+        let group = group.unwrap_or("").as_bytes();
+
+        let len : u32 = 4 + 1 + 1 + 4 + 4 + 1 + 8 + group.len() as u32;
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.seq);
+        frame.push(RecordType::ConsumerStart as u8);
+        frame.extend_from_slice(&topic_id.to_le_bytes());
+        frame.extend_from_slice(&partition.to_le_bytes());
+        frame.push(mode);
+        frame.extend_from_slice(&value.to_le_bytes());
+        frame.extend_from_slice(group);
+        self.io.write_all(&frame)?;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /* joins group on topic_id, the server replies with an Assignment now and whenever the group changes */
+    pub fn join_group(&mut self, topic_id : u32, group : &str, session_timeout : Duration) -> std::io::Result<()> {
+        let len : u32 = 4 + 1 + 1 + 4 + 4 + group.len() as u32;
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.seq);
+        frame.push(RecordType::JoinGroup as u8);
+        frame.extend_from_slice(&topic_id.to_le_bytes());
+        frame.extend_from_slice(&(session_timeout.as_millis() as u32).to_le_bytes());
+        frame.extend_from_slice(group.as_bytes());
+        self.io.write_all(&frame)?;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /* says an Assignment has been applied, committing record_index for each partition given up */
+    pub fn assignment_ack(&mut self, generation : u32, given_up : &[(u32, u64)]) -> std::io::Result<()> {
+        let len : u32 = 4 + 1 + 1 + 4 + 12 * given_up.len() as u32;
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.seq);
+        frame.push(RecordType::AssignmentAck as u8);
+        frame.extend_from_slice(&generation.to_le_bytes());
+        for (partition, record_index) in given_up {
+            frame.extend_from_slice(&partition.to_le_bytes());
+            frame.extend_from_slice(&record_index.to_le_bytes());
+        }
+        self.io.write_all(&frame)?;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /* keeps a group member alive while it has nothing else to send */
+    pub fn heartbeat(&mut self) -> std::io::Result<()> {
+        let len : u32 = 4 + 1 + 1;
+        self.io.write_all(&len.to_le_bytes())?;
+        self.io.write_all(&[self.seq, RecordType::Heartbeat as u8])?;
+
+        self.seq = self.seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /*
+     * commits record_index as processed by the group this client started in, the reply is an Ack.
+     * member is the generation and member id a group member was given the partition in, None
+     * to commit from outside the group's membership
+     */
+    pub fn commit(&mut self, record_index : u64, member : Option<(u32, u32)>) -> std::io::Result<()> {
+        let len : u32 = 4 + 1 + 1 + 8 + if member.is_some() { 8 } else { 0 };
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.seq);
+        frame.push(RecordType::ConsumerCommit as u8);
+        frame.extend_from_slice(&record_index.to_le_bytes());
+        if let Some((generation, member)) = member {
+            frame.extend_from_slice(&generation.to_le_bytes());
+            frame.extend_from_slice(&member.to_le_bytes());
+        }
+        self.io.write_all(&frame)?;
+
+        self.seq = self.seq.wrapping_add(1);
 
         Ok(())
     }
 
     pub fn next(&mut self) -> Result<Option<RecordType>, Er> {
-        let size_read = self.tcp_buff.read_data(&mut self.io)?;
-        trace!("size_read {}", size_read);
         if self.tcp_buff.rec_size.is_none() { self.tcp_buff.rec_size = self.tcp_buff.read_u32(); }
+
+        // a frame already in the buffer is handled before reading, which blocks on blocking sockets
+        if !self.tcp_buff.is_end_of_record() {
+            let size_read = self.tcp_buff.read_data(&mut self.io)?;
+            trace!("size_read {}", size_read);
+            if size_read == 0 && self.blocking {
+                // blocking reads only come back empty once the server has closed the connection
+                return Err(Er::ClientTcpRead(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+            }
+            if self.tcp_buff.rec_size.is_none() { self.tcp_buff.rec_size = self.tcp_buff.read_u32(); }
+        }
         trace!("client : buffer {:?}", self.tcp_buff);
 
         if self.tcp_buff.is_end_of_record() {
             let record_type = self.tcp_buff.read_u8().unwrap();
             trace!("rt {}, from buffer {:?} ", record_type, self.tcp_buff);
             //let record_type = self.tcp_buff.read_u8().map(|r| r.into());
-            Ok(Some(RecordType::from(record_type)))
+            match RecordType::from(record_type) {
+                RecordType::Error => {
+                    let e = server_error(&mut self.tcp_buff);
+                    self.tcp_buff.reset();
+                    Err(e)
+                },
+                RecordType::Hello => {
+                    self.hello = Some(Hello::from_reply(&mut self.tcp_buff)?);
+                    trace!("server hello {:?}", self.hello);
+                    self.tcp_buff.reset();
+                    Ok(None)
+                },
+                record_type => Ok(Some(record_type)),
+            }
         }
         else {
             Ok(None)
         }
     }
 
+    /* 
+     * waits for the server to store the next record sent, returning its index. 
+     * a rejected record comes back as Er::ServerError
+     */
+    pub fn ack(&mut self) -> Result<u64, Er> {
+        loop {
+            match self.next()? {
+                Some(RecordType::Ack) => {
+                    let _seq = self.tcp_buff.read_u8();
+                    let idx = self.tcp_buff.read_u64().ok_or(Er::IsNone)?;
+                    self.reset();
+                    return Ok(idx)
+                },
+                Some(_) => return Err(Er::UnexpectedRecordType),
+                None => {},
+            }
+        }
+    }
+
     pub fn set_blocking (&mut self, is_blocking : bool) {
         trace!("set_blocking {}", is_blocking);
         self.io.set_nonblocking(!is_blocking).expect("set_nonblocking call failed");
+        self.blocking = is_blocking;
+    }
+
+    /* largest frame the client will buffer, larger ones fail with Er::RecordTooLarge */
+    pub fn set_max_frame_size (&mut self, max_size : usize) {
+        self.tcp_buff.set_max(max_size);
     }
 
     pub fn data(&self) -> &[u8] { self.tcp_buff.data() }
@@ -303,9 +974,22 @@ impl Client {
 }
 
 
+/* reads an Error record, [code u16][seq u8][message], once all of it is in the buffer */
+pub fn server_error(buff : &mut Buff) -> Er {
+    let code = buff.read_u16().unwrap_or(0);
+    let _seq = buff.read_u8();
+    let message = String::from_utf8_lossy(buff.data()).into_owned();
+    Er::ServerError(code, message)
+}
+
+enum IndexEntry {
+    End(u64),   // data offset at the end of a record
+    Segment,    // following entries are offsets into a new segment
+}
+
 pub struct Messages {
     data : VecDeque<u8>,
-    index : VecDeque<u64>,
+    index : VecDeque<IndexEntry>,
     data_offset : u64,
     index_offset : u64,
 }
@@ -313,7 +997,7 @@ pub struct Messages {
 
 
 impl Messages {
-    fn new(index_offset : u64, data_offset : u64) -> Messages {
+    pub fn new(index_offset : u64, data_offset : u64) -> Messages {
         trace!("new messages index at {}, data at {}", index_offset, data_offset);
         Messages {
             data : VecDeque::new(),
@@ -323,35 +1007,64 @@ impl Messages {
         }
     }
 
-    fn push_data(&mut self, input_data: &[u8]) {
+    pub fn push_data(&mut self, input_data: &[u8]) {
         for c in input_data {
             self.data.push_back(*c);
         }
     }
 
-    fn push_index(&mut self, input_index: u64) {
-        self.index.push_back(input_index);
+    pub fn push_index(&mut self, input_index: u64) {
+        self.index.push_back(IndexEntry::End(input_index));
+    }
+
+    pub fn push_segment(&mut self) {
+        self.index.push_back(IndexEntry::Segment);
+    }
+
+    /* one past the record index of the last record next() returned */
+    pub fn next_index(&self) -> u64 {
+        self.index_offset
     }
 }
 
 impl Iterator for Messages {
-    type Item = Vec<u8>;
+    type Item = Result<Message, Er>;
 
     fn next(&mut self) -> Option<Self::Item> {
 
-        if let Some(idx) = self.index.pop_front() {
+        loop {
+            match self.index.front() {
+                Some(IndexEntry::Segment) => {
+                    trace!("messages : new segment, data offsets restart from zero");
+                    self.index.pop_front();
+                    self.data_offset = 0;
+                },
+                Some(IndexEntry::End(idx)) if *idx == self.data_offset => {
+                    // record removed by compaction, it keeps its index entry but has no data
+                    self.index.pop_front();
+                    self.index_offset += 1;
+                },
+                _ => break,
+            }
+        }
+
+        if let Some(IndexEntry::End(idx)) = self.index.pop_front() {
             trace!("messages : last read {}, now read upto {}", self.data_offset, idx);
-            let size = (idx - self.data_offset) as usize;
+            let size = match idx.checked_sub(self.data_offset) {
+                Some(size) => size as usize,
+                None => return Some(Err(Er::BadRecordFormat(format!("index entry {} is before data offset {}", idx, self.data_offset)))),
+            };
             trace!("messages : attempting to pop {} bytes from queue of size {}", size, self.data.len());
             if self.data.len() >= size {
                 let data = self.data.drain(..size).collect::<Vec<_>>();
                 trace!("messages: retrieved data from queue, new size : {}", self.data.len());
                 self.data_offset = idx;
                 self.index_offset += 1;
-                Some(data)
+                Some(record::open_record(data)
+                    .and_then(|(flags, body)| Message::from_body(flags, body)))
             } else {
                 trace!("not enough data yet - can't read so putting index back for next call");
-                self.index.push_front(idx); 
+                self.index.push_front(IndexEntry::End(idx)); 
                 None
             }
 
@@ -25,8 +25,127 @@ fn test_queue () {
     let result1 = message1.to_vec();
     let result2 = message2.to_vec();
     
-    assert_eq!(q.next(), None);
-    assert_eq!(q.next(), Some(result1));
-    assert_eq!(q.next(), Some(result2));
-    assert_eq!(q.next(), None);
+    assert_eq!(q.next().and_then(|m| m.ok()).map(|m| m.value), None);
+    assert_eq!(q.next().and_then(|m| m.ok()).map(|m| m.value), Some(result1));
+    assert_eq!(q.next().and_then(|m| m.ok()).map(|m| m.value), Some(result2));
+    assert_eq!(q.next().and_then(|m| m.ok()).map(|m| m.value), None);
+}
+
+fn framed(body : &[u8]) -> Vec<u8> {
+    let mut crc = record::Crc32c::new();
+    crc.update(body);
+    let mut rec = body.to_vec();
+    rec.extend_from_slice(&crc.trailer(0));
+    rec
+}
+
+#[test]
+fn test_queue_segments () {
+    let world = framed(b"world");
+    let new = framed(b"new");
+    let segment = framed(b" segment");
+
+    let mut q = Messages::new(0, 5);
+    q.push_data(&world);
+    q.push_data(&new);
+    q.push_data(&segment);
+    q.push_index(5 + world.len() as u64);
+    q.push_segment();
+    q.push_index(new.len() as u64);
+    q.push_index((new.len() + segment.len()) as u64);
+
+    assert_eq!(q.next().transpose().unwrap(), Some(Message::new(b"world")));
+    assert_eq!(q.next().transpose().unwrap(), Some(Message::new(b"new")), "offsets should restart from zero after segment start");
+    assert_eq!(q.next().transpose().unwrap(), Some(Message::new(b" segment")));
+    assert!(q.next().is_none());
+}
+
+#[test]
+fn test_queue_checksum () {
+    let mut rec = framed(b"good data");
+    rec[2] = b'X';
+
+    let mut q = Messages::new(0, 0);
+    q.push_data(&rec);
+    q.push_index(rec.len() as u64);
+
+    match q.next() {
+        Some(Err(Er::BadChecksum(_, _))) => {},
+        _ => assert!(false, "corrupt record should fail checksum"),
+    }
+}
+
+#[test]
+fn test_queue_bad_index () {
+    let mut q = Messages::new(0, 20);
+    q.push_data(b"data");
+    q.push_index(10);
+
+    match q.next() {
+        Some(Err(Er::BadRecordFormat(_))) => {},
+        _ => assert!(false, "an index entry before the data offset should be an error, not an underflow"),
+    }
+}
+
+#[test]
+fn test_queue_message () {
+    let message = Message::new(b"graham").with_key(b"user-1").with_header("trace-id", b"abc123");
+    let mut crc = record::Crc32c::new();
+    let body = message.body().unwrap();
+    crc.update(&body);
+    let mut rec = body.clone();
+    rec.extend_from_slice(&crc.trailer(message.flags()));
+
+    let mut q = Messages::new(0, 0);
+    q.push_data(&rec);
+    q.push_index(0); // removed by compaction
+    q.push_index(rec.len() as u64);
+
+    let received = q.next().transpose().unwrap().expect("should skip removed record");
+    assert_eq!(received.key, Some(b"user-1".to_vec()));
+    assert_eq!(received.header("trace-id"), Some(&b"abc123"[..]));
+    assert_eq!(received.value, b"graham".to_vec());
+    assert!(q.next().is_none());
+}
+
+#[test]
+fn test_server_error () {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::open("test", listener.local_addr().unwrap().to_string(), String::from("ANON")).unwrap(); // no metadata lookup, the server here only sends what each test needs
+    let (mut server, _) = listener.accept().unwrap();
+
+    crate::tcp::send_error(&mut server, 3, &Er::TopicNotFound).unwrap();
+    match client.ack() {
+        Err(Er::ServerError(code, message)) => {
+            assert_eq!(code, 100);
+            assert_eq!(message, Er::TopicNotFound.to_string(), "message from the server error");
+        },
+        _ => assert!(false, "error record should come back as a server error"),
+    }
+
+    // an ack after the error is still read
+    let mut ack = (4u32 + 1 + 1 + 8).to_le_bytes().to_vec();
+    ack.push(RecordType::Ack as u8);
+    ack.push(4);
+    ack.extend_from_slice(&42u64.to_le_bytes());
+    server.write_all(&ack).unwrap();
+    assert_eq!(client.ack().unwrap(), 42);
+}
+
+#[test]
+fn test_server_hello () {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::open("test", listener.local_addr().unwrap().to_string(), String::from("ANON")).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    assert!(client.server_hello().is_none(), "nothing agreed until the server answers");
+
+    let agreed = Hello { version : 2, features : FEATURE_KEYS, max_record_bytes : 16 };
+    server.write_all(&agreed.reply_frame()).unwrap();
+    crate::tcp::send_error(&mut server, 2, &Er::TopicNotFound).unwrap();
+    assert!(client.ack().is_err());
+    assert_eq!(client.server_hello(), Some(&agreed));
+
+    assert!(client.send(String::from("longer than sixteen bytes")).is_err(), "larger than the server takes");
+    assert!(client.send_message(&Message::new(b"v").with_header("h", b"")).is_err(), "server has no header support");
+    assert!(client.send_message(&Message::new(b"v").with_key(b"k")).is_ok());
 }
@@ -1,25 +1,200 @@
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::Path;
 
-#[derive(Deserialize, Debug)]
+use super::er::Er;
+use super::auth::Credentials;
+use super::tls;
+
+/* largest file_mask, 16 hex digits is the whole u64 index */
+pub const MAX_FILE_MASK : u8 = 16;
+
+/*
+ * Read from the file named on the command line. Any of these environment variables
+ * replace the value from the file :
+ *
+ *   REDFOAM_NODE_ID, REDFOAM_PRODUCER_ADDR, REDFOAM_CONSUMER_ADDR, REDFOAM_DATA_DIR,
+ *   REDFOAM_CREDENTIALS_FILE
+ */
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub node_id : u32,
+    #[serde(default = "default_producer_addr")]
+    pub producer_addr : String,
+    #[serde(default = "default_consumer_addr")]
+    pub consumer_addr : String,
+    #[serde(default)]
+    pub data_dir : Option<String>, // folder for topics that don't give one of their own
+    #[serde(default)]
+    pub credentials_file : Option<String>, // principals and their hashed secrets, everyone is anonymous without one
+    #[serde(default)]
+    pub producer_tls : Option<TlsConfig>, // producers connect over TLS, plain TCP without one
+    #[serde(default)]
+    pub consumer_tls : Option<TlsConfig>, // consumers connect over TLS, plain TCP without one
+    #[serde(default)]
+    pub websocket : Option<WebSocketConfig>, // gateway for browsers, alongside the consumer server
     pub topics : Vec<TopicConfig>,
 }
 
+fn default_producer_addr() -> String { String::from("127.0.0.1:9090") }
+fn default_consumer_addr() -> String { String::from("127.0.0.1:9091") }
+
 impl Config {
+    /* single test topic in /tmp, for running without a config file */
     pub fn new() -> Config {
         let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"test\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4";
         toml::from_str(config_string).unwrap()
     }
+
+    pub fn load(path : &str) -> Result<Config, Er> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Er::BadConfig(format!("can't read config file {} : {}", path, e)))?;
+        Config::parse(&text, env::vars())
+            .map_err(|e| Er::BadConfig(format!("{} : {}", path, e)))
+    }
+
+    /* parses text, applies any REDFOAM_ overrides in vars, then checks the result */
+    pub fn parse<I>(text : &str, vars : I) -> Result<Config, Er> where I : IntoIterator<Item = (String, String)> {
+        let mut config : Config = toml::from_str(text)
+            .map_err(|e| Er::BadConfig(e.to_string()))?;
+
+        for (name, value) in vars {
+            match name.as_str() {
+                "REDFOAM_NODE_ID" => {
+                    config.node_id = value.parse()
+                        .map_err(|_| Er::BadConfig(format!("REDFOAM_NODE_ID {:?} is not a number", value)))?;
+                },
+                "REDFOAM_PRODUCER_ADDR" => config.producer_addr = value,
+                "REDFOAM_CONSUMER_ADDR" => config.consumer_addr = value,
+                "REDFOAM_DATA_DIR" => config.data_dir = Some(value),
+                "REDFOAM_CREDENTIALS_FILE" => config.credentials_file = Some(value),
+                _ => {},
+            }
+        }
+
+        if let Some(websocket) = config.websocket.as_mut().filter(|w| w.producer_url.is_none()) {
+            websocket.producer_url = Some(config.producer_addr.clone());
+        }
+
+        if let Some(data_dir) = &config.data_dir {
+            for topic in config.topics.iter_mut().filter(|t| t.folder.is_empty()) {
+                topic.folder = data_dir.clone();
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Er> {
+        if let Some(path) = &self.credentials_file {
+            Credentials::load(path)?;
+        }
+        let websocket_tls = self.websocket.iter().filter_map(|w| w.tls.as_ref());
+        for tls_config in self.producer_tls.iter().chain(self.consumer_tls.iter()).chain(websocket_tls) {
+            tls::server_config(tls_config)?;
+        }
+
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+
+        for topic in &self.topics {
+            if !ids.insert(topic.topic_id) {
+                return Err(Er::BadConfig(format!("topic id {} is used by more than one topic", topic.topic_id)));
+            }
+            if topic.topic_name.is_empty() || !names.insert(topic.topic_name.as_str()) {
+                return Err(Er::BadConfig(format!("topic {} needs a name no other topic has, not {:?}", topic.topic_id, topic.topic_name)));
+            }
+            if topic.file_mask > MAX_FILE_MASK {
+                return Err(Er::BadConfig(format!("topic {} has file_mask {}, it should be 0 to {}", topic.topic_name, topic.file_mask, MAX_FILE_MASK)));
+            }
+            if topic.folder.is_empty() {
+                return Err(Er::BadConfig(format!("topic {} has no folder and there is no data_dir", topic.topic_name)));
+            }
+            // the topic's own folder is made on startup, but one it goes in must be there
+            if !Path::new(&topic.folder).is_dir() {
+                return Err(Er::BadConfig(format!("topic {} folder {} does not exist", topic.topic_name, topic.folder)));
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct TopicConfig {
     pub topic_id : u32,
     pub topic_name : String,
-    pub folder : String,
+    #[serde(default)]
+    pub folder : String, // the topic's files are in folder/topic_name, data_dir when left out
     pub replication : u8,
     pub file_mask : u8, // 16 - how many hex digits in filename, that is 2^(file_mask*4) = number of records in single file
+    #[serde(default)]
+    pub segment_bytes : Option<u64>, // optional max size of a data file, a new segment is started once it is reached
+    #[serde(default)]
+    pub retention_ms : Option<u64>, // delete segments whose last write is older than this
+    #[serde(default)]
+    pub retention_bytes : Option<u64>, // delete oldest segments while the topic is bigger than this
+    #[serde(default)]
+    pub retention_segments : Option<u32>, // delete oldest segments while there are more than this
+    #[serde(default)]
+    pub durability : Durability,
+    #[serde(default)]
+    pub compact : bool, // closed segments keep only the latest record for each key
+    #[serde(default)]
+    pub tombstone_ms : Option<u64>, // how long a key with an empty value survives compaction before the key is dropped
+    #[serde(default)]
+    pub partitions : u32, // logs the topic is split over, each in its own p<n> folder. 0 or 1 keeps a single log in the topic folder
+    #[serde(default)]
+    pub max_record_bytes : Option<u64>, // largest record body producers can send, larger ones are rejected
+}
+
+/*
+ * a listener's certificate, and the CA client certificates are checked against. With
+ * client_ca_file set clients have to present a certificate signed by it, unless
+ * client_cert_optional is set, and the certificate's subject common name is then the
+ * principal the connection authenticates as, see auth.rs
+ */
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub cert_file : String, // PEM, the server's certificate followed by any intermediates
+    pub key_file : String, // PEM private key for cert_file
+    #[serde(default)]
+    pub client_ca_file : Option<String>, // PEM CA certificates client certificates are verified with
+    #[serde(default)]
+    pub client_cert_optional : bool, // clients without a certificate authenticate as they would without TLS
+}
+
+/* the WebSocket gateway, see websocket.rs */
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct WebSocketConfig {
+    pub addr : String, // where browsers connect
+    #[serde(default)]
+    pub tls : Option<TlsConfig>, // wss:// when set
+    #[serde(default)]
+    pub produce : bool, // browsers may send records as well as follow topics
+    #[serde(default)]
+    pub producer_url : Option<String>, // where the gateway sends records browsers produce, producer_addr when left out
+    #[serde(default)]
+    pub allowed_origins : Vec<String>, // pages browsers may connect from, e.g. "https://app.example", or "*" for any
+}
+impl WebSocketConfig {
+    /* browsers always send an Origin, so none are let in until their pages are listed */
+    pub fn allows_origin(&self, origin : &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+/* when topic files are flushed to disk with fsync, producers are acknowledged after a record is written */
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    #[default]
+    Os,                 // never fsync, leave it to the OS
+    Record,             // fsync every record before it is acknowledged
+    EveryRecords(u32),  // fsync after this many records
+    EveryMs(u64),       // fsync when this many milliseconds have passed since the last one
 }
 
 #[test]
@@ -34,4 +209,128 @@ fn test_config() {
     assert_eq!(t.topic_name, "test");
     assert_eq!(t.replication, 0);
     assert_eq!(t.folder, "/tmp");
+    assert_eq!(t.segment_bytes, None);
+}
+
+#[test]
+fn test_config_segment_bytes() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"test\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\nsegment_bytes=1048576";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    assert_eq!(config.topics[0].segment_bytes, Some(1048576));
+}
+
+#[test]
+fn test_config_retention() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"test\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\nretention_ms=86400000\nretention_segments=10";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    let t: &TopicConfig = &config.topics[0];
+    assert_eq!(t.retention_ms, Some(86400000));
+    assert_eq!(t.retention_bytes, None);
+    assert_eq!(t.retention_segments, Some(10));
+    assert_eq!(t.durability, Durability::Os);
+    assert!(!t.compact);
+}
+
+#[test]
+fn test_config_durability() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"payments\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\ndurability=\"record\"\n\
+                               [[topics]]\ntopic_id = 2\ntopic_name = \"telemetry\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\ndurability={ every_ms = 500 }";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    assert_eq!(config.topics[0].durability, Durability::Record);
+    assert_eq!(config.topics[1].durability, Durability::EveryMs(500));
+}
+
+#[test]
+fn test_config_compact() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"entities\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\ncompact=true\ntombstone_ms=3600000";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    assert!(config.topics[0].compact);
+    assert_eq!(config.topics[0].tombstone_ms, Some(3600000));
+}
+
+#[test]
+fn test_config_partitions() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"orders\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\npartitions=8";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    assert_eq!(config.topics[0].partitions, 8);
+}
+
+#[test]
+fn test_config_max_record_bytes() {
+    let config_string: &str = "node_id = 0\n[[topics]]\ntopic_id = 1\ntopic_name = \"documents\"\nreplication = 0\nfolder=\"/tmp\"\nfile_mask=4\nmax_record_bytes=262144";
+    let config: Config = toml::from_str(config_string).unwrap();
+
+    assert_eq!(config.topics[0].max_record_bytes, Some(262144));
+}
+
+#[test]
+fn test_config_load() {
+    let env = super::test_support::TestEnvironment::new("config_load");
+    let config_string = format!("node_id = 3\nproducer_addr = \"0.0.0.0:7000\"\ndata_dir = \"{}\"\n\
+                                 [[topics]]\ntopic_id = 1\ntopic_name = \"orders\"\nreplication = 0\nfile_mask=4\n\
+                                 [[topics]]\ntopic_id = 2\ntopic_name = \"audit\"\nreplication = 0\nfolder=\"{}\"\nfile_mask=16", env.folder, env.folder);
+
+    let config = Config::parse(&config_string, vec![]).unwrap();
+    assert_eq!((config.node_id, config.producer_addr.as_str(), config.consumer_addr.as_str()), (3, "0.0.0.0:7000", "127.0.0.1:9091"));
+    assert_eq!(config.topics[0].folder, env.folder, "topics without a folder use data_dir");
+
+    let overrides = vec![
+        (String::from("REDFOAM_NODE_ID"), String::from("7")),
+        (String::from("REDFOAM_CONSUMER_ADDR"), String::from("0.0.0.0:7001")),
+        (String::from("HOME"), String::from("/root")),
+    ];
+    let config = Config::parse(&config_string, overrides).unwrap();
+    assert_eq!((config.node_id, config.consumer_addr.as_str()), (7, "0.0.0.0:7001"), "environment wins over the file");
+
+    let bad_node = vec![(String::from("REDFOAM_NODE_ID"), String::from("seven"))];
+    assert!(matches!(Config::parse(&config_string, bad_node), Err(Er::BadConfig(_))));
+    assert!(matches!(Config::load("/tmp/redfoam_config_load/missing.toml"), Err(Er::BadConfig(_))));
+}
+
+#[test]
+fn test_config_websocket() {
+    let env = super::test_support::TestEnvironment::new("config_websocket");
+    let config_string = format!("node_id = 0\nproducer_addr = \"0.0.0.0:7000\"\ndata_dir = \"{}\"\n\
+                                 [websocket]\naddr = \"0.0.0.0:7002\"\nproduce = true\n\
+                                 [[topics]]\ntopic_id = 1\ntopic_name = \"orders\"\nreplication = 0\nfile_mask=4\n", env.folder);
+
+    let websocket = Config::parse(&config_string, vec![]).unwrap().websocket.unwrap();
+    assert_eq!((websocket.addr.as_str(), websocket.produce), ("0.0.0.0:7002", true));
+    assert_eq!(websocket.producer_url.as_deref(), Some("0.0.0.0:7000"), "records go to this node's producer server unless told otherwise");
+
+    let elsewhere = config_string.replace("produce = true", "producer_url = \"10.0.0.1:9090\"");
+    let websocket = Config::parse(&elsewhere, vec![]).unwrap().websocket.unwrap();
+    assert_eq!((websocket.producer_url.as_deref(), websocket.produce), (Some("10.0.0.1:9090"), false));
+
+    assert!(!websocket.allows_origin("https://app.example"), "no pages are allowed unless listed");
+    let listed = config_string.replace("produce = true", "allowed_origins = [\"https://app.example\"]");
+    let websocket = Config::parse(&listed, vec![]).unwrap().websocket.unwrap();
+    assert!(websocket.allows_origin("https://APP.example") && !websocket.allows_origin("https://elsewhere.example"));
+
+    assert!(Config::parse(&config_string.replace("[[topics]]", "[websocket.tls]\ncert_file = \"/tmp/redfoam_config_websocket/missing.pem\"\nkey_file = \"/tmp/redfoam_config_websocket/missing.key\"\n[[topics]]"), vec![]).is_err(), "tls files are checked");
+    assert!(Config::parse(&config_string.replace("[websocket]\naddr = \"0.0.0.0:7002\"\nproduce = true\n", ""), vec![]).unwrap().websocket.is_none());
+}
+
+#[test]
+fn test_config_validate() {
+    let env = super::test_support::TestEnvironment::new("config_validate");
+    let topic = |id : u32, name : &str, mask : u8| format!("[[topics]]\ntopic_id = {}\ntopic_name = \"{}\"\nreplication = 0\nfolder=\"{}\"\nfile_mask={}\n", id, name, env.folder, mask);
+    let check = |topics : String| match Config::parse(&format!("node_id = 0\n{}", topics), vec![]) {
+        Err(Er::BadConfig(message)) => message,
+        x => panic!("config should be rejected, got {:?}", x),
+    };
+
+    assert!(check(format!("{}{}", topic(1, "orders", 4), topic(1, "orders_copy", 4))).contains("topic id 1"), "duplicate ids");
+    assert!(check(format!("{}{}", topic(1, "orders", 4), topic(2, "orders", 4))).contains("orders"), "duplicate names");
+    assert!(check(topic(1, "orders", 17)).contains("file_mask 17"));
+    assert!(check(topic(1, "orders", 4).replace(&env.folder, "/tmp/redfoam_config_validate/missing")).contains("does not exist"));
+    assert!(check(String::from("[[topics]]\ntopic_id = 1\ntopic_name = \"orders\"\nreplication = 0\nfile_mask=4\n")).contains("no folder"));
+    assert!(check(String::from("topics = []\nnode_id = 1\n")).contains("node_id"), "parse errors say where");
+    assert!(check(format!("credentials_file = \"{}/none.toml\"\n{}", env.folder, topic(1, "orders", 4))).contains("credentials"));
+    assert!(Config::parse(&format!("node_id = 0\n{}", topic(1, "orders", 4)), vec![]).is_ok());
 }
@@ -1,121 +1,440 @@
-use std::net::{TcpStream};
+use std::net::TcpListener;
+use std::sync::Arc;
 use std::io::Write;
 use std::collections::HashMap;
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
+use std::os::unix::io::AsRawFd;
 use inotify::{EventMask, Event};
+use rustls::ServerConfig;
 use std::ffi::OsStr;
-
-use super::{trace};
-
-use super::topic::{TopicList};
-use super::buff::{Buff};
-use super::tcp::{BufferState, RecordType};
-use super::auth::Auth;
+use std::time::{Duration, Instant};
+
+use super::{trace, log_warn, log_error};
+
+use super::topic::{Topic, TopicList, Catchup};
+use super::buff::{Buff, MAX_BUFF_SIZE, MAX_PRE_AUTH_SIZE};
+use super::tcp::{BufferState, Connection, RecordType, Start, ALL_PARTITIONS, accept_all, send_error, LISTENER_TOKEN, NOTIFY_TOKEN, WEBSOCKET_TOKEN, TICK};
+use super::poll::Poll;
+use super::auth::{Auth, Credentials, Principal};
+use super::acl::Operation;
+use super::scram::ScramServer;
+use super::hello::{Hello, FEATURE_TAGGED_FEEDS};
+use super::group::{Groups, Join};
+use super::metadata;
+use super::tls::{self, Stream};
+use super::websocket::WebSocket;
+use super::config::{Config, WebSocketConfig};
 use super::er::{Er,LogError};
 
 pub struct ConsumerClient {
     id : u32,
     state : BufferState,
     buff : Buff,
-    pub tcp : TcpStream,
+    pub tcp : Connection,
     auth : Option<Auth>,
+    scram : Option<ScramServer>, /* challenge sent, waiting on the proof */
+    hello : Option<Hello>, /* terms agreed in the handshake, None for clients from before it */
     rec_type : Option<RecordType>,
     topic_id : Option<u32>,
+    partition : u32,
+    group : Option<String>, /* consumer group the client commits offsets for */
+    catchups : Vec<Catchup>, /* logs the client has still to be sent history of before it follows them */
+    last_seen : Instant,
 }
+
+// feed frames a client catching up is sent each pass of the server loop, so one client can't hold up the others
+const CATCHUP_FRAMES: usize = 64;
+
 impl ConsumerClient {
-    pub fn new (id : u32, stream : TcpStream) -> ConsumerClient {
-        let buff = Buff::new();
+    pub fn new (id : u32, stream : Stream) -> ConsumerClient {
+        let buff = Buff::with_max(MAX_PRE_AUTH_SIZE);
 
         ConsumerClient {
             id : id,
             state : BufferState::Pending, 
             buff : buff,
-            tcp : stream,
+            tcp : Connection::new(stream),
             auth : None,
+            scram : None,
+            hello : None,
             rec_type : None, 
             topic_id : None,
+            partition : 0,
+            group : None,
+            catchups : Vec::new(),
+            last_seen : Instant::now(),
+        }
+    }
+
+    /* 
+     * epoll only reports the socket again when more arrives, so everything already
+     * buffered is handled now, until a pass makes no progress
+     */
+    pub fn process(&mut self, topic_list : &mut TopicList, credentials : &Credentials, groups : &mut Groups) -> Result<(),Er> {
+
+        let mut rejected = None;
+        loop {
+            let size = match self.buff.read_data(&mut self.tcp) {
+                Ok(size) => size,
+                Err(e) => {
+                    self.reject(&e);
+                    return Err(e)
+                },
+            };
+            self.last_seen = Instant::now(); // anything sent keeps a group member alive
+
+            loop {
+                let before = self.buff.position();
+                if let Err(e) = self.process_buffered(topic_list, credentials, groups) {
+                    self.reject(&e);
+                    if e.closes_connection() {
+                        return Err(e)
+                    }
+                    rejected = Some(e);
+                }
+                if self.buff.position() == before {
+                    break;
+                }
+            }
+
+            // TLS may have decrypted more than the buffer took, which epoll won't report again
+            if size == 0 || !self.tcp.has_buffered() {
+                return match rejected {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
         }
     }
 
-    pub fn process(&mut self, topic_list : &mut TopicList) -> Result<(),Er> {
+    /* sends the error to the client, then drops either the request that failed or the connection */
+    fn reject(&mut self, e : &Er) {
+        if let Err(send_err) = send_error(&mut self.tcp, self.buff.seq, e) {
+            log_warn!("failed to send error to consumer : {}", send_err);
+        }
+        if e.closes_connection() {
+            self.state = BufferState::Closed;
+        } else {
+            self.rec_type = None;
+            self.buff.reset();
+        }
+    }
+
+    fn process_buffered(&mut self, topic_list : &mut TopicList, credentials : &Credentials, groups : &mut Groups) -> Result<(),Er> {
 
-        self.buff.read_data(&mut self.tcp)?; 
         if self.buff.rec_size.is_none() { self.buff.rec_size = self.buff.read_u32(); }
+        if self.auth.is_none() {
+            // nobody unknown gets to grow the buffer, Hello, Auth and the SCRAM exchange are all small
+            if let Some(size) = self.buff.rec_size.filter(|size| *size as usize > MAX_PRE_AUTH_SIZE) {
+                return Err(Er::FrameTooLarge(size as u64, MAX_PRE_AUTH_SIZE as u64))
+            }
+        }
         self.buff.check_seq()?;
         if self.rec_type.is_none() { self.rec_type = self.buff.read_u8().map(|r| r.into()) }
 
         trace!("server processing rec_size : {:?}", self.buff.rec_size);
 
         match self.rec_type {
+            Some(RecordType::Hello) => {
+                if self.auth.is_some() || self.hello.is_some() {
+                    return Err(Er::UnexpectedRecordType) // only as the first record
+                }
+                if let Some(hello) = Hello::new(&mut self.buff)? {
+                    let agreed = hello.agree(&Hello::local(topic_list.max_record_bytes()))?;
+                    self.tcp.write_all(&agreed.reply_frame())
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.hello = Some(agreed);
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
             Some(RecordType::Auth) => {
-                self.auth = Auth::new(&mut self.buff)?;
+                if self.auth.is_some() || self.scram.is_some() {
+                    return Err(Er::BadAuth) // a connection authenticates once, as one principal
+                }
+                self.auth = Auth::new(&self.buff, credentials, self.tcp.peer_principal())?;
                 if self.auth.is_some() {
                         self.state = BufferState::Active;
+                        self.buff.set_max(MAX_BUFF_SIZE);
                         self.rec_type = None;
                         self.buff.reset();
                 }
                 Ok(())
             }, 
 
+            Some(RecordType::ScramStart) => {
+                if self.auth.is_some() || self.scram.is_some() {
+                    return Err(Er::UnexpectedRecordType) // the connection stays Pending until the one exchange is done
+                }
+                if let Some((scram, challenge)) = ScramServer::start(&mut self.buff, credentials)? {
+                    self.tcp.write_all(&challenge)
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.scram = Some(scram);
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
+            Some(RecordType::ScramProof) => {
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let (auth, outcome) = self.scram.take().ok_or(Er::UnexpectedRecordType)?
+                    .finish(&mut self.buff)?;
+                self.tcp.write_all(&outcome)
+                    .map_err(Er::ServerTcpWrite)?;
+                self.auth = Some(auth);
+                self.state = BufferState::Active;
+                self.buff.set_max(MAX_BUFF_SIZE);
+                self.rec_type = None;
+                self.buff.reset();
+                Ok(())
+            },
+
             Some(RecordType::ConsumerStart) => {
-                if self.auth.is_some() {
+                trace!("Server : found ConsumerStart");
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+                if !self.catchups.is_empty() {
+                    return Err(Er::UnexpectedRecordType) // already starting
+                }
 
-                    if self.buff.has_data() {
-                        /*
-                        if let Some(topic_id) = self.topic_id {
-                            topic_list.write(topic_id, self.buff.data());
-
-                            if self.buff.is_end_of_record() {
-                                let idx = topic_list.end_record(topic_id);
-                                self.tcp.write(&[self.buff.seq]).unwrap();
-                                self.tcp.write(&idx.to_le_bytes()).unwrap();
-                                self.rec_type = None;
-                                self.topic_id = None;
-                            }
-                            self.buff.reset();
-                        }
-                        */
-                    }
+                // [topic_id u32][partition u32][mode u8][value u64] then optionally a group name, so wait for all of it
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
                 }
+                let topic_id = self.buff.read_u32().ok_or(Er::IsNone)?;
+                let partition = self.buff.read_u32().ok_or(Er::IsNone)?;
+                let mode = self.buff.read_u8().ok_or(Er::IsNone)?;
+                let value = self.buff.read_u64().ok_or(Er::IsNone)?;
+                let start = Start::from_wire(mode, value)?;
+                self.authorize(topic_list, credentials, topic_id, Operation::Consume)?;
+                let group = match self.buff.has_data() {
+                    true => Some(String::from_utf8(self.buff.data().to_vec())
+                        .map_err(|e| Er::BadGroupName(String::from_utf8_lossy(e.as_bytes()).into_owned()))?),
+                    false => None,
+                };
+                trace!("Server : ConsumerStart on {} partition {} from {:?} in group {:?}", topic_id, partition, start, group);
+
+                let t = topic_list.partition_for_id(topic_id, partition)?;
+                let start_index = match &group {
+                    Some(group) => t.group_start_index(group, start)?,
+                    None => t.start_index(start)?,
+                };
+                let catchup = t.catchup_from(topic_id, start_index)?;
+
+                self.topic_id = Some(topic_id);
+                self.partition = partition;
+                self.group = group;
+                self.rec_type = None;
+                self.buff.reset();
+
+                // the record index and data offset the first record sent starts at
+                let size : u32 = 4 + 1 + 8 + 8;
+                let mut frame = Vec::with_capacity(size as usize);
+                frame.extend_from_slice(&size.to_le_bytes());
+                frame.push(RecordType::ConsumerStart as u8);
+                frame.extend_from_slice(&catchup.record_index().to_le_bytes());
+                frame.extend_from_slice(&catchup.data_pos.to_le_bytes());
+                self.tcp.write_all(&frame)
+                    .map_err(Er::ServerTcpWrite)?;
+
+                self.catchups.push(catchup);
+                Ok(())
+            },
+
+            Some(RecordType::Metadata) => {
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+                if let Some(names) = metadata::read_request(&mut self.buff)? {
+                    let topics = topic_list.metadata(&names)?;
+                    let topics = credentials.acl.visible(self.principal()?, topics, !names.is_empty())?;
+                    self.tcp.write_all(&metadata::reply_frame(&topics))
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
+            Some(RecordType::ConsumerCommit) => {
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+
+                // [record_index u64][generation u32][member u32], for the topic and group the client started with
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let record_index = self.buff.read_u64().ok_or(Er::IsNone)?;
+                let member = match self.buff.has_data() {
+                    true => Some((self.buff.read_u32().ok_or(Er::IsNone)?, self.buff.read_u32().ok_or(Er::IsNone)?)),
+                    false => None, // from outside the group's membership
+                };
+
+                let (topic_id, group) = match (self.topic_id, &self.group) {
+                    (Some(topic_id), Some(group)) => (topic_id, group),
+                    _ => return Err(Er::NotInGroup),
+                };
+                trace!("Server : group {} member {:?} commits {} on {} partition {}", group, member, record_index, topic_id, self.partition);
+                groups.check_commit(topic_id, group, self.partition, member)?;
+                topic_list.partition_for_id(topic_id, self.partition)?.commit(group, record_index)?;
+
+                let size : u32 = 4 + 1 + 1 + 8;
+                let mut frame = Vec::with_capacity(size as usize);
+                frame.extend_from_slice(&size.to_le_bytes());
+                frame.push(RecordType::Ack as u8);
+                frame.push(self.buff.seq);
+                frame.extend_from_slice(&record_index.to_le_bytes());
+                self.tcp.write_all(&frame)
+                    .map_err(Er::ServerTcpWrite)?;
+
+                self.rec_type = None;
+                self.buff.reset();
+                Ok(())
+            },
+
+            Some(RecordType::JoinGroup) => {
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+
+                // [topic_id u32][session_timeout_ms u32][group], so wait for all of it
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let topic_id = self.buff.read_u32().ok_or(Er::IsNone)?;
+                self.authorize(topic_list, credentials, topic_id, Operation::Consume)?;
+                let session_timeout = Duration::from_millis(self.buff.read_u32().ok_or(Er::IsNone)? as u64);
+                let group = String::from_utf8(self.buff.data().to_vec())
+                    .map_err(|e| Er::BadGroupName(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+                Topic::check_group_name(&group)?;
+                let partitions = topic_list.partition_count(topic_id)?;
+                trace!("Server : JoinGroup {} on {} with {} partitions", group, topic_id, partitions);
+
+                groups.join(self.id, Join { topic_id, group : group.clone(), session_timeout, partitions });
+                self.topic_id = Some(topic_id);
+                self.group = Some(group);
+                self.rec_type = None;
+                self.buff.reset();
+                Ok(())
+            },
+
+            Some(RecordType::AssignmentAck) => {
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+
+                // [generation u32] then [partition u32][record_index u64] for each partition given up
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let generation = self.buff.read_u32().ok_or(Er::IsNone)?;
+                let (topic_id, group) = match (self.topic_id, &self.group) {
+                    (Some(topic_id), Some(group)) => (topic_id, group),
+                    _ => return Err(Er::NotInGroup),
+                };
+
+                // what was read of them is committed before anyone else is given them
+                while self.buff.has_data() {
+                    let partition = self.buff.read_u32().ok_or(Er::IsNone)?;
+                    let record_index = self.buff.read_u64().ok_or(Er::IsNone)?;
+                    groups.check_commit(topic_id, group, partition, Some((generation, self.id)))?;
+                    topic_list.partition_for_id(topic_id, partition)?.commit(group, record_index)?;
+                }
+                trace!("Server : client {} applied generation {} of group {}", self.id, generation, group);
+                groups.release(topic_id, group, self.id, generation);
+
+                self.rec_type = None;
+                self.buff.reset();
+                Ok(())
+            },
+
+            Some(RecordType::Heartbeat) => {
+                // last_seen has already been updated
+                self.rec_type = None;
+                self.buff.reset();
                 Ok(())
             },
 
             Some(RecordType::ConsumerFollowTopics) => {
                 trace!("Server : found ConsumerFollowTopics");
-                if self.auth.is_some() {
-                    trace!("Server : ConsumerFollowTopics auth ok");
-                    match self.buff.read_u32() {
-                        Some(topic_id) => {
-                            trace!("Server : ConsumerFollowTopics on {}", topic_id);
-                            self.topic_id = Some(topic_id);
-                            let t = topic_list.topic_for_id(topic_id)?;
-                            let (index_pos, data_pos) = t.follow(self.id)?;
-
-                            self.rec_type = None;
-                            self.buff.reset();
-                            //send response
-                            let size : u32 = 4 + 1 + 8 + 8; // u8 + u64 + u64
-
-                            self.tcp.write(&size.to_le_bytes())
-                                .map_err(|e| Er::ServerTcpWrite(e))?;
-
-                            self.tcp.write(&[RecordType::ConsumerFollowTopics as u8])
-                                .map_err(|e| Er::ServerTcpWrite(e))?;
-
-                            self.tcp.write(&index_pos.to_le_bytes())
-                                .map_err(|e| Er::ServerTcpWrite(e))?;
-
-                            self.tcp.write(&data_pos.to_le_bytes())
-                                .map_err(|e| Er::ServerTcpWrite(e))?;
-                        }, 
-                        None => {unimplemented!()},
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+
+                // [topic_id u32][partition u32] for each log to follow, so wait for all of it
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let mut requested = Vec::new();
+                while self.buff.has_data() {
+                    let topic_id = self.buff.read_u32().ok_or(Er::IsNone)?;
+                    let partition = match self.buff.has_data() {
+                        true => self.buff.read_u32().ok_or(Er::IsNone)?,
+                        false => 0, // a lone topic_id, as sent before partitions
+                    };
+                    requested.push((topic_id, partition));
+                }
+
+                // everything is checked before anything is followed, so a bad request follows nothing
+                let mut follows = Vec::new();
+                for (topic_id, partition) in requested {
+                    self.authorize(topic_list, credentials, topic_id, Operation::Consume)?;
+                    match partition {
+                        ALL_PARTITIONS => {
+                            for p in 0..topic_list.partition_count(topic_id)? {
+                                follows.push((topic_id, p));
+                            }
+                        },
+                        p => {
+                            topic_list.partition_for_id(topic_id, p)?;
+                            follows.push((topic_id, p));
+                        },
+                    }
+                }
+                if follows.is_empty() {
+                    return Err(Er::TopicNotFound)
+                }
+                if follows.len() > 1 && !self.tags_feeds() {
+                    return Err(Er::FeatureNotAgreed(FEATURE_TAGGED_FEEDS))
+                }
+                trace!("Server : ConsumerFollowTopics on {:?}", follows);
+
+                // the record index and data offset each log's feed starts at, tagged clients are told which log is which
+                let mut reply = vec![RecordType::ConsumerFollowTopics as u8];
+                let mut catchups = Vec::with_capacity(follows.len());
+                for (topic_id, partition) in follows.iter() {
+                    let catchup = topic_list.partition_for_id(*topic_id, *partition)?.catchup_from(*topic_id, None)?;
+                    if self.tags_feeds() {
+                        reply.extend_from_slice(&topic_id.to_le_bytes());
+                        reply.extend_from_slice(&partition.to_le_bytes());
                     }
+                    reply.extend_from_slice(&catchup.record_index().to_le_bytes());
+                    reply.extend_from_slice(&catchup.data_pos.to_le_bytes());
+                    catchups.push(catchup);
                 }
+                // any record being written as they joined is sent from the files, then they follow
+                self.catchups.extend(catchups);
+                self.topic_id = Some(follows[0].0);
+                self.partition = follows[0].1;
+
+                self.rec_type = None;
+                self.buff.reset();
+
+                let size : u32 = 4 + reply.len() as u32;
+                self.tcp.write_all(&size.to_le_bytes())
+                    .map_err(Er::ServerTcpWrite)?;
+                self.tcp.write_all(&reply)
+                    .map_err(Er::ServerTcpWrite)?;
                 Ok(())
             },
-            _ => { Ok(()) },
+            Some(_) => Err(Er::UnexpectedRecordType),
+            None => Ok(()),
         }
     }
 
@@ -137,6 +456,122 @@ impl ConsumerClient {
         Ok(())
     }
 
+    /* 
+     * header for a feed whose content is written straight from the topic file with sendfile.
+     * clients that agreed to tagged feeds are told the topic and partition it is from
+     */
+    pub fn send_feed_header(&mut self, size : usize, feed_type : RecordType, (topic_id, partition) : (u32, u32)) -> Result<(),Er> {
+        let mut header = Vec::with_capacity(4 + 1 + 4 + 4);
+        match self.tags_feeds() {
+            true => {
+                header.extend_from_slice(&(4 + 1 + 4 + 4 + size as u32).to_le_bytes());
+                header.push(feed_type as u8);
+                header.extend_from_slice(&topic_id.to_le_bytes());
+                header.extend_from_slice(&partition.to_le_bytes());
+            },
+            false => {
+                header.extend_from_slice(&(4 + 1 + size as u32).to_le_bytes());
+                header.push(feed_type as u8);
+            },
+        }
+
+        self.tcp.write_all(&header)
+            .map_err(Er::ClientTcpWrite)
+    }
+
+    pub fn send_tagged_feed(&mut self, buffer : &[u8], feed_type : RecordType, tag : (u32, u32)) -> Result<(),Er> {
+        self.send_feed_header(buffer.len(), feed_type, tag)?;
+
+        self.tcp.write_all(buffer)
+            .map_err(Er::ClientTcpWrite)
+    }
+
+    fn tags_feeds(&self) -> bool {
+        match &self.hello {
+            Some(hello) => hello.supports(FEATURE_TAGGED_FEEDS),
+            None => false, // clients from before the handshake only follow one topic
+        }
+    }
+
+    /* 
+     * sends some more of the history of each log the client has still to catch up on, true
+     * while there is more the socket can take now. once caught up on a log the client is
+     * one of its followers
+     */
+    pub fn catch_up(&mut self, topic_list : &mut TopicList) -> bool {
+        let mut more = false;
+        for mut catchup in std::mem::take(&mut self.catchups) {
+            let result = topic_list.partition_for_id(catchup.topic_id, catchup.partition)
+                .and_then(|t| t.catch_up(self, &mut catchup, CATCHUP_FRAMES));
+
+            match result {
+                Ok(true) => {},
+                Ok(false) => {
+                    self.catchups.push(catchup);
+                    more = !self.tcp.is_queued(); // otherwise carried on once the socket has room
+                },
+                Err(e) => {
+                    log_warn!("consumer {} catching up : {}", self.id, e);
+                    if let Err(send_err) = send_error(&mut self.tcp, self.buff.seq, &e) {
+                        log_warn!("failed to send error to consumer : {}", send_err);
+                    }
+                    if e.closes_connection() {
+                        self.state = BufferState::Closed;
+                    }
+                },
+            }
+        }
+        more
+    }
+
+    /* a follower the socket has fallen behind, which is sent the rest of the log from the files */
+    pub fn fall_behind(&mut self, catchup : Catchup) {
+        self.catchups.push(catchup);
+    }
+
+    #[cfg(test)]
+    pub fn take_catchups(&mut self) -> Vec<Catchup> {
+        std::mem::take(&mut self.catchups)
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /* writes anything the socket couldn't take earlier, dropping the connection if it has gone */
+    pub fn send_pending(&mut self) {
+        if let Err(e) = self.tcp.send_pending() {
+            log_warn!("failed to write to consumer {} : {}", self.id, e);
+            self.state = BufferState::Closed;
+        }
+    }
+
+    /* has poll report the socket when it has room, while anything is queued for it */
+    pub fn watch_writable(&mut self, poll : &Poll) {
+        if let Err(e) = self.tcp.watch_writable(poll, self.id as u64) {
+            log_warn!("failed to poll consumer {} : {}", self.id, e);
+            self.state = BufferState::Closed;
+        }
+    }
+
+    /* drops the connection once the server loop next tidies up */
+    pub fn close(&mut self) {
+        self.state = BufferState::Closed;
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /* AccessDenied unless the rules let this connection's principal do op on the topic */
+    fn authorize(&self, topic_list : &TopicList, credentials : &Credentials, topic_id : u32, op : Operation) -> Result<(), Er> {
+        credentials.acl.check(self.principal()?, topic_list.topic_name(topic_id)?, op)
+    }
+
+    fn principal(&self) -> Result<&Principal, Er> {
+        self.auth.as_ref().map(|a| &a.principal).ok_or(Er::BadAuth)
+    }
+
     pub fn state(&self) -> &BufferState {
         &self.state
     }
@@ -144,135 +579,201 @@ impl ConsumerClient {
 
 
 pub struct ConsumerServer {
-    rx :  mpsc::Receiver<TcpStream>,
+    listener : TcpListener,
+    tls : Option<Arc<ServerConfig>>,
+    poll : Poll,
     client_list : HashMap<u32, ConsumerClient>,
     topic_list : TopicList,
+    credentials : Credentials,
+    groups : Groups,
     next_client_id : u32,
+    websocket : Option<WebSocketListener>,
+}
+
+/* browsers connect here, each is a consumer client speaking through a WebSocket */
+struct WebSocketListener {
+    listener : TcpListener,
+    tls : Option<Arc<ServerConfig>>,
+    config : WebSocketConfig,
 }
+
 impl ConsumerServer {
-    pub fn init (rx :  mpsc::Receiver<TcpStream>) -> ConsumerServer {
+    pub fn init (listener : TcpListener, config : &Config) -> ConsumerServer {
 
         let client_list : HashMap<u32, ConsumerClient> = HashMap::new();
-        let topic_list_result = TopicList::init(false);
+        let topic_list_result = TopicList::init(config, false);
+
+        listener.set_nonblocking(true).expect("set_nonblocking call failed");
+        let poll = Poll::new().handle_err("Failed to create epoll instance");
+        poll.add(listener.as_raw_fd(), LISTENER_TOKEN).handle_err("Failed to poll listener");
 
         if let Ok(topic_list) = topic_list_result {
+            poll.add(topic_list.notify.as_raw_fd(), NOTIFY_TOKEN).handle_err("Failed to poll inotify");
             ConsumerServer {
-                rx,
+                listener,
+                tls : config.consumer_tls.as_ref().map(tls::server_config).transpose().handle_err("Failed to set up TLS"),
+                poll,
                 client_list,
                 topic_list,
+                credentials : Credentials::from_config(config).handle_err("Failed to load credentials"),
+                groups : Groups::new(),
                 next_client_id : 0,
+                websocket : None,
             }
         } else { panic!("failed to initialise topic list");}
     }
 
-    pub fn run (&mut self) { 
-        //let mut client_list : Vec<ClientBuffx> = Vec::new();
+    /* takes browsers on listener too, see websocket.rs */
+    pub fn add_websocket (&mut self, listener : TcpListener, config : &WebSocketConfig) {
+        listener.set_nonblocking(true).expect("set_nonblocking call failed");
+        self.poll.add(listener.as_raw_fd(), WEBSOCKET_TOKEN).handle_err("Failed to poll websocket listener");
+        self.websocket = Some(WebSocketListener {
+            listener,
+            tls : config.tls.as_ref().map(tls::server_config).transpose().handle_err("Failed to set up websocket TLS"),
+            config : config.clone(),
+        });
+    }
 
+    pub fn run (&mut self) { 
+        let mut catching_up = false;
         loop {
-            // add new client if sent
-            let message = self.rx.try_recv();
-
-            self.next_client_id += 1;
-
-            match message {
-                Err(mpsc::TryRecvError::Empty) => {
-                    //no new stream do nothing
-                },
-
-                Ok(instream) => {
-                    let c = ConsumerClient::new(self.next_client_id, instream);
-                    self.client_list.insert(self.next_client_id, c);
-                },
-                
-                Err(_e) => {
-                    unimplemented!();
+            // clients still being sent history are served again straight away
+            let timeout = if catching_up { Duration::ZERO } else { TICK };
+            let ready_list = self.poll.wait(timeout).handle_err("Consumer error waiting for events");
+
+            for ready in ready_list {
+                match ready.token {
+                    LISTENER_TOKEN => {
+                        for instream in accept_all(&self.listener, self.tls.as_ref()) {
+                            self.next_client_id += 1;
+                            self.poll.add(instream.as_raw_fd(), self.next_client_id as u64).handle_err("Failed to poll client");
+                            let c = ConsumerClient::new(self.next_client_id, instream);
+                            self.client_list.insert(self.next_client_id, c);
+                        }
+                    },
+                    WEBSOCKET_TOKEN => {
+                        if let Some(ws) = &self.websocket {
+                            for instream in accept_all(&ws.listener, ws.tls.as_ref()) {
+                                self.next_client_id += 1;
+                                self.poll.add(instream.as_raw_fd(), self.next_client_id as u64).handle_err("Failed to poll client");
+                                let browser = Stream::WebSocket(Box::new(WebSocket::new(instream, ws.config.clone())));
+                                self.client_list.insert(self.next_client_id, ConsumerClient::new(self.next_client_id, browser));
+                            }
+                        }
+                    },
+                    NOTIFY_TOKEN => {
+                        self.process_topic_events();
+                    },
+                    token => {
+                        let client_id = token as u32;
+                        if let Some(client) = self.client_list.get_mut(&client_id) {
+                            // the client has been sent the error, and closed if it cannot carry on
+                            if let Err(e) = client.process(&mut self.topic_list, &self.credentials, &mut self.groups) {
+                                log_warn!("consumer {} : {}", client_id, e);
+                            }
+                            if ready.closed {
+                                self.client_list.remove(&client_id);
+                            }
+                        }
+                    },
                 }
             }
 
-            // process existing clients - go backwards as list will change length
-            for (_, client) in &mut self.client_list {
-                client.process(&mut self.topic_list).handle_err("Consumer process() error");
+            catching_up = false;
+            for client in self.client_list.values_mut() {
+                catching_up |= client.catch_up(&mut self.topic_list);
+                client.send_pending(); // what the socket couldn't take earlier
+                client.watch_writable(&self.poll);
             }
 
             self.client_list.retain(| _, c | match c.state() {
                 BufferState::Closed => false, _ => true 
             });
 
+            // members that have gone are dropped, and groups that changed are sent new assignments
+            self.groups.check(&mut self.client_list);
+        }
+    }
 
-            // process topic updates
-            let mut event_buffer = [0; 1024];
-            let events = self.topic_list.notify.read_events(&mut event_buffer)
-                .map_err(|e| Er::InotifyError(e))
-                .handle_err("Consumer error reading events");
-
-            for e in events {
-                let (file_name, topic_id, mask) = self.unwrap_event(e)
-                    .handle_err("Consumer Error unwraping event");
+    /* failures are logged rather than ending the server, followers that can't be sent a feed are closed as it is sent */
+    fn process_topic_events(&mut self) {
+        let mut event_buffer = [0; 1024];
+        let events = match self.topic_list.notify.read_events(&mut event_buffer) {
+            Ok(events) => events,
+            Err(e) => {
+                log_error!("Consumer error reading events : {}", Er::InotifyError(e));
+                return
+            },
+        };
 
-                let action_result = match mask {
-                    EventMask::CREATE => {
-                        let t = self.topic_list.topic_for_id(topic_id)
-                            .handle_err("topic should exist in list");
-                        t.switch_file(file_name)
-                    },
-                    EventMask::MODIFY => {
-                        self.send_to_client(topic_id, file_name)  
-                    },
-                    _ => Err(Er::InvalidEventMask)
-                };
+        for e in events {
+            let (file_name, topic_id, partition, mask) = match self.unwrap_event(e) {
+                Ok(event) => event,
+                Err(e) => {
+                    log_error!("Consumer error unwrapping event : {}", e);
+                    continue;
+                },
+            };
 
-                action_result.handle_err("Failed to take appropriate action on event");
-                /*
-                match self.topic_list.watchers.get(&e.wd) {
-                    Some(topic_id) => {
-                        match e.name {
-                            Some(event_name) => {
-                                if let Some(file_name) = event_name.to_str() {
-                                    let t_id = *topic_id;
-                                    if e.mask == EventMask::CREATE {
-                                        let mut t = self.topic_list.topic_for_id(t_id).expect("topic should exist in list");
-                                        t.switch_file(file_name);
-                                    } else {
-                                        self.send_to_client(t_id, file_name);  
-                                    }
-                                } else {
-                                    unimplemented!();
-                                }
-                            }, 
-                            None => { /* error "event should have a file name" */},
-                        }
-                    },
-                    None => assert!(false, "topic id missing from watcher list"),
-                }
-                */
+            if mask.contains(EventMask::ISDIR) {
+                continue; // e.g. the folder group offsets are kept in
             }
 
+            let action_result = match mask {
+                EventMask::CREATE => {
+                    self.switch_segment(topic_id, partition, file_name)
+                },
+                EventMask::MODIFY => {
+                    self.send_to_client(topic_id, partition, file_name)  
+                },
+                _ => Err(Er::InvalidEventMask)
+            };
 
-            thread::sleep(Duration::from_millis(100))
+            if let Err(e) = action_result {
+                log_error!("Failed to take appropriate action on event : {}", e);
+            }
         }
     }
 
-    fn unwrap_event<'a>(&self, ev : Event<&'a OsStr>) -> Result<(&'a str, u32, EventMask), Er> {
+    fn unwrap_event<'a>(&self, ev : Event<&'a OsStr>) -> Result<(&'a str, u32, u32, EventMask), Er> {
 
-        let topic_id = self.topic_list.watchers.get(&ev.wd)
+        let (topic_id, partition) = self.topic_list.watchers.get(&ev.wd)
             .ok_or(Er::TopicNotFound)?;
 
         let name = ev.name
             .ok_or(Er::BadFileName)?
             .to_str().ok_or(Er::BadFileName)?;
 
-        return Ok((name, *topic_id, ev.mask))
+        Ok((name, *topic_id, *partition, ev.mask))
     }
 
-    fn send_to_client(&mut self, topic_id : u32, file_name : &str) -> Result<(), Er> {
+    fn switch_segment(&mut self, topic_id : u32, partition : u32, file_name : &str) -> Result<(), Er> {
+        if Topic::parse_file_name(file_name).is_err() {
+            return Ok(()) // not a segment file
+        }
+
+        // anything still unsent in the old segment has to go out before followers move on
+        self.send_to_client(topic_id, partition, file_name)?;
+
+        let topic = self.topic_list.partition_for_id(topic_id, partition)?;
+        if topic.switch_file(file_name)? && file_name.starts_with('i') {
+            topic.send_segment_start(&mut self.client_list)?;
+        }
+        Ok(())
+    }
+
+    fn send_to_client(&mut self, topic_id : u32, partition : u32, file_name : &str) -> Result<(), Er> {
+        if Topic::parse_file_name(file_name).is_err() {
+            return Ok(()) // not a segment file, e.g. one being compacted
+        }
 
         let feed_type = match file_name.chars().nth(0) {
             Some('i') => RecordType::IndexFeed, 
             Some('d') => RecordType::DataFeed,
-            _       => RecordType::Undefined,
+            _       => return Ok(()), // not a file followers are sent
         };
 
-        let topic = self.topic_list.topic_for_id(topic_id)?;
+        let topic = self.topic_list.partition_for_id(topic_id, partition)?;
 
         topic.send_followers(&mut self.client_list, feed_type)?;
 
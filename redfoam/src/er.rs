@@ -6,6 +6,8 @@ use std::num;
 use std::result::Result;
 
 use super::log_error;
+use super::topic::MAX_GROUP_NAME;
+use super::acl::Operation;
 
 #[derive(Debug)]
 pub enum Er {
@@ -23,15 +25,99 @@ pub enum Er {
     CantReadDir(io::Error),
     CantOpenFile(io::Error),
     InotifyError(io::Error),
+    PollError(io::Error),
     FailedToReturnMessage(mpsc::SendError<Vec<u8>>),
     NoConsumerStart,
     FailedToReadDataStart,
     TopicNotFound,
+    PartitionNotFound(u32, u32),
     IsNone,
     InvalidEventMask,
     BadFileName,
     BadOffset(String, num::ParseIntError),
+    OffsetOutOfRange(u64, u64),
+    BadChecksum(u32, u32),
+    BadRecordFormat(String),
+    RecordTooLarge(u64, u64),
     ParseError(String),
+    BadStartMode(u8),
+    BadGroupName(String),
+    NotInGroup,
+    FeatureNotAgreed(u32),
+    UnknownTopic(String),
+    AccessDenied(String, Operation, String), /* principal, what it tried, topic */
+    StaleGeneration(u32, u32), /* generation a commit was sent for, the group's current one */
+    NotPartitionOwner(u32),
+    UnexpectedRecordType,
+    BadConfig(String),
+    InconsistentSegments(String),
+    TlsError(String),
+    UnsupportedVersion(u16, u16, u16), /* client version, lowest and highest the server speaks */
+    FrameTooLarge(u64, u64), /* size of a frame sent before authenticating, and the most allowed */
+    ServerError(u16, String), /* Error record sent by the server, code and message */
+}
+
+impl Er {
+    /* 
+     * code sent to clients in Error records. These are part of the protocol, so once 
+     * given out a code keeps its meaning, new variants get new codes
+     */
+    pub fn code(&self) -> u16 {
+        match self {
+            // request problems, the connection stays open
+            Er::TopicNotFound => 100,
+            Er::PartitionNotFound(_, _) => 101,
+            Er::RecordTooLarge(_, _) => 102,
+            Er::BadRecordFormat(_) => 103,
+            Er::BadChecksum(_, _) => 104,
+            Er::OffsetOutOfRange(_, _) => 105,
+            Er::ParseError(_) => 106,
+            Er::BadStartMode(_) => 107,
+            Er::BadGroupName(_) => 108,
+            Er::NotInGroup => 109,
+            Er::FeatureNotAgreed(_) => 110,
+            Er::UnknownTopic(_) => 111,
+            Er::AccessDenied(_, _, _) => 112,
+            Er::StaleGeneration(_, _) => 113,
+            Er::NotPartitionOwner(_) => 114,
+            // protocol problems, the connection is closed
+            Er::BadAuth => 200,
+            Er::InvalidSequence => 201,
+            Er::NotReady => 202,
+            Er::IsClosed => 203,
+            Er::NoConsumerStart => 204,
+            Er::FailedToReadDataStart => 205,
+            Er::IsNone => 206,
+            Er::UnexpectedRecordType => 207,
+            Er::UnsupportedVersion(_, _, _) => 208,
+            Er::FrameTooLarge(_, _) => 209,
+            // server problems, the connection is closed
+            Er::ClientTcpRead(_) => 300,
+            Er::ClientTcpWrite(_) => 301,
+            Er::ServerTcpRead(_) => 302,
+            Er::ServerTcpWrite(_) => 303,
+            Er::CantReadFile(_) => 304,
+            Er::CantWriteFile(_) => 305,
+            Er::CantSendFile(_) => 306,
+            Er::CantReadDir(_) => 307,
+            Er::CantOpenFile(_) => 308,
+            Er::InotifyError(_) => 309,
+            Er::PollError(_) => 310,
+            Er::FailedToReturnMessage(_) => 311,
+            Er::InvalidEventMask => 312,
+            Er::BadFileName => 313,
+            Er::BadOffset(_, _) => 314,
+            Er::BadConfig(_) => 315,
+            Er::InconsistentSegments(_) => 316,
+            Er::TlsError(_) => 317,
+            Er::ServerError(code, _) => *code,
+        }
+    }
+
+    /* after anything other than a problem with one request the client and server can no longer agree where records start */
+    pub fn closes_connection(&self) -> bool {
+        !(100..200).contains(&self.code())
+    }
 }
 
 pub trait LogError {
@@ -102,6 +188,10 @@ impl Display for Er {
                 s = format!("Failed to open topic file :{}", e);
                 s.as_str()
             },
+            Er::PollError(e) => {
+                s = format!("Failed waiting for socket or file events :{}", e);
+                s.as_str()
+            },
             Er::FailedToReturnMessage(e) => {
                 s = format!("Failed to read topic file :{}", e);
                 s.as_str()
@@ -109,6 +199,10 @@ impl Display for Er {
             Er::NoConsumerStart => "Recieved content from server, but never recieved the header message containing start references.",
             Er::FailedToReadDataStart => "Could not read expected start value from consumer header message",
             Er::TopicNotFound => "Trying to retrieve topic - not found in collection",
+            Er::PartitionNotFound(topic_id, partition) => {
+                s = format!("Topic {} has no partition {}", topic_id, partition);
+                s.as_str()
+            },
             Er::BadFileName => "Trying to parse a file for topic - bad filename",
             Er::IsNone => "Value returned as 'None' but this should not be possible",
             Er::InvalidEventMask => "Event mask returned from event is unexpected",
@@ -116,12 +210,102 @@ impl Display for Er {
                 s = format!("Bad topic filename {} - cannot parse the hex offset value :{}", f_name, e);
                 s.as_str()
             },
+            Er::OffsetOutOfRange(index, earliest) => {
+                s = format!("Record index {} has been removed by retention, earliest available is {}", index, earliest);
+                s.as_str()
+            },
+            Er::BadChecksum(expected, actual) => {
+                s = format!("Record checksum mismatch, stored crc32c {:08x} but content gives {:08x}", expected, actual);
+                s.as_str()
+            },
+            Er::BadRecordFormat(message) => {
+                s = format!("Record read from topic is malformed : {}", message);
+                s.as_str()
+            },
+            Er::RecordTooLarge(size, max) => {
+                s = format!("Record of {} bytes is larger than the {} bytes allowed", size, max);
+                s.as_str()
+            },
             Er::ParseError(message) => {
                 s = format!("Error coverting to type {}", message);
                 s.as_str()
             },
+            Er::BadStartMode(mode) => {
+                s = format!("Consumer start mode {} is unknown, expected earliest(0), latest(1), index(2) or timestamp(3)", mode);
+                s.as_str()
+            },
+            Er::BadGroupName(group) => {
+                s = format!("Consumer group name {:?} should be 1 to {} letters, digits, '-' or '_'", group, MAX_GROUP_NAME);
+                s.as_str()
+            },
+            Er::NotInGroup => "Offsets can only be committed by a consumer started as a member of a group",
+            Er::FeatureNotAgreed(feature) => {
+                s = format!("Request needs feature {:#x}, which was not agreed in the handshake", feature);
+                s.as_str()
+            },
+            Er::UnknownTopic(name) => {
+                s = format!("There is no topic called {:?}", name);
+                s.as_str()
+            },
+            Er::AccessDenied(principal, op, topic) => {
+                s = format!("{} is not allowed to {} on topic {}", principal, op, topic);
+                s.as_str()
+            },
+            Er::StaleGeneration(generation, current) => {
+                s = format!("Commit is for generation {} of the group, which is now at {}", generation, current);
+                s.as_str()
+            },
+            Er::NotPartitionOwner(partition) => {
+                s = format!("Partition {} is not assigned to this member of the group", partition);
+                s.as_str()
+            },
+            Er::UnexpectedRecordType => "Record type is unknown or not expected on this connection",
+            Er::BadConfig(message) => {
+                s = format!("Bad configuration, {}", message);
+                s.as_str()
+            },
+            Er::InconsistentSegments(message) => {
+                s = format!("Topic segments don't match, {}", message);
+                s.as_str()
+            },
+            Er::TlsError(message) => {
+                s = format!("TLS problem, {}", message);
+                s.as_str()
+            },
+            Er::UnsupportedVersion(version, min, max) => {
+                s = format!("Protocol version {} is not supported, server speaks versions {} to {}", version, min, max);
+                s.as_str()
+            },
+            Er::FrameTooLarge(size, max) => {
+                s = format!("Frame of {} bytes is larger than the {} allowed before authenticating", size, max);
+                s.as_str()
+            },
+            Er::ServerError(code, message) => {
+                s = format!("Server returned error {} : {}", code, message);
+                s.as_str()
+            },
         };
         f.write_str(message)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(Er::TopicNotFound.code(), 100);
+        assert_eq!(Er::RecordTooLarge(2, 1).code(), 102);
+        assert_eq!(Er::BadAuth.code(), 200);
+        assert_eq!(Er::InvalidSequence.code(), 201);
+        assert_eq!(Er::ServerError(105, String::from("gone")).code(), 105, "server errors keep the code they were sent with");
+
+        assert!(!Er::TopicNotFound.closes_connection(), "only the request fails");
+        assert!(!Er::RecordTooLarge(2, 1).closes_connection());
+        assert!(Er::BadAuth.closes_connection());
+        assert!(Er::InvalidSequence.closes_connection());
+        assert!(Er::CantWriteFile(io::Error::from(io::ErrorKind::Other)).closes_connection());
+    }
+}
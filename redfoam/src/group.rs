@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::{trace, log_warn};
+use super::consumer::ConsumerClient;
+use super::tcp::RecordType;
+use super::er::Er;
+
+/*
+ * Members of a consumer group join with a JoinGroup record on a connection they keep
+ * open, and are sent an Assignment record whenever the group's partitions are divided
+ * up again, which they answer with an AssignmentAck once they have applied it :
+ *
+ *   client -> server   JoinGroup       topic_id[4] | session_timeout_ms[4] | group
+ *   client -> server   Heartbeat       (empty)
+ *   server -> client   Assignment      generation[4] | member[4] | partitions[4 * n]
+ *   client -> server   AssignmentAck   generation[4] | (partition[4] | record_index[8]) * n
+ *
+ * Members read their partitions on connections of their own, started from the group's
+ * committed offsets, and say which member and generation they read for in each commit.
+ * A commit is only taken from the member a partition was given to.
+ *
+ * Partitions stay with their members as the group changes, only the ones needed to even
+ * the group out move. A partition moving between members is first taken out of its old
+ * member's assignment, and is only given to the new member once the old member's
+ * AssignmentAck has committed what it read and stopped reading it, so no records are
+ * read twice. The pairs in an AssignmentAck are those commits.
+ *
+ * A member leaves when its connection closes, or when nothing has been heard from it,
+ * or no AssignmentAck giving up a partition, for its session timeout.
+ */
+
+/* a member's request to join, as read from JoinGroup */
+pub struct Join {
+    pub topic_id : u32,
+    pub group : String,
+    pub session_timeout : Duration,
+    pub partitions : u32, /* of the topic when the member joined */
+}
+
+struct Member {
+    session_timeout : Duration,
+    assigned : Vec<u32>, /* in its last Assignment */
+    revoking : Option<Instant>, /* when it was first asked to give up partitions it has not yet acknowledged */
+}
+
+/* the member reading a partition, and the generation it was given it in */
+struct Owner {
+    member : u32,
+    since : u32,
+}
+
+struct Group {
+    partitions : u32,
+    members : BTreeMap<u32, Member>, /* by client id */
+    owners : HashMap<u32, Owner>, /* by partition, kept until the member acknowledges giving it up */
+    moving : HashMap<u32, u32>, /* partitions waiting to be given up, and the member they go to */
+    generation : u32,
+    changed : bool,
+}
+impl Group {
+    /* drops a member along with the partitions it owned, false if it was not a member */
+    fn remove(&mut self, client_id : u32) -> bool {
+        self.owners.retain(|_, owner| owner.member != client_id);
+        self.members.remove(&client_id).is_some()
+    }
+
+    /*
+     * starts a new generation, returning what each member is now assigned. Members keep what
+     * they own and are given what nobody owns, partitions moving to them are left out until
+     * their old member has acknowledged giving them up
+     */
+    fn rebalance(&mut self) -> Vec<(u32, Vec<u32>)> {
+        self.generation = self.generation.wrapping_add(1);
+
+        // partitions given up since the last generation go where they were moving to
+        let mut owned : HashMap<u32, u32> = self.moving.drain().collect();
+        owned.extend(self.owners.iter().map(|(p, owner)| (*p, owner.member)));
+        let members : Vec<u32> = self.members.keys().copied().collect();
+        let mut assignments = Vec::new();
+        for (client_id, partitions) in assign(&members, self.partitions, &owned) {
+            let mut assigned = Vec::new();
+            for p in partitions {
+                match self.owners.get(&p) {
+                    Some(owner) if owner.member != client_id => {
+                        self.moving.insert(p, client_id);
+                        continue
+                    },
+                    Some(_) => {},
+                    None => { self.owners.insert(p, Owner { member : client_id, since : self.generation }); },
+                }
+                assigned.push(p);
+            }
+
+            let giving_up = self.owners.iter().any(|(p, owner)| owner.member == client_id && !assigned.contains(p));
+            if let Some(member) = self.members.get_mut(&client_id) {
+                member.revoking = match giving_up {
+                    true => member.revoking.or_else(|| Some(Instant::now())),
+                    false => None,
+                };
+                member.assigned = assigned.clone();
+            }
+            assignments.push((client_id, assigned));
+        }
+        assignments
+    }
+
+    /* frees the partitions client_id gave up in generation, so the next rebalance hands them on */
+    fn release(&mut self, client_id : u32, generation : u32) {
+        if generation != self.generation {
+            return // a later Assignment is on its way, the ack for that one will do
+        }
+        let member = match self.members.get_mut(&client_id) {
+            Some(member) => member,
+            None => return,
+        };
+        member.revoking = None;
+
+        let before = self.owners.len();
+        let assigned = &member.assigned;
+        self.owners.retain(|p, owner| owner.member != client_id || assigned.contains(p));
+        if self.owners.len() != before {
+            self.changed = true;
+        }
+    }
+
+    /* Ok if client_id owns partition, and generation is no older than when it was given it */
+    fn check_owner(&self, partition : u32, generation : u32, client_id : u32) -> Result<(), Er> {
+        match self.owners.get(&partition) {
+            Some(owner) if owner.member == client_id => match generation >= owner.since && generation <= self.generation {
+                true => Ok(()),
+                false => Err(Er::StaleGeneration(generation, self.generation)),
+            },
+            _ => Err(Er::NotPartitionOwner(partition)),
+        }
+    }
+}
+
+pub struct Groups {
+    groups : HashMap<(u32, String), Group>,
+}
+impl Groups {
+    pub fn new() -> Groups {
+        Groups { groups : HashMap::new() }
+    }
+
+    pub fn join(&mut self, client_id : u32, join : Join) {
+        trace!("client {} joining group {} on topic {}", client_id, join.group, join.topic_id);
+        let group = self.groups.entry((join.topic_id, join.group)).or_insert(Group {
+            partitions : join.partitions,
+            members : BTreeMap::new(),
+            owners : HashMap::new(),
+            moving : HashMap::new(),
+            generation : 0,
+            changed : false,
+        });
+        group.partitions = join.partitions;
+        group.members.insert(client_id, Member { session_timeout : join.session_timeout, assigned : Vec::new(), revoking : None });
+        group.changed = true;
+    }
+
+    pub fn leave(&mut self, client_id : u32) {
+        for group in self.groups.values_mut() {
+            if group.remove(client_id) {
+                group.changed = true;
+            }
+        }
+    }
+
+    /* partitions client_id was last assigned in group, None if it is not a member */
+    pub fn assignment(&self, topic_id : u32, group : &str, client_id : u32) -> Option<Vec<u32>> {
+        let group = self.groups.get(&(topic_id, String::from(group)))?;
+        group.members.get(&client_id).map(|member| member.assigned.clone())
+    }
+
+    /* an AssignmentAck from client_id, once what it read of the partitions it gave up has been committed */
+    pub fn release(&mut self, topic_id : u32, group : &str, client_id : u32, generation : u32) {
+        if let Some(group) = self.groups.get_mut(&(topic_id, String::from(group))) {
+            group.release(client_id, generation);
+        }
+    }
+
+    /*
+     * Ok if a commit to partition may be taken. member is the (generation, client id) the
+     * commit was sent for, None from consumers outside the group's membership, which may
+     * only commit while the group has no members
+     */
+    pub fn check_commit(&self, topic_id : u32, group : &str, partition : u32, member : Option<(u32, u32)>) -> Result<(), Er> {
+        match (self.groups.get(&(topic_id, String::from(group))), member) {
+            (Some(group), Some((generation, client_id))) => group.check_owner(partition, generation, client_id),
+            (None, None) => Ok(()),
+            _ => Err(Er::NotPartitionOwner(partition)),
+        }
+    }
+
+    /*
+     * removes members that have gone, stopped sending heartbeats or not given up partitions
+     * when asked, then sends every member of a group that has changed its new assignment.
+     * Called each pass of the server loop
+     */
+    pub fn check(&mut self, client_list : &mut HashMap<u32, ConsumerClient>) {
+        for ((topic_id, name), group) in self.groups.iter_mut() {
+            let mut gone = Vec::new();
+            for (client_id, member) in group.members.iter() {
+                match client_list.get_mut(client_id) {
+                    None => gone.push(*client_id),
+                    Some(client) if client.last_seen().elapsed() > member.session_timeout => {
+                        log_warn!("consumer {} timed out of group {} on topic {}", client_id, name, topic_id);
+                        client.close();
+                        gone.push(*client_id);
+                    },
+                    Some(client) if member.revoking.is_some_and(|since| since.elapsed() > member.session_timeout) => {
+                        log_warn!("consumer {} did not give up partitions of group {} on topic {}", client_id, name, topic_id);
+                        client.close();
+                        gone.push(*client_id);
+                    },
+                    Some(_) => {},
+                }
+            }
+            for client_id in gone {
+                group.remove(client_id);
+                group.changed = true;
+            }
+
+            if !group.changed {
+                continue;
+            }
+            group.changed = false;
+
+            for (client_id, partitions) in group.rebalance() {
+                trace!("group {} generation {} gives client {} partitions {:?}", name, group.generation, client_id, partitions);
+                let mut payload = Vec::with_capacity(4 + 4 + 4 * partitions.len());
+                payload.extend_from_slice(&group.generation.to_le_bytes());
+                payload.extend_from_slice(&client_id.to_le_bytes());
+                for p in partitions {
+                    payload.extend_from_slice(&p.to_le_bytes());
+                }
+
+                if let Some(client) = client_list.get_mut(&client_id) {
+                    if let Err(e) = client.send_feed(0, &payload, RecordType::Assignment) {
+                        log_warn!("failed to send assignment to consumer {} : {}", client_id, e);
+                        client.close();
+                    }
+                }
+            }
+        }
+
+        self.groups.retain(|_, group| !group.members.is_empty());
+    }
+}
+impl Default for Groups {
+    fn default() -> Self { Groups::new() }
+}
+
+/*
+ * divides partitions between members, given the member that owns each partition now.
+ * Members end up with as near the same number as can be, those ordered first by client id
+ * taking any left over. Members keep what they own, apart from any over their share, and
+ * partitions nobody keeps go to whoever has fewest. Members past the partition count get none
+ */
+pub fn assign(members : &[u32], partitions : u32, owned : &HashMap<u32, u32>) -> BTreeMap<u32, Vec<u32>> {
+    let mut assignment : BTreeMap<u32, Vec<u32>> = members.iter().map(|m| (*m, Vec::new())).collect();
+    if members.is_empty() {
+        return assignment
+    }
+
+    let partitions = partitions.max(1); // unpartitioned topics are one partition
+    let share = partitions as usize / members.len();
+    let mut larger = partitions as usize % members.len(); // members that can have one more than share
+
+    let mut free = Vec::new();
+    for p in 0..partitions {
+        match owned.get(&p).and_then(|m| assignment.get_mut(m)) {
+            Some(kept) => kept.push(p),
+            None => free.push(p),
+        }
+    }
+
+    for kept in assignment.values_mut() {
+        let limit = match larger > 0 && kept.len() > share {
+            true => { larger -= 1; share + 1 },
+            false => share,
+        };
+        while kept.len() > limit {
+            free.extend(kept.pop());
+        }
+    }
+
+    free.sort_unstable();
+    for p in free {
+        let fewest = assignment.values_mut().min_by_key(|a| a.len());
+        if let Some(fewest) = fewest {
+            fewest.push(p);
+        }
+    }
+    for assigned in assignment.values_mut() {
+        assigned.sort_unstable();
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* what check does for changed groups, without the connections */
+    fn rebalance(groups : &mut Groups) {
+        for group in groups.groups.values_mut().filter(|group| group.changed) {
+            group.changed = false;
+            group.rebalance();
+        }
+    }
+
+    #[test]
+    fn test_assign() {
+        let assignment = assign(&[3, 5, 9], 8, &HashMap::new());
+        assert_eq!(assignment[&3], vec![0, 3, 6]);
+        assert_eq!(assignment[&5], vec![1, 4, 7]);
+        assert_eq!(assignment[&9], vec![2, 5]);
+
+        let owned : HashMap<u32, u32> = assignment.iter().flat_map(|(m, ps)| ps.iter().map(move |p| (*p, *m))).collect();
+        let joined = assign(&[3, 5, 9, 11], 8, &owned);
+        assert_eq!(joined[&3], vec![0, 3], "members keep what they have, up to their share");
+        assert_eq!(joined[&5], vec![1, 4]);
+        assert_eq!(joined[&9], vec![2, 5]);
+        assert_eq!(joined[&11], vec![6, 7], "only what the others gave up moves");
+
+        let left = assign(&[3, 9], 8, &owned);
+        assert_eq!(left[&3], vec![0, 3, 4, 6]);
+        assert_eq!(left[&9], vec![1, 2, 5, 7], "partitions of members that leave are shared out");
+
+        let crowded = assign(&[1, 2, 3], 2, &HashMap::new());
+        assert_eq!(crowded[&3], Vec::<u32>::new(), "more members than partitions leaves some idle");
+        assert_eq!(assign(&[4], 0, &HashMap::new())[&4], vec![0], "unpartitioned topics are one partition");
+        assert!(assign(&[], 4, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_join_leave() {
+        let mut groups = Groups::new();
+        let join = |group : &str| Join { topic_id : 1, group : String::from(group), session_timeout : Duration::from_secs(10), partitions : 4 };
+
+        groups.join(1, join("billing"));
+        rebalance(&mut groups);
+        assert_eq!(groups.assignment(1, "billing", 1), Some(vec![0, 1, 2, 3]), "a lone member reads everything");
+
+        groups.join(2, join("billing"));
+        groups.join(3, join("audit"));
+        rebalance(&mut groups);
+        assert_eq!(groups.assignment(1, "billing", 1), Some(vec![0, 1]), "half is taken away");
+        assert_eq!(groups.assignment(1, "billing", 2), Some(vec![]), "but not handed on until it is given up");
+        assert_eq!(groups.assignment(1, "audit", 3), Some(vec![0, 1, 2, 3]), "groups are divided up separately");
+        assert!(groups.check_commit(1, "billing", 2, Some((2, 1))).is_ok(), "the old member commits what it read before giving it up");
+        assert!(matches!(groups.check_commit(1, "billing", 2, Some((2, 2))), Err(Er::NotPartitionOwner(2))));
+
+        groups.release(1, "billing", 1, 1);
+        assert!(groups.check_commit(1, "billing", 2, Some((2, 1))).is_ok(), "acknowledging an old generation gives nothing up");
+
+        groups.release(1, "billing", 1, 2);
+        rebalance(&mut groups);
+        assert_eq!(groups.assignment(1, "billing", 1), Some(vec![0, 1]));
+        assert_eq!(groups.assignment(1, "billing", 2), Some(vec![2, 3]), "given up partitions go to the new member");
+        assert!(matches!(groups.check_commit(1, "billing", 2, Some((3, 1))), Err(Er::NotPartitionOwner(2))), "the old member can no longer commit");
+        assert!(groups.check_commit(1, "billing", 2, Some((3, 2))).is_ok());
+        assert!(matches!(groups.check_commit(1, "billing", 2, Some((2, 2))), Err(Er::StaleGeneration(2, 3))), "from before it was given the partition");
+        assert!(matches!(groups.check_commit(1, "billing", 0, None), Err(Er::NotPartitionOwner(0))), "only members commit while a group has them");
+        assert!(groups.check_commit(1, "reports", 0, None).is_ok(), "groups without members take any commit");
+
+        groups.leave(1);
+        rebalance(&mut groups);
+        assert_eq!(groups.assignment(1, "billing", 2), Some(vec![0, 1, 2, 3]), "partitions of members that leave are taken over straight away");
+        assert_eq!(groups.assignment(1, "billing", 1), None);
+    }
+}
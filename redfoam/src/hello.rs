@@ -0,0 +1,153 @@
+use super::buff::Buff;
+use super::er::Er;
+use super::tcp::RecordType;
+
+/*
+ * Clients open a connection with a Hello giving the newest protocol version they
+ * speak and the features they support. Servers speak every version from
+ * MIN_PROTOCOL_VERSION to their own, and answer with the version both will use, the
+ * features both support and the largest record it accepts. A client with a version
+ * outside that range is sent an UnsupportedVersion error and the connection closed.
+ *
+ *   client -> server   version[2] | features[4]
+ *   server -> client   version[2] | features[4] | max_record_bytes[8]
+ *
+ * Clients from before the handshake start with Auth, and are taken as version 1.
+ */
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const FEATURE_KEYS: u32 = 0x01;
+pub const FEATURE_HEADERS: u32 = 0x02;
+pub const FEATURE_PARTITIONS: u32 = 0x04;
+pub const FEATURE_COMPRESSION: u32 = 0x08;
+pub const FEATURE_TAGGED_FEEDS: u32 = 0x10; // feed frames start topic_id[4] | partition[4], so one connection can follow many topics
+
+// everything this build supports, compression is reserved for a later version
+pub const SUPPORTED_FEATURES: u32 = FEATURE_KEYS | FEATURE_HEADERS | FEATURE_PARTITIONS | FEATURE_TAGGED_FEEDS;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version : u16,
+    pub features : u32,
+    pub max_record_bytes : u64,
+}
+impl Hello {
+    /* what this build offers, max_record_bytes is only known to servers */
+    pub fn local(max_record_bytes : u64) -> Hello {
+        Hello { version : PROTOCOL_VERSION, features : SUPPORTED_FEATURES, max_record_bytes }
+    }
+
+    /* reads a client Hello once all of it is in the buffer */
+    pub fn new(buff : &mut Buff) -> Result<Option<Self>, Er> {
+        if !buff.is_end_of_record() {
+            return Ok(None)
+        }
+
+        let version = buff.read_u16().ok_or(Er::UnsupportedVersion(0, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION))?;
+        let features = buff.read_u32().unwrap_or(0);
+        Ok(Some(Hello { version, features, max_record_bytes : 0 }))
+    }
+
+    /* reads the server's answer to a Hello */
+    pub fn from_reply(buff : &mut Buff) -> Result<Self, Er> {
+        let version = buff.read_u16().ok_or(Er::IsNone)?;
+        let features = buff.read_u32().ok_or(Er::IsNone)?;
+        let max_record_bytes = buff.read_u64().ok_or(Er::IsNone)?;
+        Ok(Hello { version, features, max_record_bytes })
+    }
+
+    /* terms for a connection from a client that sent this Hello, or why the client can't be served */
+    pub fn agree(&self, server : &Hello) -> Result<Hello, Er> {
+        if !(MIN_PROTOCOL_VERSION..=server.version).contains(&self.version) {
+            return Err(Er::UnsupportedVersion(self.version, MIN_PROTOCOL_VERSION, server.version))
+        }
+
+        Ok(Hello {
+            version : self.version,
+            features : self.features & server.features,
+            max_record_bytes : server.max_record_bytes,
+        })
+    }
+
+    pub fn supports(&self, feature : u32) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn request_frame(&self, seq : u8) -> Vec<u8> {
+        let size : u32 = 4 + 1 + 1 + 2 + 4;
+        let mut frame = Vec::with_capacity(size as usize);
+        frame.extend_from_slice(&size.to_le_bytes());
+        frame.push(seq);
+        frame.push(RecordType::Hello as u8);
+        frame.extend_from_slice(&self.version.to_le_bytes());
+        frame.extend_from_slice(&self.features.to_le_bytes());
+        frame
+    }
+
+    pub fn reply_frame(&self) -> Vec<u8> {
+        let size : u32 = 4 + 1 + 2 + 4 + 8;
+        let mut frame = Vec::with_capacity(size as usize);
+        frame.extend_from_slice(&size.to_le_bytes());
+        frame.push(RecordType::Hello as u8);
+        frame.extend_from_slice(&self.version.to_le_bytes());
+        frame.extend_from_slice(&self.features.to_le_bytes());
+        frame.extend_from_slice(&self.max_record_bytes.to_le_bytes());
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello() {
+        let client = Hello::local(0);
+        let mut bstr : &[u8] = &client.request_frame(0);
+        let mut b = Buff::new();
+        b.read_data(&mut bstr).unwrap();
+        b.rec_size = b.read_u32();
+        b.check_seq().unwrap();
+        assert_eq!(b.read_u8(), Some(RecordType::Hello as u8));
+
+        let received = Hello::new(&mut b).unwrap().expect("whole hello in buffer");
+        assert_eq!(received.version, PROTOCOL_VERSION);
+        assert!(received.supports(FEATURE_HEADERS));
+
+        let agreed = received.agree(&Hello::local(4096)).unwrap();
+        assert_eq!(agreed.max_record_bytes, 4096);
+
+        let mut bstr : &[u8] = &agreed.reply_frame();
+        let mut b = Buff::new();
+        b.read_data(&mut bstr).unwrap();
+        b.rec_size = b.read_u32();
+        assert_eq!(b.read_u8(), Some(RecordType::Hello as u8));
+        assert_eq!(Hello::from_reply(&mut b).unwrap(), agreed);
+    }
+
+    #[test]
+    fn test_agree() {
+        let server = Hello { version : 3, features : FEATURE_KEYS | FEATURE_COMPRESSION, max_record_bytes : 100 };
+
+        let same = Hello { version : 3, features : FEATURE_KEYS | FEATURE_HEADERS | FEATURE_COMPRESSION, max_record_bytes : 0 };
+        let agreed = same.agree(&server).unwrap();
+        assert_eq!(agreed.version, 3);
+        assert_eq!(agreed.features, FEATURE_KEYS | FEATURE_COMPRESSION, "only features both support");
+
+        let older = Hello { version : 2, features : FEATURE_KEYS, max_record_bytes : 0 };
+        assert_eq!(older.agree(&server).unwrap().version, 2, "older clients keep their version");
+
+        let newer = Hello { version : 7, features : FEATURE_KEYS, max_record_bytes : 0 };
+        match newer.agree(&server) {
+            Err(e @ Er::UnsupportedVersion(7, MIN_PROTOCOL_VERSION, 3)) => assert!(e.closes_connection()),
+            _ => assert!(false, "versions above the server's should be rejected"),
+        }
+
+        let ancient = Hello { version : 0, features : 0, max_record_bytes : 0 };
+        match ancient.agree(&server) {
+            Err(Er::UnsupportedVersion(0, MIN_PROTOCOL_VERSION, 3)) => {},
+            _ => assert!(false, "versions below the minimum should be rejected"),
+        }
+    }
+}
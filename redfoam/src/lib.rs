@@ -3,10 +3,19 @@ pub mod config;
 pub mod client;
 pub mod producer;
 pub mod consumer;
+pub mod group;
 pub mod tcp;
+pub mod poll;
 pub mod topic;
 pub mod buff;
+pub mod record;
 pub mod auth;
+pub mod acl;
+pub mod scram;
+pub mod tls;
+pub mod websocket;
+pub mod hello;
+pub mod metadata;
 pub mod er;
 #[cfg(test)]
 pub mod test_support;
@@ -38,3 +38,23 @@ macro_rules! log_error {
             print!("\u{001B}[0m");
         };
 }
+
+#[macro_export]
+macro_rules! log_warn {
+        ($msg:expr) => {
+            let trace_current_time = std::time::SystemTime::now();
+            let trace_timestamp = trace_current_time.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
+            print!("{:?}", trace_timestamp);
+            print!("\u{001B}[33mWARN [{}:{}] : ", file!(), line!());
+            println!($msg);
+            print!("\u{001B}[0m");
+        };
+        ($($msg:expr),*) => {
+            let trace_current_time = std::time::SystemTime::now();
+            let trace_timestamp = trace_current_time.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
+            print!("{:?}", trace_timestamp);
+            print!("\u{001B}[33mWARN [{}:{}] : ", file!(), line!());
+            println!($($msg,)*);
+            print!("\u{001B}[0m");
+        };
+}
@@ -1,14 +1,55 @@
 use redfoam::tcp;
+use redfoam::config::Config;
+use redfoam::auth::{self, SecretHash};
 use std::env;
+use std::io::{self, BufRead};
+use std::process;
+use std::thread;
 
 fn main() {
-    println!("start");
+    let path = match env::args().nth(1).or_else(|| env::var("REDFOAM_CONFIG").ok()) {
+        Some(command) if command == "hash-secret" => {
+            hash_secret();
+            return
+        },
+        Some(path) => path,
+        None => {
+            eprintln!("usage : redfoam <config file>, or set REDFOAM_CONFIG");
+            eprintln!("        redfoam hash-secret < secret");
+            process::exit(2);
+        },
+    };
 
-    let addr = env::args()
-            .nth(1)
-            .unwrap_or_else(|| "127.0.0.1:9090".to_string());
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
 
-    tcp::run_server(addr);
-    tcp::run_consumer_server("127.0.0.1:9091".to_string());
+    println!("start node {}", config.node_id);
+
+    // both servers loop forever, so the producer gets a thread of its own
+    let producer_config = config.clone();
+    let producer = thread::spawn(move || tcp::run_server(&producer_config));
+    tcp::run_consumer_server(&config);
+    let _ = producer.join();
 }
 
+/* prints the credentials file value for the secret on the first line of stdin */
+fn hash_secret() {
+    let mut secret = String::new();
+    if let Err(e) = io::stdin().lock().read_line(&mut secret) {
+        eprintln!("can't read secret : {}", e);
+        process::exit(1);
+    }
+    let salt = match auth::random_salt() {
+        Ok(salt) => salt,
+        Err(e) => {
+            eprintln!("can't make a salt : {}", e);
+            process::exit(1);
+        },
+    };
+    println!("{}", SecretHash::new(secret.trim_end_matches(&['\r', '\n'][..]).as_bytes(), &salt, auth::DEFAULT_ITERATIONS));
+}
@@ -0,0 +1,115 @@
+use super::buff::Buff;
+use super::er::Er;
+use super::tcp::RecordType;
+
+/*
+ * Clients name topics, the protocol uses their ids. A Metadata request lists the names
+ * a client wants ids for, or none for every topic, and either server answers with each :
+ *
+ *   client -> server   count[2] | count * ( name_len[2] | name[name_len] )
+ *   server -> client   count[2] | count * ( topic_id[4] | partitions[4] | name_len[2] | name[name_len] )
+ *
+ * A name the server has no topic for fails the whole request with UnknownTopic.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicMetadata {
+    pub topic_id : u32,
+    pub partitions : u32, /* 1 for topics that are not partitioned */
+    pub name : String,
+}
+
+/* reads the names asked for once all of the request is in the buffer */
+pub fn read_request(buff : &mut Buff) -> Result<Option<Vec<String>>, Er> {
+    if !buff.is_end_of_record() {
+        return Ok(None)
+    }
+
+    let count = buff.read_u16().ok_or(Er::IsNone)?;
+    let mut names = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        names.push(read_name(buff)?);
+    }
+    Ok(Some(names))
+}
+
+pub fn request_frame(seq : u8, names : &[&str]) -> Vec<u8> {
+    let mut frame = vec![0, 0, 0, 0, seq, RecordType::Metadata as u8];
+    frame.extend_from_slice(&(names.len() as u16).to_le_bytes());
+    for name in names {
+        push_name(&mut frame, name);
+    }
+    finish(frame)
+}
+
+pub fn reply_frame(topics : &[TopicMetadata]) -> Vec<u8> {
+    let mut frame = vec![0, 0, 0, 0, RecordType::Metadata as u8];
+    frame.extend_from_slice(&(topics.len() as u16).to_le_bytes());
+    for topic in topics {
+        frame.extend_from_slice(&topic.topic_id.to_le_bytes());
+        frame.extend_from_slice(&topic.partitions.to_le_bytes());
+        push_name(&mut frame, &topic.name);
+    }
+    finish(frame)
+}
+
+/* reads the server's answer, once its record type has been read */
+pub fn from_reply(buff : &mut Buff) -> Result<Vec<TopicMetadata>, Er> {
+    let count = buff.read_u16().ok_or(Er::IsNone)?;
+    let mut topics = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let topic_id = buff.read_u32().ok_or(Er::IsNone)?;
+        let partitions = buff.read_u32().ok_or(Er::IsNone)?;
+        let name = read_name(buff)?;
+        topics.push(TopicMetadata { topic_id, partitions, name });
+    }
+    Ok(topics)
+}
+
+fn push_name(frame : &mut Vec<u8>, name : &str) {
+    frame.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    frame.extend_from_slice(name.as_bytes());
+}
+
+fn read_name(buff : &mut Buff) -> Result<String, Er> {
+    let len = buff.read_u16().ok_or(Er::IsNone)? as usize;
+    let mut name = Vec::with_capacity(len);
+    for _ in 0..len {
+        name.push(buff.read_u8().ok_or(Er::IsNone)?);
+    }
+    String::from_utf8(name).map_err(|e| Er::UnknownTopic(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+}
+
+/* fills in the size, now the rest of the frame is known */
+fn finish(mut frame : Vec<u8>) -> Vec<u8> {
+    let size = frame.len() as u32;
+    frame[0..4].copy_from_slice(&size.to_le_bytes());
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let mut bstr : &[u8] = &request_frame(0, &["orders", "payments"]);
+        let mut b = Buff::new();
+        b.read_data(&mut bstr).unwrap();
+        b.rec_size = b.read_u32();
+        b.check_seq().unwrap();
+        assert_eq!(b.read_u8(), Some(RecordType::Metadata as u8));
+        assert_eq!(read_request(&mut b).unwrap(), Some(vec![String::from("orders"), String::from("payments")]));
+
+        let topics = vec![
+            TopicMetadata { topic_id : 4, partitions : 8, name : String::from("orders") },
+            TopicMetadata { topic_id : 9, partitions : 1, name : String::from("payments") },
+        ];
+        let mut bstr : &[u8] = &reply_frame(&topics);
+        let mut b = Buff::new();
+        b.read_data(&mut bstr).unwrap();
+        b.rec_size = b.read_u32();
+        assert_eq!(b.read_u8(), Some(RecordType::Metadata as u8));
+        assert_eq!(from_reply(&mut b).unwrap(), topics);
+        assert!(!b.has_data(), "whole reply read");
+    }
+}
@@ -0,0 +1,140 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use super::er::Er;
+
+// most events handled from one call to epoll_wait, any more are picked up by the next call
+const MAX_EVENTS: usize = 256;
+
+/* an fd registered with Poll that has something to read, room to write if watched, or has been closed by the other end */
+pub struct Ready {
+    pub token : u64,
+    pub closed : bool,
+}
+
+/*
+ * level triggered epoll over the sockets, listener and inotify fd a server owns.
+ * level triggered means anything left unread is reported again on the next wait,
+ * so handlers can read as much as fits in their buffer and leave the rest
+ */
+pub struct Poll {
+    epfd : RawFd,
+    events : Vec<libc::epoll_event>,
+}
+impl Poll {
+    pub fn new() -> Result<Poll, Er> {
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            return Err(Er::PollError(io::Error::last_os_error()))
+        }
+
+        Ok(Poll {
+            epfd,
+            events : vec![libc::epoll_event { events : 0, u64 : 0 }; MAX_EVENTS],
+        })
+    }
+
+    /* fds are removed by the kernel when they are closed, so there is no matching remove */
+    pub fn add(&self, fd : RawFd, token : u64) -> Result<(), Er> {
+        let mut event = libc::epoll_event {
+            events : (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64 : token,
+        };
+
+        if unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            return Err(Er::PollError(io::Error::last_os_error()))
+        }
+        Ok(())
+    }
+
+    /*
+     * also reports fd as ready while its socket has room to write, for clients with
+     * writes queued. Only set while something is queued, as it is almost always true
+     */
+    pub fn watch_writable(&self, fd : RawFd, token : u64, writable : bool) -> Result<(), Er> {
+        let events = match writable {
+            true => libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLOUT,
+            false => libc::EPOLLIN | libc::EPOLLRDHUP,
+        };
+        let mut event = libc::epoll_event { events : events as u32, u64 : token };
+
+        if unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, fd, &mut event) } < 0 {
+            return Err(Er::PollError(io::Error::last_os_error()))
+        }
+        Ok(())
+    }
+
+    /* waits until at least one fd is ready, or timeout has passed */
+    pub fn wait(&mut self, timeout : Duration) -> Result<Vec<Ready>, Er> {
+        let n = unsafe {
+            libc::epoll_wait(self.epfd, self.events.as_mut_ptr(), MAX_EVENTS as i32, timeout.as_millis() as i32)
+        };
+
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new())
+            }
+            return Err(Er::PollError(e))
+        }
+
+        let closed_mask = (libc::EPOLLRDHUP | libc::EPOLLHUP | libc::EPOLLERR) as u32;
+
+        Ok(self.events[..n as usize].iter()
+            .map(|e| {
+                // copy out of the packed struct before use
+                let (events, token) = (e.events, e.u64);
+                Ready { token, closed : events & closed_mask != 0 }
+            })
+            .collect())
+    }
+}
+impl Drop for Poll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epfd); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.add(listener.as_raw_fd(), 7).unwrap();
+
+        assert!(poll.wait(Duration::from_millis(10)).unwrap().is_empty(), "nothing ready before a connection");
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let ready = poll.wait(Duration::from_secs(1)).unwrap();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].token, 7, "listener ready to accept");
+
+        let (server, _) = listener.accept().unwrap();
+        poll.add(server.as_raw_fd(), 8).unwrap();
+
+        client.write_all(b"hello").unwrap();
+        let ready = poll.wait(Duration::from_secs(1)).unwrap();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].token, 8);
+        assert!(!ready[0].closed);
+
+        let mut buf = [0u8; 5];
+        (&server).read_exact(&mut buf).unwrap();
+        poll.watch_writable(server.as_raw_fd(), 8, true).unwrap();
+        let ready = poll.wait(Duration::from_secs(1)).unwrap();
+        assert_eq!(ready.len(), 1, "an empty socket has room to write");
+        poll.watch_writable(server.as_raw_fd(), 8, false).unwrap();
+        assert!(poll.wait(Duration::from_millis(10)).unwrap().is_empty());
+
+        drop(client);
+        let ready = poll.wait(Duration::from_secs(1)).unwrap();
+        assert!(ready[0].closed, "hang up reported once the client goes");
+    }
+}
@@ -1,74 +1,329 @@
-use std::net::{TcpStream};
 use std::io::Write;
 use super::topic::{TopicList};
-use super::buff::{Buff};
-use super::tcp::{BufferState, RecordType};
-use super::auth::Auth;
+use super::buff::{Buff, MAX_BUFF_SIZE, MAX_PRE_AUTH_SIZE};
+use super::tcp::{BufferState, Connection, RecordType, send_error};
+use super::auth::{Auth, Credentials, Principal};
+use super::acl::Operation;
+use super::scram::ScramServer;
+use super::hello::Hello;
+use super::tls::Stream;
+use super::poll::Poll;
+use super::metadata;
 use super::er::Er;
+use super::record::FLAG_KEY;
+use super::log_warn;
 
 pub struct ProducerClient {
     state : BufferState,
     buff : Buff,
-    tcp : TcpStream,
+    tcp : Connection,
     auth : Option<Auth>,
+    scram : Option<ScramServer>, /* challenge sent, waiting on the proof */
+    hello : Option<Hello>, /* terms agreed in the handshake, None for clients from before it */
     rec_type : Option<RecordType>,
     topic_id : Option<u32>,
+    flags : Option<u8>,
+    key_len : Option<u16>,
+    partition : Option<u32>,
+    record : Vec<u8>, /* body of the record being read, written to the topic once it is whole */
+    discard : bool, /* rest of the current record is dropped as it arrives */
 }
 impl ProducerClient {
-    pub fn new (stream : TcpStream) -> ProducerClient {
-        let buff = Buff::new();
+    pub fn new (stream : Stream) -> ProducerClient {
+        let buff = Buff::with_max(MAX_PRE_AUTH_SIZE);
 
         ProducerClient {
             state : BufferState::Pending, 
             buff : buff,
-            tcp : stream,
+            tcp : Connection::new(stream),
             auth : None,
+            scram : None,
+            hello : None,
             rec_type : None, 
             topic_id : None,
+            flags : None,
+            key_len : None,
+            partition : None,
+            record : Vec::new(),
+            discard : false,
         }
     }
 
-    pub fn process(&mut self, topic_list : &mut TopicList) -> Result<(),Er> {
+    /* 
+     * epoll only reports the socket again when more arrives, so everything already
+     * buffered is handled now, until a pass makes no progress
+     */
+    pub fn process(&mut self, topic_list : &mut TopicList, credentials : &Credentials) -> Result<(),Er> {
+
+        let mut rejected = None;
+        loop {
+            let size = match self.buff.read_data(&mut self.tcp) {
+                Ok(size) => size,
+                Err(e) => {
+                    self.reject(&e);
+                    return Err(e)
+                },
+            };
+
+            loop {
+                let before = self.buff.position();
+                if let Err(e) = self.process_buffered(topic_list, credentials) {
+                    self.reject(&e);
+                    if e.closes_connection() {
+                        return Err(e)
+                    }
+                    rejected = Some(e);
+                }
+                if self.buff.position() == before {
+                    break;
+                }
+            }
+
+            // TLS may have decrypted more than the buffer took, which epoll won't report again
+            if size == 0 || !self.tcp.has_buffered() {
+                return match rejected {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /* sends the error to the client, then drops either the record that failed or the connection */
+    fn reject(&mut self, e : &Er) {
+        if let Err(send_err) = send_error(&mut self.tcp, self.buff.seq, e) {
+            log_warn!("failed to send error to producer : {}", send_err);
+        }
+        if e.closes_connection() {
+            self.state = BufferState::Closed;
+        } else {
+            match self.rec_type {
+                // the rest of the record is read and dropped as it arrives
+                Some(RecordType::Producer) | Some(RecordType::ProducerRecord) => self.discard = true,
+                // requests are only acted on once they are whole
+                _ => {
+                    self.rec_type = None;
+                    self.buff.reset();
+                },
+            }
+        }
+    }
+
+    fn process_buffered(&mut self, topic_list : &mut TopicList, credentials : &Credentials) -> Result<(),Er> {
 
-        self.buff.read_data(&mut self.tcp)?; 
         if self.buff.rec_size.is_none() { self.buff.rec_size = self.buff.read_u32(); }
+        if self.auth.is_none() {
+            // nobody unknown gets to grow the buffer, Hello, Auth and the SCRAM exchange are all small
+            if let Some(size) = self.buff.rec_size.filter(|size| *size as usize > MAX_PRE_AUTH_SIZE) {
+                return Err(Er::FrameTooLarge(size as u64, MAX_PRE_AUTH_SIZE as u64))
+            }
+        }
         self.buff.check_seq()?;
         if self.rec_type.is_none() { self.rec_type = self.buff.read_u8().map(|r| r.into()) }
 
         match self.rec_type {
+            Some(RecordType::Hello) => {
+                if self.auth.is_some() || self.hello.is_some() {
+                    return Err(Er::UnexpectedRecordType) // only as the first record
+                }
+                if let Some(hello) = Hello::new(&mut self.buff)? {
+                    let agreed = hello.agree(&Hello::local(topic_list.max_record_bytes()))?;
+                    self.tcp.write_all(&agreed.reply_frame())
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.hello = Some(agreed);
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
             Some(RecordType::Auth) => {
-                self.auth = Auth::new(&mut self.buff)?;
+                if self.auth.is_some() || self.scram.is_some() {
+                    return Err(Er::BadAuth) // a connection authenticates once, as one principal
+                }
+                self.auth = Auth::new(&self.buff, credentials, self.tcp.peer_principal())?;
                 if self.auth.is_some() {
                         self.state = BufferState::Active;
+                        self.buff.set_max(MAX_BUFF_SIZE);
                         self.rec_type = None;
                         self.buff.reset();
                 }
                 Ok(())
             }, 
 
-            Some(RecordType::Producer) => {
-                if self.auth.is_some() {
-                    if self.topic_id.is_none() { self.topic_id = self.buff.read_u32(); }
-
-                    if self.buff.has_data() {
-                        if let Some(topic_id) = self.topic_id {
-                            let topic = topic_list.topic_for_id(topic_id)?;
-                            topic.write(self.buff.data())?;
-
-                            if self.buff.is_end_of_record() {
-                                let idx = topic.end_rec()?;
-                                self.tcp.write(&[self.buff.seq]).unwrap();
-                                self.tcp.write(&idx.to_le_bytes()).unwrap();
-                                self.rec_type = None;
-                                self.topic_id = None;
-                            }
-                            self.buff.reset();
+            Some(RecordType::ScramStart) => {
+                if self.auth.is_some() || self.scram.is_some() {
+                    return Err(Er::UnexpectedRecordType) // the connection stays Pending until the one exchange is done
+                }
+                if let Some((scram, challenge)) = ScramServer::start(&mut self.buff, credentials)? {
+                    self.tcp.write_all(&challenge)
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.scram = Some(scram);
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
+            Some(RecordType::ScramProof) => {
+                if !self.buff.is_end_of_record() {
+                    return Ok(())
+                }
+                let (auth, outcome) = self.scram.take().ok_or(Er::UnexpectedRecordType)?
+                    .finish(&mut self.buff)?;
+                self.tcp.write_all(&outcome)
+                    .map_err(Er::ServerTcpWrite)?;
+                self.auth = Some(auth);
+                self.state = BufferState::Active;
+                self.buff.set_max(MAX_BUFF_SIZE);
+                self.rec_type = None;
+                self.buff.reset();
+                Ok(())
+            },
+
+            Some(RecordType::Metadata) => {
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+                if let Some(names) = metadata::read_request(&mut self.buff)? {
+                    let topics = topic_list.metadata(&names)?;
+                    let topics = credentials.acl.visible(self.principal()?, topics, !names.is_empty())?;
+                    self.tcp.write_all(&metadata::reply_frame(&topics))
+                        .map_err(Er::ServerTcpWrite)?;
+                    self.rec_type = None;
+                    self.buff.reset();
+                }
+                Ok(())
+            },
+
+            Some(RecordType::Producer) | Some(RecordType::ProducerRecord) => {
+                if self.discard {
+                    if self.buff.is_end_of_record() {
+                        self.end_record();
+                    }
+                    self.buff.reset();
+                    return Ok(())
+                }
+
+                if self.auth.is_none() {
+                    return Err(Er::BadAuth)
+                }
+
+                if self.topic_id.is_none() { self.topic_id = self.buff.read_u32(); }
+                if self.topic_id.is_some() && self.flags.is_none() {
+                    // plain producer records have no flags byte, their body is all value
+                    self.flags = match self.rec_type {
+                        Some(RecordType::ProducerRecord) => self.buff.read_u8(),
+                        _ => Some(0),
+                    };
+                    if let (Some(topic_id), Some(_)) = (self.topic_id, self.flags) {
+                        self.authorize(topic_list, credentials, topic_id, Operation::Produce)?;
+                        self.check_size(topic_list, topic_id)?;
+                    }
+                }
+
+                if let (Some(topic_id), Some(flags), None) = (self.topic_id, self.flags, self.partition) {
+                    self.partition = self.route(topic_list, topic_id, flags)?;
+                }
+
+                if self.buff.has_data() {
+                    if let (Some(topic_id), Some(flags), Some(partition)) = (self.topic_id, self.flags, self.partition) {
+                        self.record.extend_from_slice(self.buff.data());
+
+                        if self.buff.is_end_of_record() {
+                            // append() has already met the topic durability policy, so it is safe to acknowledge
+                            let idx = topic_list.partition_for_id(topic_id, partition)?.append(&self.record, flags)?;
+                            self.send_ack(idx)?;
+                            self.end_record();
                         }
+                        self.buff.reset();
                     }
                 }
                 Ok(())
             },
-            _ => { Ok(()) },
+            Some(_) => Err(Er::UnexpectedRecordType),
+            None => Ok(()),
+        }
+    }
+
+    /* the body is held until it is whole, so its size is checked against the topic limit before any of it is kept */
+    fn check_size(&mut self, topic_list : &mut TopicList, topic_id : u32) -> Result<(), Er> {
+        let size = self.buff.remaining() as u64;
+        let max = topic_list.topic_for_id(topic_id)?.max_record_bytes();
+        if size > max {
+            return Err(Er::RecordTooLarge(size, max))
+        }
+        Ok(())
+    }
+
+    /* [seq u8][index u64] of a record once it is stored */
+    fn send_ack(&mut self, idx : u64) -> Result<(), Er> {
+        let size : u32 = 4 + 1 + 1 + 8;
+        let mut frame = Vec::with_capacity(size as usize);
+        frame.extend_from_slice(&size.to_le_bytes());
+        frame.push(RecordType::Ack as u8);
+        frame.push(self.buff.seq);
+        frame.extend_from_slice(&idx.to_le_bytes());
+
+        self.tcp.write_all(&frame)
+            .map_err(Er::ServerTcpWrite)
+    }
+
+    fn end_record(&mut self) {
+        self.rec_type = None;
+        self.topic_id = None;
+        self.flags = None;
+        self.key_len = None;
+        self.partition = None;
+        self.record.clear();
+        self.discard = false;
+    }
+
+    /* partition the record goes to, None until the key of a keyed record has arrived */
+    fn route(&mut self, topic_list : &mut TopicList, topic_id : u32, flags : u8) -> Result<Option<u32>, Er> {
+        if flags & FLAG_KEY == 0 {
+            return topic_list.route(topic_id, None).map(Some)
+        }
+
+        if self.key_len.is_none() { self.key_len = self.buff.read_u16(); }
+        let key_len = match self.key_len {
+            Some(key_len) => key_len,
+            None => return Ok(None),
+        };
+
+        match self.buff.peek(key_len as usize) {
+            Some(key) => {
+                let partition = topic_list.route(topic_id, Some(key))?;
+                // the key length was read to find the key, so goes ahead of the rest of the body
+                self.record.extend_from_slice(&key_len.to_le_bytes());
+                Ok(Some(partition))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /* AccessDenied unless the rules let this connection's principal do op on the topic */
+    fn authorize(&self, topic_list : &TopicList, credentials : &Credentials, topic_id : u32, op : Operation) -> Result<(), Er> {
+        credentials.acl.check(self.principal()?, topic_list.topic_name(topic_id)?, op)
+    }
+
+    fn principal(&self) -> Result<&Principal, Er> {
+        self.auth.as_ref().map(|a| &a.principal).ok_or(Er::BadAuth)
+    }
+
+    /* writes anything the socket couldn't take earlier, dropping the connection if it has gone */
+    pub fn send_pending(&mut self) {
+        if let Err(e) = self.tcp.send_pending() {
+            log_warn!("failed to write to producer : {}", e);
+            self.state = BufferState::Closed;
+        }
+    }
+
+    /* has poll report the socket when it has room, while anything is queued for it */
+    pub fn watch_writable(&mut self, poll : &Poll, token : u64) {
+        if let Err(e) = self.tcp.watch_writable(poll, token) {
+            log_warn!("failed to poll producer : {}", e);
+            self.state = BufferState::Closed;
         }
     }
 
@@ -77,3 +332,166 @@ impl ProducerClient {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::Read;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use crate::client::Messages;
+    use crate::config::Config;
+    use crate::test_support::TestEnvironment;
+    use crate::hello::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, SUPPORTED_FEATURES};
+
+    fn connect(listener : &TcpListener) -> (ProducerClient, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = listener.accept().unwrap().0;
+        sock.set_nonblocking(true).unwrap();
+        (ProducerClient::new(Stream::Plain(sock)), client)
+    }
+
+    fn frame(seq : u8, record_type : RecordType, body : &[u8]) -> Vec<u8> {
+        let mut frame = (6 + body.len() as u32).to_le_bytes().to_vec();
+        frame.push(seq);
+        frame.push(record_type as u8);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn ack(client : &mut TcpStream) -> u64 {
+        let mut ack = [0u8; 14];
+        client.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[4], RecordType::Ack as u8);
+        u64::from_le_bytes(ack[6..].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_interleaved_producers() {
+        let env = TestEnvironment::new("interleaved_producers");
+        let config = Config::parse(&format!("node_id = 0\ndata_dir = \"{}\"\n[[topics]]\ntopic_id = 1\ntopic_name = \"shared\"\nreplication = 0\nfile_mask = 4\n", env.folder), vec![]).unwrap();
+        let mut topic_list = TopicList::init(&config, true).unwrap();
+        let credentials = Credentials::from_config(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (mut producer, mut client) = connect(&listener);
+            client.write_all(&frame(0, RecordType::Auth, b";ANON")).unwrap();
+            producer.process(&mut topic_list, &credentials).unwrap();
+            clients.push((producer, client));
+        }
+        let record = frame(1, RecordType::Producer, b"\x01\x00\x00\x00first half, second half");
+        let (mut gone, mut gone_client) = clients.pop().unwrap();
+        let (mut whole, mut whole_client) = clients.pop().unwrap();
+        let (mut halves, mut halves_client) = clients.pop().unwrap();
+
+        // one record arriving in two parts, another whole in between, and one that never finishes
+        halves_client.write_all(&record[..20]).unwrap();
+        halves.process(&mut topic_list, &credentials).unwrap();
+        whole_client.write_all(&frame(1, RecordType::Producer, b"\x01\x00\x00\x00whole")).unwrap();
+        whole.process(&mut topic_list, &credentials).unwrap();
+        gone_client.write_all(&record[..24]).unwrap();
+        gone.process(&mut topic_list, &credentials).unwrap();
+        drop(gone);
+        halves_client.write_all(&record[20..]).unwrap();
+        halves.process(&mut topic_list, &credentials).unwrap();
+
+        assert_eq!((ack(&mut whole_client), ack(&mut halves_client)), (0, 1));
+
+        let topic = topic_list.partition_for_id(1, 0).unwrap();
+        let mut messages = Messages::new(0, 0);
+        messages.push_data(&fs::read(topic.get_data_file_name()).unwrap());
+        for entry in fs::read(topic.get_index_file_name()).unwrap().chunks(8) {
+            messages.push_index(u64::from_le_bytes(entry.try_into().unwrap()));
+        }
+        let values : Vec<Vec<u8>> = messages.map(|m| m.unwrap().value).collect();
+        assert_eq!(values, vec![b"whole".to_vec(), b"first half, second half".to_vec()], "records are written whole, and checksums match");
+    }
+
+    #[test]
+    fn test_frame_size_before_auth() {
+        let env = TestEnvironment::new("frame_size_before_auth");
+        let config = Config::parse(&format!("node_id = 0\ndata_dir = \"{}\"\n[[topics]]\ntopic_id = 1\ntopic_name = \"shared\"\nreplication = 0\nfile_mask = 4\n", env.folder), vec![]).unwrap();
+        let mut topic_list = TopicList::init(&config, true).unwrap();
+        let credentials = Credentials::from_config(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // an Auth claiming to be large is refused from its header, before the buffer grows for it
+        let (mut stranger, mut stranger_client) = connect(&listener);
+        let mut huge = frame(0, RecordType::Auth, &[b'x'; 4000]);
+        huge[0..4].copy_from_slice(&(8 * 1024 * 1024u32).to_le_bytes());
+        stranger_client.write_all(&huge).unwrap();
+        match stranger.process(&mut topic_list, &credentials) {
+            Err(Er::FrameTooLarge(size, max)) => assert_eq!((size, max), (8 * 1024 * 1024, MAX_PRE_AUTH_SIZE as u64)),
+            _ => assert!(false, "large frames before auth should be refused"),
+        }
+        assert!(matches!(stranger.state(), BufferState::Closed));
+        assert!(stranger.buff.buffer.len() <= MAX_PRE_AUTH_SIZE);
+
+        // once authenticated, records can be larger
+        let (mut producer, mut client) = connect(&listener);
+        client.write_all(&frame(0, RecordType::Auth, b";ANON")).unwrap();
+        producer.process(&mut topic_list, &credentials).unwrap();
+        let mut body = b"\x01\x00\x00\x00".to_vec();
+        body.extend_from_slice(&[b'y'; 3 * MAX_PRE_AUTH_SIZE]);
+        client.write_all(&frame(1, RecordType::Producer, &body)).unwrap();
+        for _ in 0..100 {
+            producer.process(&mut topic_list, &credentials).unwrap();
+            client.set_read_timeout(Some(std::time::Duration::from_millis(10))).unwrap();
+            let mut ack = [0u8; 14];
+            if client.read_exact(&mut ack).is_ok() {
+                assert_eq!((ack[4], ack[5]), (RecordType::Ack as u8, 1));
+                return
+            }
+        }
+        assert!(false, "record after auth should be acked");
+    }
+
+    #[test]
+    fn test_second_auth_rejected() {
+        let env = TestEnvironment::new("second_auth");
+        let config = Config::parse(&format!("node_id = 0\ndata_dir = \"{}\"\n[[topics]]\ntopic_id = 1\ntopic_name = \"shared\"\nreplication = 0\nfile_mask = 4\n", env.folder), vec![]).unwrap();
+        let mut topic_list = TopicList::init(&config, true).unwrap();
+        let credentials = Credentials::from_config(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (mut producer, mut client) = connect(&listener);
+        client.write_all(&frame(0, RecordType::Auth, b";ANON")).unwrap();
+        producer.process(&mut topic_list, &credentials).unwrap();
+        client.write_all(&frame(1, RecordType::Auth, b";ANON")).unwrap();
+        assert!(matches!(producer.process(&mut topic_list, &credentials), Err(Er::BadAuth)), "the principal can't be switched");
+        assert!(matches!(producer.state(), BufferState::Closed), "the connection is closed");
+        drop(producer);
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply[4], RecordType::Error as u8);
+        assert_eq!(u16::from_le_bytes([reply[5], reply[6]]), Er::BadAuth.code());
+    }
+
+    #[test]
+    fn test_newer_hello_rejected() {
+        let env = TestEnvironment::new("newer_hello");
+        let config = Config::parse(&format!("node_id = 0\ndata_dir = \"{}\"\n[[topics]]\ntopic_id = 1\ntopic_name = \"shared\"\nreplication = 0\nfile_mask = 4\n", env.folder), vec![]).unwrap();
+        let mut topic_list = TopicList::init(&config, true).unwrap();
+        let credentials = Credentials::from_config(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (mut producer, mut client) = connect(&listener);
+        let newer = Hello { version : PROTOCOL_VERSION + 1, features : SUPPORTED_FEATURES, max_record_bytes : 0 };
+        client.write_all(&newer.request_frame(0)).unwrap();
+        match producer.process(&mut topic_list, &credentials) {
+            Err(Er::UnsupportedVersion(version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)) => assert_eq!(version, PROTOCOL_VERSION + 1),
+            _ => assert!(false, "a version above the server's should be rejected"),
+        }
+        assert!(matches!(producer.state(), BufferState::Closed), "the connection is closed");
+        drop(producer);
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply[4], RecordType::Error as u8, "the client is sent why");
+        assert_eq!(u16::from_le_bytes([reply[5], reply[6]]), Er::UnsupportedVersion(0, 0, 0).code());
+    }
+}
@@ -0,0 +1,308 @@
+use std::convert::TryInto;
+
+use super::er::Er;
+
+/*
+ * Every record in a data file is its body followed by a fixed size trailer :
+ *
+ *   body[len] | len[4] | crc32c[4] | flags[1] | version[1]
+ *
+ * The trailer goes at the end as producers stream the body to disk before the
+ * whole record (and so its checksum) is known. The index entry for a record
+ * is the data offset just past its trailer.
+ */
+pub const RECORD_VERSION: u8 = 1;
+pub const TRAILER_SIZE: usize = 10;
+
+/*
+ * flags in the trailer say what the body holds, in this order :
+ *
+ *   FLAG_KEY      key_len[2] | key[key_len]
+ *   FLAG_HEADERS  count[2] | count * ( name_len[2] | name[name_len] | value_len[2] | value[value_len] )
+ *   (always)      value
+ *
+ * a keyed record with an empty value is a tombstone, marking the key as deleted
+ */
+pub const FLAG_KEY: u8 = 0x01;
+pub const FLAG_HEADERS: u8 = 0x02;
+
+const CRC32C_POLY: u32 = 0x82f6_3b78; // Castagnoli, reversed
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/* running CRC32C, so a body can be checksummed a chunk at a time as it is written */
+pub struct Crc32c {
+    crc : u32,
+    len : u32,
+}
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        Crc32c { crc : !0, len : 0 }
+    }
+
+    pub fn update(&mut self, bytes : &[u8]) {
+        for b in bytes {
+            self.crc = CRC32C_TABLE[((self.crc ^ *b as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+        self.len += bytes.len() as u32;
+    }
+
+    pub fn value(&self) -> u32 { !self.crc }
+
+    pub fn len(&self) -> u32 { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /* trailer for everything passed to update() so far */
+    pub fn trailer(&self, flags : u8) -> [u8; TRAILER_SIZE] {
+        let mut trailer = [0u8; TRAILER_SIZE];
+        trailer[0..4].copy_from_slice(&self.len.to_le_bytes());
+        trailer[4..8].copy_from_slice(&self.value().to_le_bytes());
+        trailer[8] = flags;
+        trailer[9] = RECORD_VERSION;
+        trailer
+    }
+}
+impl Default for Crc32c {
+    fn default() -> Self { Crc32c::new() }
+}
+
+pub fn crc32c(bytes : &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(bytes);
+    crc.value()
+}
+
+/* checks a record read back from a data file, returning its flags and body */
+pub fn open_record(mut record : Vec<u8>) -> Result<(u8, Vec<u8>), Er> {
+    if record.len() < TRAILER_SIZE {
+        return Err(Er::BadRecordFormat(format!("record of {} bytes is too short for its trailer", record.len())))
+    }
+
+    let body_len = record.len() - TRAILER_SIZE;
+    let trailer = &record[body_len..];
+
+    if trailer[9] != RECORD_VERSION {
+        return Err(Er::BadRecordFormat(format!("unknown record version {}", trailer[9])))
+    }
+
+    let len = u32::from_le_bytes(trailer[0..4].try_into().map_err(|_| Er::IsNone)?);
+    if len as usize != body_len {
+        return Err(Er::BadRecordFormat(format!("trailer length {} does not match body length {}", len, body_len)))
+    }
+
+    let expected = u32::from_le_bytes(trailer[4..8].try_into().map_err(|_| Er::IsNone)?);
+    let flags = trailer[8];
+
+    record.truncate(body_len);
+    let actual = crc32c(&record);
+    if expected != actual {
+        return Err(Er::BadChecksum(expected, actual))
+    }
+
+    Ok((flags, record))
+}
+
+/* a record as producers send it and consumers receive it */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Message {
+    pub key : Option<Vec<u8>>,
+    pub headers : Vec<(String, Vec<u8>)>,
+    pub value : Vec<u8>,
+}
+impl Message {
+    pub fn new(value : &[u8]) -> Message {
+        Message { value : value.to_vec(), ..Default::default() }
+    }
+
+    pub fn with_key(mut self, key : &[u8]) -> Message {
+        self.key = Some(key.to_vec());
+        self
+    }
+
+    pub fn with_header(mut self, name : &str, value : &[u8]) -> Message {
+        self.headers.push((String::from(name), value.to_vec()));
+        self
+    }
+
+    /* value of the first header called name */
+    pub fn header(&self, name : &str) -> Option<&[u8]> {
+        self.headers.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.key.is_some() && self.value.is_empty()
+    }
+
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.key.is_some() { flags |= FLAG_KEY; }
+        if !self.headers.is_empty() { flags |= FLAG_HEADERS; }
+        flags
+    }
+
+    /* body as stored in the data file, to go with flags() in the trailer */
+    pub fn body(&self) -> Result<Vec<u8>, Er> {
+        let mut body = Vec::new();
+
+        if let Some(key) = &self.key {
+            push_sized(&mut body, key, "key")?;
+        }
+
+        if !self.headers.is_empty() {
+            if self.headers.len() > u16::MAX as usize {
+                return Err(Er::BadRecordFormat(format!("{} headers is more than {}", self.headers.len(), u16::MAX)))
+            }
+            body.extend_from_slice(&(self.headers.len() as u16).to_le_bytes());
+            for (name, value) in self.headers.iter() {
+                push_sized(&mut body, name.as_bytes(), "header name")?;
+                push_sized(&mut body, value, "header value")?;
+            }
+        }
+
+        body.extend_from_slice(&self.value);
+        Ok(body)
+    }
+
+    pub fn from_body(flags : u8, mut body : Vec<u8>) -> Result<Message, Er> {
+        let mut pos = 0;
+        let mut message = Message::default();
+
+        if flags & FLAG_KEY != 0 {
+            message.key = Some(take_sized(&body, &mut pos, "key")?.to_vec());
+        }
+
+        if flags & FLAG_HEADERS != 0 {
+            let count = take_u16(&body, &mut pos, "header count")?;
+            for _ in 0..count {
+                let name = String::from_utf8(take_sized(&body, &mut pos, "header name")?.to_vec())
+                    .map_err(|e| Er::BadRecordFormat(format!("header name is not utf8 : {}", e)))?;
+                let value = take_sized(&body, &mut pos, "header value")?.to_vec();
+                message.headers.push((name, value));
+            }
+        }
+
+        message.value = body.split_off(pos);
+        Ok(message)
+    }
+}
+
+fn push_sized(body : &mut Vec<u8>, bytes : &[u8], what : &str) -> Result<(), Er> {
+    if bytes.len() > u16::MAX as usize {
+        return Err(Er::BadRecordFormat(format!("{} of {} bytes is longer than {}", what, bytes.len(), u16::MAX)))
+    }
+    body.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    body.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn take_u16(body : &[u8], pos : &mut usize, what : &str) -> Result<u16, Er> {
+    if body.len() < *pos + 2 {
+        return Err(Er::BadRecordFormat(format!("record too short for {} length", what)))
+    }
+    let n = u16::from_le_bytes([body[*pos], body[*pos + 1]]);
+    *pos += 2;
+    Ok(n)
+}
+
+fn take_sized<'a>(body : &'a [u8], pos : &mut usize, what : &str) -> Result<&'a [u8], Er> {
+    let len = take_u16(body, pos, what)? as usize;
+    if body.len() < *pos + len {
+        return Err(Er::BadRecordFormat(format!("{} length {} is past the end of a {} byte record", what, len, body.len())))
+    }
+    let bytes = &body[*pos..*pos + len];
+    *pos += len;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        // check value for CRC-32C from the iSCSI spec (RFC 3720)
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8a91_36aa);
+
+        let mut crc = Crc32c::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xe306_9283, "checksum in chunks should match checksum in one go");
+        assert_eq!(crc.len(), 9);
+    }
+
+    #[test]
+    fn test_message_body() {
+        let message = Message::new(b"{\"name\":\"graham\"}")
+            .with_key(b"user-1")
+            .with_header("trace-id", b"abc123")
+            .with_header("content-type", b"application/json");
+
+        assert_eq!(message.flags(), FLAG_KEY | FLAG_HEADERS);
+        let body = message.body().unwrap();
+        let decoded = Message::from_body(message.flags(), body.clone()).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.header("content-type"), Some(&b"application/json"[..]));
+        assert_eq!(decoded.header("schema-id"), None);
+
+        let plain = Message::from_body(0, body.clone()).unwrap();
+        assert_eq!(plain.key, None, "unflagged records are all value");
+        assert_eq!(plain.value, body);
+
+        let headers_only = Message::new(b"v").with_header("h", b"");
+        assert_eq!(Message::from_body(FLAG_HEADERS, headers_only.body().unwrap()).unwrap(), headers_only);
+
+        match Message::from_body(FLAG_KEY, vec![9, 0, b'x']) {
+            Err(Er::BadRecordFormat(_)) => {},
+            _ => assert!(false, "key length past end of record should be rejected"),
+        }
+        assert!(Message::new(b"").with_key(b"gone").is_tombstone());
+    }
+
+    #[test]
+    fn test_open_record() {
+        let mut crc = Crc32c::new();
+        crc.update(b"hello world");
+
+        let mut record = b"hello world".to_vec();
+        record.extend_from_slice(&crc.trailer(0));
+
+        match open_record(record.clone()) {
+            Ok((flags, body)) => {
+                assert_eq!(flags, 0);
+                assert_eq!(body, b"hello world".to_vec());
+            },
+            Err(e) => assert!(false, "record should be valid {}", e),
+        }
+
+        record[4] = b'O';
+        match open_record(record) {
+            Err(Er::BadChecksum(_, _)) => {},
+            _ => assert!(false, "corrupt body should fail checksum"),
+        }
+
+        match open_record(b"short".to_vec()) {
+            Err(Er::BadRecordFormat(_)) => {},
+            _ => assert!(false, "record without trailer should be rejected"),
+        }
+    }
+}
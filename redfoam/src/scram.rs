@@ -0,0 +1,282 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::auth::{self, Auth, Credentials, Principal, to_hex};
+use super::buff::Buff;
+use super::er::Er;
+use super::tcp::RecordType;
+
+/*
+ * Named principals can prove they know their secret without sending it, by a salted
+ * challenge-response in the style of SCRAM-SHA-256 (RFC 5802). It takes the place of
+ * the Auth record, and the connection stays Pending until it is done :
+ *
+ *   client -> server   ScramStart      topic_len[2] topic | name_len[2] name | nonce_len[2] client_nonce
+ *   server -> client   ScramChallenge  nonce_len[2] nonce | salt_len[2] salt | iterations[4]
+ *   client -> server   ScramProof      proof[32]
+ *   server -> client   ScramOutcome    signature[32]
+ *
+ * nonce is client_nonce with the server's own added. The client works from the salted
+ * secret, the server only has stored_key and server_key, which the credentials file keeps :
+ *
+ *   client_key = HMAC(salted, "Client Key")     stored_key = SHA-256(client_key)
+ *   server_key = HMAC(salted, "Server Key")
+ *   auth_message = n=<name>,r=<client_nonce>,r=<nonce>,s=<salt hex>,i=<iterations>,r=<nonce>
+ *   proof = client_key XOR HMAC(stored_key, auth_message)
+ *   signature = HMAC(server_key, auth_message)
+ *
+ * The server gets client_key back from the proof and checks it hashes to stored_key,
+ * the client checks the signature to know the server holds the secret too.
+ */
+const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 18;
+const MAX_ITERATIONS: u32 = 1_000_000; /* clients won't be made to spend longer than this hashing */
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key : &[u8], message : &[u8]) -> [u8; KEY_BYTES] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn xor(a : &[u8; KEY_BYTES], b : &[u8; KEY_BYTES]) -> [u8; KEY_BYTES] {
+    let mut out = [0u8; KEY_BYTES];
+    for i in 0..KEY_BYTES {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+/* stored_key and server_key from the salted secret */
+pub fn keys(salted : &[u8]) -> ([u8; KEY_BYTES], [u8; KEY_BYTES]) {
+    let client_key = hmac(salted, b"Client Key");
+    (Sha256::digest(client_key).into(), hmac(salted, b"Server Key"))
+}
+
+fn auth_message(name : &str, client_nonce : &str, nonce : &str, salt : &[u8], iterations : u32) -> String {
+    format!("n={},r={},r={},s={},i={},r={}", name, client_nonce, nonce, to_hex(salt), iterations, nonce)
+}
+
+fn new_nonce() -> Result<String, Er> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    auth::random_bytes(&mut nonce).map_err(|_| Er::BadAuth)?;
+    Ok(to_hex(&nonce))
+}
+
+/* a server's side of one exchange, between the challenge going out and the proof coming back */
+pub struct ScramServer {
+    topic : String,
+    name : String,
+    auth_message : String,
+    keys : Option<([u8; KEY_BYTES], [u8; KEY_BYTES])>, /* None for names that are not in the credentials file */
+}
+impl ScramServer {
+    /* reads a ScramStart once all of it is in the buffer, and gives the ScramChallenge frame to answer it with */
+    pub fn start(buff : &mut Buff, credentials : &Credentials) -> Result<Option<(ScramServer, Vec<u8>)>, Er> {
+        if !buff.is_end_of_record() {
+            return Ok(None)
+        }
+        let topic = read_string(buff)?;
+        let name = read_string(buff)?;
+        let client_nonce = read_string(buff)?;
+        if client_nonce.is_empty() || client_nonce.contains(',') || name.contains(',') {
+            return Err(Er::BadAuth)
+        }
+
+        // names that aren't known are still challenged, so the answer doesn't say which are
+        let (salt, iterations, keys) = match credentials.secret(&name) {
+            Some(secret) => (secret.salt().to_vec(), secret.iterations(), Some((secret.stored_key(), secret.server_key()))),
+            None => (credentials.decoy_salt(&name), credentials.decoy_iterations(), None),
+        };
+        let nonce = format!("{}{}", client_nonce, new_nonce()?);
+
+        let mut frame = vec![0, 0, 0, 0, RecordType::ScramChallenge as u8];
+        push_bytes(&mut frame, nonce.as_bytes());
+        push_bytes(&mut frame, &salt);
+        frame.extend_from_slice(&iterations.to_le_bytes());
+
+        let auth_message = auth_message(&name, &client_nonce, &nonce, &salt, iterations);
+        Ok(Some((ScramServer { topic, name, auth_message, keys }, finish(frame))))
+    }
+
+    /* checks the proof in a ScramProof, giving who connected and the ScramOutcome frame to send them */
+    pub fn finish(&self, buff : &mut Buff) -> Result<(Auth, Vec<u8>), Er> {
+        let proof = read_key(buff)?;
+        let (stored_key, server_key) = self.keys.ok_or(Er::BadAuth)?;
+
+        let client_key = xor(&proof, &hmac(&stored_key, self.auth_message.as_bytes()));
+        let hashed : [u8; KEY_BYTES] = Sha256::digest(client_key).into();
+        if !auth::constant_time_eq(&hashed, &stored_key) {
+            return Err(Er::BadAuth)
+        }
+
+        let mut frame = vec![0, 0, 0, 0, RecordType::ScramOutcome as u8];
+        frame.extend_from_slice(&hmac(&server_key, self.auth_message.as_bytes()));
+        Ok((Auth { topic : self.topic.clone(), principal : Principal::Named(self.name.clone()) }, finish(frame)))
+    }
+}
+
+/* a client's side of one exchange */
+pub struct ScramClient {
+    name : String,
+    secret : String,
+    client_nonce : String,
+    server_key : Option<[u8; KEY_BYTES]>,
+    auth_message : String,
+}
+impl ScramClient {
+    /* the exchange for name, and the ScramStart frame that begins it */
+    pub fn start(seq : u8, topic : &str, name : &str, secret : &str) -> Result<(ScramClient, Vec<u8>), Er> {
+        let client_nonce = new_nonce()?;
+
+        let mut frame = vec![0, 0, 0, 0, seq, RecordType::ScramStart as u8];
+        push_bytes(&mut frame, topic.as_bytes());
+        push_bytes(&mut frame, name.as_bytes());
+        push_bytes(&mut frame, client_nonce.as_bytes());
+
+        let client = ScramClient { name : String::from(name), secret : String::from(secret), client_nonce, server_key : None, auth_message : String::new() };
+        Ok((client, finish(frame)))
+    }
+
+    /* reads the server's ScramChallenge, once its record type has been read, and gives the ScramProof frame */
+    pub fn proof(&mut self, seq : u8, buff : &mut Buff) -> Result<Vec<u8>, Er> {
+        let nonce = read_string(buff)?;
+        let salt = read_bytes(buff)?;
+        let iterations = buff.read_u32().ok_or(Er::IsNone)?;
+        if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len() {
+            return Err(Er::BadAuth) // not an answer to our start
+        }
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err(Er::BadAuth)
+        }
+
+        let salted = auth::salted(self.secret.as_bytes(), &salt, iterations);
+        let client_key = hmac(&salted, b"Client Key");
+        let (stored_key, server_key) = keys(&salted);
+        self.auth_message = auth_message(&self.name, &self.client_nonce, &nonce, &salt, iterations);
+        self.server_key = Some(server_key);
+
+        let mut frame = vec![0, 0, 0, 0, seq, RecordType::ScramProof as u8];
+        frame.extend_from_slice(&xor(&client_key, &hmac(&stored_key, self.auth_message.as_bytes())));
+        Ok(finish(frame))
+    }
+
+    /* checks the server's ScramOutcome, once its record type has been read */
+    pub fn check_outcome(&self, buff : &mut Buff) -> Result<(), Er> {
+        let signature = read_key(buff)?;
+        let server_key = self.server_key.ok_or(Er::UnexpectedRecordType)?;
+
+        match auth::constant_time_eq(&signature, &hmac(&server_key, self.auth_message.as_bytes())) {
+            true => Ok(()),
+            false => Err(Er::BadAuth), // whoever answered doesn't know the secret
+        }
+    }
+}
+
+fn push_bytes(frame : &mut Vec<u8>, bytes : &[u8]) {
+    frame.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    frame.extend_from_slice(bytes);
+}
+
+fn read_bytes(buff : &mut Buff) -> Result<Vec<u8>, Er> {
+    let len = buff.read_u16().ok_or(Er::BadAuth)? as usize;
+    let mut bytes = Vec::with_capacity(len);
+    for _ in 0..len {
+        bytes.push(buff.read_u8().ok_or(Er::BadAuth)?);
+    }
+    Ok(bytes)
+}
+
+fn read_string(buff : &mut Buff) -> Result<String, Er> {
+    String::from_utf8(read_bytes(buff)?).map_err(|_| Er::BadAuth)
+}
+
+fn read_key(buff : &mut Buff) -> Result<[u8; KEY_BYTES], Er> {
+    let mut key = [0u8; KEY_BYTES];
+    for byte in key.iter_mut() {
+        *byte = buff.read_u8().ok_or(Er::BadAuth)?;
+    }
+    Ok(key)
+}
+
+/* fills in the size, now the rest of the frame is known */
+fn finish(mut frame : Vec<u8>) -> Vec<u8> {
+    let size = frame.len() as u32;
+    frame[0..4].copy_from_slice(&size.to_le_bytes());
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* the frame as the other side reads it, up to and including the record type */
+    fn received(frame : &[u8], has_seq : bool) -> Buff {
+        let mut bstr : &[u8] = frame;
+        let mut b = Buff::new();
+        b.read_data(&mut bstr).unwrap();
+        b.rec_size = b.read_u32();
+        if has_seq {
+            b.seq = frame[4];
+            b.check_seq().unwrap();
+        }
+        b.read_u8();
+        b
+    }
+
+    fn credentials() -> Credentials {
+        let secret = auth::SecretHash::new(b"hunter2", b"0123456789abcdef", 1000);
+        Credentials::parse(&format!("[[principals]]\nname = \"billing\"\nsecret = \"{}\"\n", secret)).unwrap()
+    }
+
+    #[test]
+    fn test_exchange() {
+        let credentials = credentials();
+        let (mut client, start) = ScramClient::start(0, "orders", "billing", "hunter2").unwrap();
+        assert!(!start.windows(7).any(|w| w == b"hunter2"), "the secret is never sent");
+
+        let (server, challenge) = ScramServer::start(&mut received(&start, true), &credentials).unwrap().unwrap();
+        let mut proof = client.proof(1, &mut received(&challenge, false)).unwrap();
+        let (auth, outcome) = server.finish(&mut received(&proof, true)).unwrap();
+        assert_eq!((auth.topic.as_str(), auth.principal), ("orders", Principal::Named(String::from("billing"))));
+        assert!(client.check_outcome(&mut received(&outcome, false)).is_ok(), "server proved it knows the secret");
+
+        // a proof altered on the way, or for another exchange, is refused
+        let last = proof.len() - 1;
+        proof[last] ^= 1;
+        assert!(server.finish(&mut received(&proof, true)).is_err());
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let credentials = credentials();
+        let (mut client, start) = ScramClient::start(0, "orders", "billing", "hunter3").unwrap();
+        let (server, challenge) = ScramServer::start(&mut received(&start, true), &credentials).unwrap().unwrap();
+        let proof = client.proof(1, &mut received(&challenge, false)).unwrap();
+        assert!(server.finish(&mut received(&proof, true)).is_err());
+
+        // unknown names get a challenge like any other, and fail at the proof
+        let (mut client, start) = ScramClient::start(0, "orders", "nobody", "hunter2").unwrap();
+        let (server, challenge) = ScramServer::start(&mut received(&start, true), &credentials).unwrap().unwrap();
+        let proof = client.proof(1, &mut received(&challenge, false)).unwrap();
+        assert!(server.finish(&mut received(&proof, true)).is_err());
+
+        let (_, again) = ScramServer::start(&mut received(&ScramClient::start(0, "orders", "nobody", "x").unwrap().1, true), &credentials).unwrap().unwrap();
+        let salt_of = |frame : &[u8]| { let mut b = received(frame, false); read_bytes(&mut b).unwrap(); (read_bytes(&mut b).unwrap(), b.read_u32().unwrap()) };
+        assert_eq!(salt_of(&challenge), salt_of(&again), "an unknown name always gets the same salt");
+        assert_eq!(salt_of(&challenge).1, 1000, "and as many iterations as the names that are known");
+    }
+
+    #[test]
+    fn test_forged_outcome() {
+        let credentials = credentials();
+        let (mut client, start) = ScramClient::start(0, "orders", "billing", "hunter2").unwrap();
+        let (_, challenge) = ScramServer::start(&mut received(&start, true), &credentials).unwrap().unwrap();
+        client.proof(1, &mut received(&challenge, false)).unwrap();
+
+        let mut forged = vec![0, 0, 0, 0, RecordType::ScramOutcome as u8];
+        forged.extend_from_slice(&[0u8; KEY_BYTES]);
+        assert!(client.check_outcome(&mut received(&finish(forged), false)).is_err());
+    }
+}
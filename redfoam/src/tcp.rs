@@ -1,12 +1,41 @@
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
+use std::net::TcpListener;
+use std::io::{self, Read, Write, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use rustls::ServerConfig;
+
+use super::{trace, log_error, log_warn};
 use super::consumer::{ConsumerServer};
 use super::producer::{ProducerClient};
-use super::topic::{TopicList};
-use super::er::Er;
+use super::topic::{TopicList, Compaction};
+use super::config::Config;
+use super::auth::Credentials;
+use super::poll::Poll;
+use super::tls::{self, Stream};
+use super::er::{Er, LogError};
+
+// partition in a follow request for every partition of the topic
+pub const ALL_PARTITIONS: u32 = u32::MAX;
+
+// poll tokens for the fds servers own, clients are given tokens counting up from zero
+pub const LISTENER_TOKEN: u64 = u64::MAX;
+pub const NOTIFY_TOKEN: u64 = u64::MAX - 1;
+pub const WEBSOCKET_TOKEN: u64 = u64::MAX - 2;
+
+// longest a server waits on epoll before running timed work, i.e. fsync by time and retention
+pub const TICK: Duration = Duration::from_millis(50);
+
+// how often producer servers check topics for segments past their retention limits
+const RETENTION_INTERVAL: Duration = Duration::from_secs(10);
+
+// how often closed segments of topics with compact set are compacted, on a thread of the producer server's own
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// most a client can have queued before it is dropped, followers falling behind are caught up from the files instead
+const QUEUE_LIMIT: usize = 1 << 20;
 
 pub enum BufferState {
     Pending,
@@ -22,6 +51,21 @@ pub enum RecordType {
     DataFeed = 4,
     IndexFeed = 5,
     ConsumerStart = 6,
+    SegmentStart = 7,
+    ProducerRecord = 8,
+    Error = 9,
+    Ack = 10,
+    Hello = 11,
+    ConsumerCommit = 12,
+    JoinGroup = 13,
+    Heartbeat = 14,
+    Assignment = 15,
+    Metadata = 16,
+    ScramStart = 17,
+    ScramChallenge = 18,
+    ScramProof = 19,
+    ScramOutcome = 20,
+    AssignmentAck = 21,
     Undefined = 255,
 }
 
@@ -34,117 +78,337 @@ impl From<u8> for RecordType {
             4 => Self::DataFeed,
             5 => Self::IndexFeed,
             6 => Self::ConsumerStart,
+            7 => Self::SegmentStart,
+            8 => Self::ProducerRecord,
+            9 => Self::Error,
+            10 => Self::Ack,
+            11 => Self::Hello,
+            12 => Self::ConsumerCommit,
+            13 => Self::JoinGroup,
+            14 => Self::Heartbeat,
+            15 => Self::Assignment,
+            16 => Self::Metadata,
+            17 => Self::ScramStart,
+            18 => Self::ScramChallenge,
+            19 => Self::ScramProof,
+            20 => Self::ScramOutcome,
+            21 => Self::AssignmentAck,
             _ => Self::Undefined,
         }
     }
 
 }
 
+/* where a consumer starts reading a topic, sent in ConsumerStart as [mode u8][value u64] */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Start {
+    Earliest,           /* oldest record retention has kept */
+    Latest,             /* only records produced from now on */
+    Index(u64),         /* record index */
+    Timestamp(u64),     /* first record produced at or after this time, in ms since the epoch */
+}
+impl Start {
+    pub fn to_wire(&self) -> (u8, u64) {
+        match self {
+            Start::Earliest => (0, 0),
+            Start::Latest => (1, 0),
+            Start::Index(idx) => (2, *idx),
+            Start::Timestamp(ms) => (3, *ms),
+        }
+    }
+
+    pub fn from_wire(mode : u8, value : u64) -> Result<Start, Er> {
+        match mode {
+            0 => Ok(Start::Earliest),
+            1 => Ok(Start::Latest),
+            2 => Ok(Start::Index(value)),
+            3 => Ok(Start::Timestamp(value)),
+            _ => Err(Er::BadStartMode(mode)),
+        }
+    }
+}
+
+/* 
+ * tells a client why its request failed, as [code u16][seq u8][message]. seq is that
+ * of the request that failed, so producers can tell which record was rejected
+ */
+pub fn send_error(tcp : &mut impl Write, seq : u8, e : &Er) -> Result<(), Er> {
+    let message = e.to_string();
+    let size : u32 = 4 + 1 + 2 + 1 + message.len() as u32;
+
+    let mut frame = Vec::with_capacity(size as usize);
+    frame.extend_from_slice(&size.to_le_bytes());
+    frame.push(RecordType::Error as u8);
+    frame.extend_from_slice(&e.code().to_le_bytes());
+    frame.push(seq);
+    frame.extend_from_slice(message.as_bytes());
+
+    tcp.write_all(&frame)
+        .map_err(Er::ServerTcpWrite)
+}
+
+/* takes every connection waiting on the listener, ready to be added to a server's poll */
+pub fn accept_all(listener : &TcpListener, tls : Option<&Arc<ServerConfig>>) -> Vec<Stream> {
+    let mut streams = Vec::new();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true).expect("set_nonblocking call failed");
+                match Stream::accept(stream, tls) {
+                    Ok(stream) => streams.push(stream),
+                    Err(e) => { log_warn!("connection refused : {}", e); },
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return streams,
+            Err(e) => {
+                log_error!("connection failed : {}", e);
+                return streams
+            },
+        }
+    }
+}
+
+/*
+ * a server's end of a client socket. Sockets are nonblocking, so whatever one can't take
+ * yet is queued, and goes out in order as the server loop calls send_pending. Servers
+ * watch the socket for room to write while anything is queued
+ */
+pub struct Connection {
+    stream : Stream,
+    queued : VecDeque<u8>,
+    watching : bool, /* poll reports the socket when it has room */
+}
+impl Connection {
+    pub fn new(stream : Stream) -> Connection {
+        Connection { stream, queued : VecDeque::new(), watching : false }
+    }
+
+    /* true while anything written has still to reach the socket */
+    pub fn is_queued(&self) -> bool {
+        !self.queued.is_empty() || self.stream.wants_write()
+    }
+
+    /* the socket topic files can be sent to with sendfile, None while what is queued has to go first */
+    pub fn sendfile_socket(&self) -> Option<RawFd> {
+        match self.is_queued() {
+            true => None,
+            false => self.stream.sendfile_socket(),
+        }
+    }
+
+    /* writes as much of the queue as the socket takes */
+    pub fn send_pending(&mut self) -> io::Result<()> {
+        self.stream.send_pending()?;
+        while !self.queued.is_empty() {
+            let size = match self.stream.write(self.queued.as_slices().0) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(size) => size,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            self.queued.drain(..size);
+        }
+        Ok(())
+    }
+
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    pub fn has_buffered(&mut self) -> bool {
+        self.stream.has_buffered()
+    }
+
+    pub fn peer_principal(&self) -> Option<String> {
+        self.stream.peer_principal()
+    }
+
+    /* tells poll to report the socket while it has room, as long as anything is queued */
+    pub fn watch_writable(&mut self, poll : &Poll, token : u64) -> Result<(), Er> {
+        let queued = self.is_queued();
+        if queued != self.watching {
+            poll.watch_writable(self.as_raw_fd(), token, queued)?;
+            self.watching = queued;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+/* never WouldBlock, what the socket doesn't take is queued. Fails once the queue is over QUEUE_LIMIT */
+impl Write for Connection {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        let mut taken = 0;
+        if self.queued.is_empty() {
+            taken = match self.stream.write(buf) {
+                Ok(size) => size,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+        }
+
+        if self.queued.len() + buf.len() - taken > QUEUE_LIMIT {
+            return Err(io::Error::other("client is not reading what it is sent"))
+        }
+        self.queued.extend(&buf[taken..]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
 macro_rules! make_server {
-    ($typename: ident, $handler : ty) => {
+    ($typename: ident, $handler : ty, $tls : ident) => {
         pub struct $typename {
-            rx :  mpsc::Receiver<TcpStream>,
-            client_list : Vec<$handler>,
+            listener : TcpListener,
+            tls : Option<Arc<ServerConfig>>,
+            poll : Poll,
+            client_list : HashMap<u64, $handler>,
+            next_token : u64,
             topic_list : TopicList,
+            credentials : Credentials,
+            last_retention : Instant,
         }
         impl $typename {
-            pub fn new (rx :  mpsc::Receiver<TcpStream>) -> $typename {
+            pub fn new (listener : TcpListener, config : &Config) -> $typename {
+                listener.set_nonblocking(true).expect("set_nonblocking call failed");
+                let poll = Poll::new().handle_err("Failed to create epoll instance");
+                poll.add(listener.as_raw_fd(), LISTENER_TOKEN).handle_err("Failed to poll listener");
+
                 $typename { 
-                    rx : rx,
-                    client_list : Vec::new(),
-                    topic_list : TopicList::init(true).unwrap(),
+                    listener,
+                    tls : config.$tls.as_ref().map(tls::server_config).transpose().handle_err("Failed to set up TLS"),
+                    poll,
+                    client_list : HashMap::new(),
+                    next_token : 0,
+                    topic_list : TopicList::init(config, true).handle_err("Failed to open topics"),
+                    credentials : Credentials::from_config(config).handle_err("Failed to load credentials"),
+                    last_retention : Instant::now(),
                 }
             }
 
             pub fn run (&mut self) { 
                 loop {
+                    let ready_list = self.poll.wait(TICK).handle_err("Failed waiting for events");
+
+                    for ready in ready_list {
+                        if ready.token == LISTENER_TOKEN {
+                            for instream in accept_all(&self.listener, self.tls.as_ref()) {
+                                trace!("creating new client");
+                                self.poll.add(instream.as_raw_fd(), self.next_token).handle_err("Failed to poll client");
+                                self.client_list.insert(self.next_token, <$handler>::new(instream));
+                                self.next_token += 1;
+                            }
+                        } else if let Some(c) = self.client_list.get_mut(&ready.token) {
+                            if let Err(e) = c.process(&mut self.topic_list, &self.credentials) {
+                                match e { 
+                                    Er::NotReady   => {println!("Error : {}", e);},
+                                    _               => {println!("Error : {}", e);},
+                                }
+                            }
+                            // anything sent before the hang up has been processed above
+                            if ready.closed {
+                                self.client_list.remove(&ready.token);
+                            }
+                        }
+                    }
 
-                    match self.rx.try_recv() {
-                        Ok(instream) => {
-                            println!("creating new client");
-                            let c = <$handler>::new(instream);
-                            self.client_list.push(c);
-                        },
-                        Err(mpsc::TryRecvError::Empty) => { }, // no new stream - do nothing
-                        Err(_e) => { panic!("  OOOPSS");  }
+                    // what the sockets couldn't take earlier
+                    for (token, c) in self.client_list.iter_mut() {
+                        c.send_pending();
+                        c.watch_writable(&self.poll, *token);
                     }
 
-                    self.client_list.retain(|c| match c.state() {
+                    self.client_list.retain(|_, c| match c.state() {
                         BufferState::Closed => false, _ => true 
                     });
 
-                    for c in self.client_list.iter_mut() {
-                        if let Err(e) = c.process(&mut self.topic_list) {
-                            match e { 
-                                Er::NotReady   => {println!("Error : {}", e);},
-                                _               => {println!("Error : {}", e);},
-                            }
-                        }
-                    }
+                    self.topic_list.sync_check();
 
-                    thread::sleep(Duration::from_millis(100))
+                    if self.last_retention.elapsed() >= RETENTION_INTERVAL {
+                        self.topic_list.apply_retention();
+                        self.last_retention = Instant::now();
+                    }
                 }
             }
         }
     }
 }
 
-make_server!(ProducerServer, ProducerClient);
-
-pub fn run_server(addr : String) {
-
-    let (tx, rx) : (mpsc::Sender<TcpStream>, mpsc::Receiver<TcpStream>) = mpsc::channel();
+make_server!(ProducerServer, ProducerClient, producer_tls);
 
-    thread::spawn(move || {
-        ProducerServer::new(rx).run();
-    });
+pub fn run_server(config : &Config) {
 
+    let listener = TcpListener::bind(&config.producer_addr)
+        .unwrap_or_else(|e| panic!("Failed to listen on {} : {}", config.producer_addr, e));
+    println!("Listening on: {}", config.producer_addr);
 
-    let listener = TcpListener::bind(&addr).unwrap();
-    println!("Listening on: {}", addr);
+    let mut server = ProducerServer::new(listener, config);
+    Compaction::start(config, COMPACTION_INTERVAL);
+    server.run();
+}
 
+pub fn run_consumer_server(config : &Config) {
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_nonblocking(true).expect("set_nonblocking call failed");
-                println!("sending stream");
-                tx.send(stream).unwrap();
-                println!("sent stream");
-            },
+    let listener = TcpListener::bind(&config.consumer_addr)
+        .unwrap_or_else(|e| panic!("Failed to listen on {} : {}", config.consumer_addr, e));
+    println!("Listening on: {}", config.consumer_addr);
 
-            Err(_e) => {
-                panic!("connection failed");
-            }
-        }
+    let mut server = ConsumerServer::init(listener, config);
+    if let Some(websocket) = &config.websocket {
+        let listener = TcpListener::bind(&websocket.addr)
+            .unwrap_or_else(|e| panic!("Failed to listen on {} : {}", websocket.addr, e));
+        println!("Websocket listening on: {}", websocket.addr);
+        server.add_websocket(listener, websocket);
     }
+    server.run();
 }
 
-pub fn run_consumer_server(addr : String) {
-
-    let (tx, rx) : (mpsc::Sender<TcpStream>, mpsc::Receiver<TcpStream>) = mpsc::channel();
-
-    thread::spawn(move || {
-        ConsumerServer::init(rx).run();
-    });
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
 
-    let listener = TcpListener::bind(&addr).unwrap();
-    println!("Listening on: {}", addr);
+    #[test]
+    fn test_connection_queues() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = listener.accept().unwrap().0;
+        sock.set_nonblocking(true).unwrap();
+        let mut connection = Connection::new(Stream::Plain(sock));
 
+        // more than the socket takes while the client isn't reading
+        let content : Vec<u8> = (0..QUEUE_LIMIT).map(|i| i as u8).collect();
+        while !connection.is_queued() {
+            connection.write_all(&content[..64 * 1024]).expect("what the socket can't take is queued");
+        }
+        assert!(connection.sendfile_socket().is_none(), "nothing goes around the queue");
+        assert!(connection.write_all(&content).is_err(), "a client that doesn't read is dropped past QUEUE_LIMIT");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_nonblocking(true).expect("set_nonblocking call failed");
-                println!("sending stream for consumer");
-                tx.send(stream).unwrap();
-                println!("sent stream for consumer");
-            },
-
-            Err(_e) => {
-                panic!("connection failed");
-            }
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+        while connection.is_queued() {
+            connection.send_pending().unwrap();
         }
+        drop(connection);
+        let received = reader.join().unwrap();
+        assert!(received.chunks(64 * 1024).all(|chunk| chunk == &content[..chunk.len()]), "written in order");
     }
 }
@@ -13,7 +13,9 @@ pub struct TestEnvironment {
 
 impl TestEnvironment {
     pub fn new( name:&str ) -> TestEnvironment {
-        let dir_name = format!("/tmp/redfoam_{}", name);
+        // the process id keeps runs apart, a folder left by a killed run with the same id is stale
+        let dir_name = std::env::temp_dir().join(format!("redfoam_{}_{}", name, std::process::id())).to_string_lossy().into_owned();
+        let _ = fs::remove_dir_all(&dir_name);
         fs::create_dir(&dir_name).expect("create topic dir failed");
         TestEnvironment { folder : dir_name}
     }
@@ -21,28 +23,34 @@ impl TestEnvironment {
 
 pub trait TestTopic {
     fn test_new(env:&TestEnvironment, id:u32, name:&str, is_producer:bool) -> Self;
+    fn test_new_with(config:config::TopicConfig, is_producer:bool) -> Self;
     fn test_open(&self, is_producer:bool) -> Self;
     fn create_entries(&self, content:&[u8], max_repeat:u32, record_count:u32) ;
 }
 
 impl TestTopic for topic::Topic {
     fn test_new(env:&TestEnvironment, id:u32, name:&str, is_producer:bool) -> Self {
-        let dir_name = format!("{}/{}", &env.folder, name);
-        let data_file_name = format!("{}/{}/d0000000000000000", env.folder, name);
-        let index_file_name = format!("{}/{}/i0000000000000000", env.folder, name);
-
-        fs::create_dir(dir_name).expect("create topic dir failed");
-        File::create(index_file_name).expect("create topic index file failed");
-        File::create(data_file_name).expect("create topic data file failed");
-
         let topic_config = config::TopicConfig {
             topic_id : id,
             topic_name : String::from(name),
             folder : env.folder.clone(),
             replication : 0,
-            file_mask : 0,
+            file_mask : 4,
+            ..Default::default()
         };
 
+        topic::Topic::test_new_with(topic_config, is_producer)
+    }
+
+    fn test_new_with(topic_config:config::TopicConfig, is_producer:bool) -> Self {
+        let dir_name = format!("{}/{}", &topic_config.folder, topic_config.topic_name);
+        let data_file_name = format!("{}/d0000000000000000", dir_name);
+        let index_file_name = format!("{}/i0000000000000000", dir_name);
+
+        fs::create_dir(&dir_name).expect("create topic dir failed");
+        File::create(index_file_name).expect("create topic index file failed");
+        File::create(data_file_name).expect("create topic data file failed");
+
         topic::Topic::open(topic_config, is_producer).expect("trying to create topic")
     }
 
@@ -74,7 +82,7 @@ impl TestTopic for topic::Topic {
 
 impl Drop for TestEnvironment {
     fn drop(&mut self) {
-        fs::remove_dir_all(&self.folder);
+        let _ = fs::remove_dir_all(&self.folder);
     }
 }
 
@@ -85,27 +93,27 @@ mod test {
 
     #[test] 
     fn test_env() {
-
+        let folder;
         {
-            let _te = TestEnvironment::new("mytest");
-            assert!(Path::new("/tmp/redfoam_mytest").exists());
-
+            let te = TestEnvironment::new("mytest");
+            assert!(Path::new(&te.folder).exists());
+            folder = te.folder.clone();
         }
-        assert!(!Path::new("/tmp/redfoam_mytest").exists()); //ensure cleaned up
+        assert!(!Path::new(&folder).exists()); //ensure cleaned up
     }
 
     #[test]
     fn test_testtopic() {
         let te = TestEnvironment::new("testtopic");
         let t = topic::Topic::test_new(&te, 1, "mytesttopic", false);
-        assert!(Path::new("/tmp/redfoam_testtopic/mytesttopic").exists());
-        assert!(Path::new("/tmp/redfoam_testtopic/mytesttopic/d0000000000000000").exists());
-        assert!(Path::new("/tmp/redfoam_testtopic/mytesttopic/i0000000000000000").exists());
+        assert!(Path::new(&format!("{}/mytesttopic", te.folder)).exists());
+        assert!(Path::new(&format!("{}/mytesttopic/d0000000000000000", te.folder)).exists());
+        assert!(Path::new(&format!("{}/mytesttopic/i0000000000000000", te.folder)).exists());
 
         let content = b":START:Some junk content blahdebalah barghasldkfj :END:";
         t.create_entries(content, 10, 10);
 
-        let alldata_result = fs::read_to_string("/tmp/redfoam_testtopic/mytesttopic/d0000000000000000");
+        let alldata_result = fs::read_to_string(format!("{}/mytesttopic/d0000000000000000", te.folder));
         assert!(alldata_result.is_ok(), "Error trying to read from data file");
         let alldata = alldata_result.unwrap();
 
@@ -115,7 +123,7 @@ mod test {
         assert!(alldata.starts_with(":START:"), "data file doesn't start with same start marker");
         assert!(alldata.ends_with(":END:"), "data file doesn't end with same end marker");
 
-        let index_result = fs::read("/tmp/redfoam_testtopic/mytesttopic/i0000000000000000");
+        let index_result = fs::read(format!("{}/mytesttopic/i0000000000000000", te.folder));
 
         assert!(index_result.is_ok(), "can't read index file");
         let index = index_result.unwrap();
@@ -0,0 +1,511 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+use super::config::TlsConfig;
+use super::er::Er;
+use super::websocket::WebSocket;
+
+/*
+ * Connections to either server are plain TCP, or TLS when the listener has a TlsConfig.
+ * Clients choose TLS with a tls:// url, whose query gives the files they need :
+ *
+ *   tls://host:port?ca=<pem file>                  server certificate checked against ca
+ *   tls://host:port?ca=<pem>&cert=<pem>&key=<pem>  presenting a client certificate too
+ *   &name=<server name>                            when the certificate is for a name other than host
+ *
+ * Sockets are nonblocking on the servers, so TLS records the socket can't take yet are
+ * queued and go out with the next write, or when the server loop calls send_pending.
+ * Once TLS_BUFFER_LIMIT is queued writes are WouldBlock, as they would be on the socket.
+ * Browsers on the consumer server's websocket listener are a WebSocket around either,
+ * see websocket.rs.
+ */
+const SCHEME: &str = "tls://";
+
+// most plaintext TLS holds for a socket that isn't taking it, about a socket buffer's worth
+const TLS_BUFFER_LIMIT: usize = 64 * 1024;
+
+// ASN.1 tags for the parts of a certificate read to find its subject
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_OID: u8 = 0x06;
+const DER_VERSION: u8 = 0xa0;
+const OID_COMMON_NAME: [u8; 3] = [0x55, 0x04, 0x03];
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+    WebSocket(Box<WebSocket>),
+}
+
+pub struct TlsStream {
+    conn : Connection,
+    sock : TcpStream,
+}
+
+impl Stream {
+    /* a connection just accepted by a server, which is TLS if the listener has a config */
+    pub fn accept(sock : TcpStream, config : Option<&Arc<ServerConfig>>) -> Result<Stream, Er> {
+        match config {
+            Some(config) => {
+                let conn = ServerConnection::new(config.clone())
+                    .map_err(|e| Er::TlsError(e.to_string()))?;
+                Ok(Stream::tls(Connection::from(conn), sock))
+            },
+            None => Ok(Stream::Plain(sock)),
+        }
+    }
+
+    /* connects to host:port, or tls://host:port?ca=... */
+    pub fn connect(url : &str) -> Result<Stream, Er> {
+        let rest = match url.strip_prefix(SCHEME) {
+            Some(rest) => rest,
+            None => return TcpStream::connect(url).map(Stream::Plain).map_err(Er::ClientTcpWrite),
+        };
+
+        let (addr, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut params = ClientParams::default();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("ca", path)) => params.ca = Some(path),
+                Some(("cert", path)) => params.cert = Some(path),
+                Some(("key", path)) => params.key = Some(path),
+                Some(("name", name)) => params.name = Some(name),
+                _ => return Err(Er::TlsError(format!("{:?} in {} should be ca, cert, key or name", param, url))),
+            }
+        }
+
+        let host = params.name.unwrap_or_else(|| addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr));
+        let host = host.trim_start_matches('[').trim_end_matches(']'); // ipv6 addresses are bracketed
+        let name = ServerName::try_from(String::from(host))
+            .map_err(|e| Er::TlsError(format!("server name {} : {}", host, e)))?;
+        let conn = ClientConnection::new(client_config(&params)?, name)
+            .map_err(|e| Er::TlsError(e.to_string()))?;
+
+        let sock = TcpStream::connect(addr)
+            .map_err(Er::ClientTcpWrite)?;
+        Ok(Stream::tls(Connection::from(conn), sock))
+    }
+
+    fn tls(mut conn : Connection, sock : TcpStream) -> Stream {
+        conn.set_buffer_limit(Some(TLS_BUFFER_LIMIT));
+        Stream::Tls(Box::new(TlsStream { conn, sock }))
+    }
+
+    /* the socket topic files can be sent to with sendfile, None when what is sent has to be encrypted first */
+    pub fn sendfile_socket(&self) -> Option<RawFd> {
+        match self {
+            Stream::Plain(sock) => Some(sock.as_raw_fd()),
+            Stream::Tls(_) | Stream::WebSocket(_) => None,
+        }
+    }
+
+    /*
+     * true when TLS has decrypted more than the last read took. epoll only sees the socket,
+     * so this is read before waiting on it again
+     */
+    pub fn has_buffered(&mut self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(tls) => match tls.conn.process_new_packets() {
+                Ok(state) => state.plaintext_bytes_to_read() > 0,
+                Err(_) => false, // the next read reports it
+            },
+            Stream::WebSocket(ws) => ws.has_buffered(),
+        }
+    }
+
+    /* writes TLS records queued for the socket, as far as it takes them */
+    pub fn send_pending(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => Ok(()),
+            Stream::Tls(tls) => tls.send_pending(),
+            Stream::WebSocket(ws) => ws.send_pending(),
+        }
+    }
+
+    /* true while TLS records are waiting on the socket */
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(tls) => tls.conn.wants_write(),
+            Stream::WebSocket(ws) => ws.inner().is_queued(),
+        }
+    }
+
+    /*
+     * the subject common name of the certificate the client presented, which the server
+     * has verified against its client CA. None without TLS or a client certificate
+     */
+    pub fn peer_principal(&self) -> Option<String> {
+        match self {
+            Stream::Plain(_) => None,
+            Stream::Tls(tls) => tls.conn.peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| subject_common_name(cert)),
+            Stream::WebSocket(ws) => ws.inner().peer_principal(),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking : bool) -> io::Result<()> {
+        self.socket().set_nonblocking(nonblocking)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(sock) => sock,
+            Stream::Tls(tls) => &tls.sock,
+            Stream::WebSocket(ws) => ws.inner().stream().socket(),
+        }
+    }
+}
+
+impl TlsStream {
+    fn send_pending(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let tls = match self {
+            Stream::Plain(sock) => return sock.read(buf),
+            Stream::WebSocket(ws) => return ws.read(buf),
+            Stream::Tls(tls) => tls,
+        };
+        // anything written while the socket was full goes first, the other end may be waiting on it
+        tls.send_pending()?;
+
+        loop {
+            match tls.conn.reader().read(buf) {
+                Ok(size) => return Ok(size),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0), // closed without a close_notify, as plain TCP would be
+                Err(e) => return Err(e),
+            }
+
+            // nothing decrypted yet, so more is read from the socket. WouldBlock goes back to the caller
+            if tls.conn.read_tls(&mut tls.sock)? == 0 {
+                return Ok(0)
+            }
+            let processed = tls.conn.process_new_packets();
+            // handshake messages, or the alert for what failed
+            tls.send_pending()?;
+            processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Tls(tls) => {
+                // whatever the socket takes first leaves more room under the limit
+                tls.send_pending()?;
+                let size = tls.conn.writer().write(buf)?;
+                tls.send_pending()?;
+                match size {
+                    0 if !buf.is_empty() => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                    size => Ok(size),
+                }
+            },
+            Stream::WebSocket(ws) => ws.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Tls(tls) => tls.send_pending(),
+            Stream::WebSocket(ws) => ws.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket().as_raw_fd()
+    }
+}
+
+/* checks the files a listener's TLS config names, and builds what its connections share */
+pub fn server_config(tls : &TlsConfig) -> Result<Arc<ServerConfig>, Er> {
+    let builder = match &tls.client_ca_file {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(root_store(path)?));
+            let verifier = match tls.client_cert_optional {
+                true => verifier.allow_unauthenticated(),
+                false => verifier,
+            };
+            let verifier = verifier.build()
+                .map_err(|e| Er::TlsError(format!("client_ca_file {} : {}", path, e)))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        },
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(certificates(&tls.cert_file)?, private_key(&tls.key_file)?)
+        .map_err(|e| Er::TlsError(format!("cert_file {} and key_file {} : {}", tls.cert_file, tls.key_file, e)))?;
+    Ok(Arc::new(config))
+}
+
+#[derive(Default)]
+struct ClientParams<'a> {
+    ca : Option<&'a str>,
+    cert : Option<&'a str>,
+    key : Option<&'a str>,
+    name : Option<&'a str>,
+}
+
+fn client_config(params : &ClientParams) -> Result<Arc<ClientConfig>, Er> {
+    let ca = params.ca.ok_or_else(|| Er::TlsError(String::from("tls:// urls need a ca to check the server with")))?;
+    let builder = ClientConfig::builder().with_root_certificates(root_store(ca)?);
+
+    let config = match (params.cert, params.key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(certificates(cert)?, private_key(key)?)
+            .map_err(|e| Er::TlsError(format!("cert {} and key {} : {}", cert, key, e)))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(Er::TlsError(String::from("a client certificate needs both cert and key"))),
+    };
+    Ok(Arc::new(config))
+}
+
+fn certificates(path : &str) -> Result<Vec<CertificateDer<'static>>, Er> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Er::TlsError(format!("can't read certificates from {} : {}", path, e)))?;
+    match certs.is_empty() {
+        true => Err(Er::TlsError(format!("there are no certificates in {}", path))),
+        false => Ok(certs),
+    }
+}
+
+fn private_key(path : &str) -> Result<PrivateKeyDer<'static>, Er> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| Er::TlsError(format!("can't read a private key from {} : {}", path, e)))
+}
+
+fn root_store(path : &str) -> Result<RootCertStore, Er> {
+    let mut roots = RootCertStore::empty();
+    for cert in certificates(path)? {
+        roots.add(cert)
+            .map_err(|e| Er::TlsError(format!("CA certificate in {} : {}", path, e)))?;
+    }
+    Ok(roots)
+}
+
+/* the common name in a certificate's subject, read straight from its DER */
+fn subject_common_name(cert : &[u8]) -> Option<String> {
+    let (_, certificate, _) = der(cert, DER_SEQUENCE)?;
+    let (_, mut tbs, _) = der(certificate, DER_SEQUENCE)?;
+
+    // [0] version, serial, signature algorithm, issuer and validity come before the subject
+    if tbs.first() == Some(&DER_VERSION) {
+        tbs = der(tbs, DER_VERSION)?.2;
+    }
+    for _ in 0..4 {
+        tbs = der(tbs, *tbs.first()?)?.2;
+    }
+    let (_, mut subject, _) = der(tbs, DER_SEQUENCE)?;
+
+    while !subject.is_empty() {
+        let (_, mut names, rest) = der(subject, DER_SET)?;
+        while !names.is_empty() {
+            let (_, name, more) = der(names, DER_SEQUENCE)?;
+            let (_, oid, value) = der(name, DER_OID)?;
+            if oid == OID_COMMON_NAME {
+                let (_, value, _) = der(value, *value.first()?)?;
+                return String::from_utf8(value.to_vec()).ok()
+            }
+            names = more;
+        }
+        subject = rest;
+    }
+    None
+}
+
+/* splits a DER value with tag off the front of input, as (tag, content, what follows) */
+fn der(input : &[u8], tag : u8) -> Option<(u8, &[u8], &[u8])> {
+    if *input.first()? != tag {
+        return None
+    }
+    let first = *input.get(1)? as usize;
+    let (len, start) = match first < 0x80 {
+        true => (first, 2),
+        false => {
+            let bytes = first & 0x7f;
+            if bytes == 0 || bytes > 4 {
+                return None
+            }
+            let len = input.get(2..2 + bytes)?.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, 2 + bytes)
+        },
+    };
+    let end = start.checked_add(len)?;
+    Some((tag, input.get(start..end)?, &input[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use crate::test_support::TestEnvironment;
+
+    /* a CA, a certificate for the server on 127.0.0.1 and one for a client called billing, as PEM files in folder */
+    fn write_certificates(folder : &str) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca = CertificateParams::new(Vec::new()).unwrap();
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca.distinguished_name.push(DnType::CommonName, "redfoam test ca");
+        let ca_cert = ca.self_signed(&ca_key).unwrap();
+
+        for (name, common_name, purpose) in [("server", "127.0.0.1", ExtendedKeyUsagePurpose::ServerAuth), ("client", "billing", ExtendedKeyUsagePurpose::ClientAuth)] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![String::from("127.0.0.1")]).unwrap();
+            params.distinguished_name.push(DnType::OrganizationName, "redfoam");
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            params.extended_key_usages = vec![purpose];
+            let cert = params.signed_by(&key, &ca_cert, &ca_key).unwrap();
+            fs::write(format!("{}/{}.pem", folder, name), cert.pem()).unwrap();
+            fs::write(format!("{}/{}.key", folder, name), key.serialize_pem()).unwrap();
+        }
+        fs::write(format!("{}/ca.pem", folder), ca_cert.pem()).unwrap();
+    }
+
+    fn tls_config(folder : &str) -> TlsConfig {
+        TlsConfig {
+            cert_file : format!("{}/server.pem", folder),
+            key_file : format!("{}/server.key", folder),
+            client_ca_file : Some(format!("{}/ca.pem", folder)),
+            client_cert_optional : false,
+        }
+    }
+
+    #[test]
+    fn test_tls_stream() {
+        let env = TestEnvironment::new("tls_stream");
+        write_certificates(&env.folder);
+        let config = server_config(&tls_config(&env.folder)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = Stream::accept(listener.accept().unwrap().0, Some(&config)).unwrap();
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"pong").unwrap();
+            (request, stream.peer_principal())
+        });
+
+        let url = format!("tls://{}?ca={}/ca.pem&cert={}/client.pem&key={}/client.key", addr, env.folder, env.folder, env.folder);
+        let mut client = Stream::connect(&url).unwrap();
+        assert!(client.sendfile_socket().is_none(), "encrypted sockets can't be sent files");
+        client.write_all(b"ping!").unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).unwrap();
+
+        assert_eq!(&reply, b"pong");
+        assert_eq!(server.join().unwrap(), (*b"ping!", Some(String::from("billing"))), "the principal is the certificate subject");
+    }
+
+    #[test]
+    fn test_tls_backpressure() {
+        let env = TestEnvironment::new("tls_backpressure");
+        write_certificates(&env.folder);
+        let config = server_config(&tls_config(&env.folder)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (filled, full) = std::sync::mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut stream = Stream::accept(listener.accept().unwrap().0, Some(&config)).unwrap();
+            stream.read_exact(&mut [0u8; 5]).unwrap();
+            stream.write_all(b"pong").unwrap();
+            stream.set_nonblocking(true).unwrap();
+
+            // the client isn't reading, so writes stop once the socket and TLS_BUFFER_LIMIT are full
+            let mut written = 0;
+            loop {
+                match stream.write(&[7u8; 16 * 1024]) {
+                    Ok(size) => written += size,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("writing to the client : {}", e),
+                }
+                assert!(written < 64 * 1024 * 1024, "TLS should stop taking writes");
+            }
+            filled.send(written).unwrap();
+
+            while stream.wants_write() {
+                stream.send_pending().unwrap();
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+
+        let url = format!("tls://{}?ca={}/ca.pem&cert={}/client.pem&key={}/client.key", addr, env.folder, env.folder, env.folder);
+        let mut client = Stream::connect(&url).unwrap();
+        client.write_all(b"ping!").unwrap();
+        client.read_exact(&mut [0u8; 4]).unwrap(); // the handshake is done
+        let written = full.recv().unwrap();
+        let mut content = vec![0u8; written];
+        client.read_exact(&mut content).unwrap();
+        server.join().unwrap();
+        assert!(content.iter().all(|b| *b == 7), "everything taken is sent once the client reads");
+    }
+
+    #[test]
+    fn test_tls_refused() {
+        let env = TestEnvironment::new("tls_refused");
+        write_certificates(&env.folder);
+        let config = server_config(&tls_config(&env.folder)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = Stream::accept(listener.accept().unwrap().0, Some(&config)).unwrap();
+            stream.read(&mut [0u8; 1])
+        });
+
+        // no client certificate, so the server ends the handshake
+        let mut client = Stream::connect(&format!("tls://{}?ca={}/ca.pem", addr, env.folder)).unwrap();
+        let _ = client.write_all(b"hello");
+        let _ = client.read(&mut [0u8; 1]);
+        assert!(server.join().unwrap().is_err());
+
+        assert!(matches!(Stream::connect(&format!("tls://{}", addr)), Err(Er::TlsError(_))), "the server certificate can't be checked without a ca");
+        assert!(matches!(Stream::connect(&format!("tls://{}?ca={}/ca.pem&cert={}/client.pem", addr, env.folder, env.folder)), Err(Er::TlsError(_))));
+        let mut missing = tls_config(&env.folder);
+        missing.key_file = format!("{}/none.key", env.folder);
+        assert!(matches!(server_config(&missing), Err(Er::TlsError(_))));
+    }
+
+    #[test]
+    fn test_subject_common_name() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CountryName, "GB");
+        params.distinguished_name.push(DnType::CommonName, "reporting");
+        let cert = params.self_signed(&key).unwrap();
+
+        assert_eq!(subject_common_name(cert.der()), Some(String::from("reporting")));
+        assert_eq!(subject_common_name(&cert.der()[..20]), None, "truncated");
+    }
+}
@@ -1,72 +1,644 @@
 use std::fs;
 use std::fs::{File, OpenOptions};
+use std::path::Path;
 use std::io;
+use std::convert::TryInto;
 use std::io::{Write, Read, SeekFrom, Seek};
 use inotify::{Inotify, WatchMask, WatchDescriptor };
 use std::collections::{HashMap, HashSet};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::ptr;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, Instant, Duration};
+use std::thread;
 
 use super::er::Er;
-use super::trace;
-use super::config::{Config, TopicConfig};
-use super::tcp::RecordType;
+use super::{trace, log_error, log_warn};
+use super::config::{Config, TopicConfig, Durability};
+use super::tcp::{RecordType, Start};
 use super::consumer::ConsumerClient;
+use super::buff::BUFF_SIZE;
+use super::record;
+use super::record::{Crc32c, Message};
+use super::metadata::TopicMetadata;
+
+// largest feed frame payload that still fits in a client buffer with its size[4] + type[1] header
+const MAX_FEED_SIZE: usize = BUFF_SIZE - 5;
+
+// closed segments are rewritten to a file with this suffix, then renamed over the original
+const COMPACT_SUFFIX: &str = ".compact";
+
+// delete markers are kept this long after compaction when the topic does not set tombstone_ms
+const DEFAULT_TOMBSTONE_MS: u64 = 24 * 60 * 60 * 1000;
+
+// consumer groups commit offsets to a file per group, in this folder under each partition folder
+const OFFSETS_FOLDER: &str = "offsets";
+pub const MAX_GROUP_NAME: usize = 255;
+
+// largest record body accepted when the topic does not set max_record_bytes
+pub const DEFAULT_MAX_RECORD_BYTES: u64 = 1024 * 1024;
+
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/* 
+ * partition a key is written to. FNV-1a is used as it is stable across builds and 
+ * platforms, so a key maps to the same partition whichever producer server receives it
+ */
+pub fn partition_for_key(key : &[u8], partitions : u32) -> u32 {
+    if partitions <= 1 {
+        return 0
+    }
+    let mut hash = FNV_OFFSET_BASIS;
+    for b in key {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash % partitions
+}
+
+/* how far a consumer that started in the past has got through the topic history */
+pub struct Catchup {
+    pub topic_id : u32,
+    pub partition : u32,
+    base_index : u64,
+    index_pos : u64,
+    pub data_pos : u64, /* offset in the segment data file of the next record to send */
+    files : Option<(File, File)>, /* index and data of the segment being sent, kept open so compaction can't move its records */
+}
+impl Catchup {
+    /* index of the next record to send */
+    pub fn record_index(&self) -> u64 {
+        self.base_index + self.index_pos / 8
+    }
+}
+
+/* reads a segment one record at a time, for compaction */
+struct SegmentReader {
+    base_index : u64,
+    data : File,
+    data_len : u64,
+    index : io::BufReader<File>,
+    times : Option<io::BufReader<File>>, /* None if the segment has no time index */
+    next_index : u64,
+    next_start : u64, /* data offset of the next record */
+}
+
+/* a record read by a SegmentReader */
+struct SegmentRecord {
+    index : u64,
+    bytes : Vec<u8>, /* empty for records compaction has already removed */
+    key : Result<Option<(Vec<u8>, bool)>, Er>, /* key and whether it is a tombstone, None for unkeyed or removed records */
+    time : Option<u64>,
+}
+
+impl SegmentReader {
+    fn open(folder : &str, base_index : u64) -> Result<SegmentReader, Er> {
+        let data = File::open(Topic::segment_file_name('d', base_index, folder))
+            .map_err(Er::CantOpenFile)?;
+        let data_len = data.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+        let index = File::open(Topic::segment_file_name('i', base_index, folder))
+            .map_err(Er::CantOpenFile)?;
+        let times = match File::open(Topic::segment_file_name('t', base_index, folder)) {
+            Ok(f) => Some(io::BufReader::new(f)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Er::CantOpenFile(e)),
+        };
+        Ok(SegmentReader { base_index, data, data_len, index : io::BufReader::new(index), times, next_index : base_index, next_start : 0 })
+    }
+
+    /* starts n records into the segment */
+    fn open_at(folder : &str, base_index : u64, n : u64) -> Result<SegmentReader, Er> {
+        let mut segment = SegmentReader::open(folder, base_index)?;
+        if n == 0 { return Ok(segment) }
+
+        // records start where the one before ends
+        let mut entry = [0u8; 8];
+        segment.index.get_ref().read_exact_at(&mut entry, (n - 1) * 8)
+            .map_err(Er::CantReadFile)?;
+        segment.next_start = u64::from_le_bytes(entry);
+        segment.next_index = base_index + n;
+
+        segment.index.seek(SeekFrom::Start(n * 8))
+            .map_err(Er::CantReadFile)?;
+        if let Some(times) = segment.times.as_mut() {
+            times.seek(SeekFrom::Start(n * 8))
+                .map_err(Er::CantReadFile)?;
+        }
+        Ok(segment)
+    }
+
+    /* None at the end of the index, a partly written entry counts as the end too */
+    fn read_u64(reader : &mut impl Read) -> Result<Option<u64>, Er> {
+        let mut bytes = [0u8; 8];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(u64::from_le_bytes(bytes))),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(Er::CantReadFile(e)),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<SegmentRecord>, Er> {
+        let end = match SegmentReader::read_u64(&mut self.index)? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        // the current segment grows while it is read, its data is always written before the index entry
+        if end > self.data_len {
+            self.data_len = self.data.metadata()
+                .map_err(Er::CantReadFile)?
+                .len();
+        }
+        if end < self.next_start || end > self.data_len {
+            return Err(Er::BadRecordFormat(format!("segment {:016x} record {} ends at {}, outside {} to {}",
+                self.base_index, self.next_index, end, self.next_start, self.data_len)))
+        }
+
+        let mut bytes = vec![0u8; (end - self.next_start) as usize];
+        self.data.read_exact_at(&mut bytes, self.next_start)
+            .map_err(Er::CantReadFile)?;
+
+        let key = if bytes.is_empty() {
+            Ok(None)
+        } else {
+            record::open_record(bytes.clone())
+                .and_then(|(flags, body)| Message::from_body(flags, body))
+                .map(|Message { key, value, .. }| key.map(|key| (key, value.is_empty())))
+        };
+        let time = match self.times.as_mut() {
+            Some(times) => SegmentReader::read_u64(times)?,
+            None => None,
+        };
+
+        let record = SegmentRecord { index : self.next_index, bytes, key, time };
+        self.next_index += 1;
+        self.next_start = end;
+        Ok(Some(record))
+    }
+}
+
+/* the latest record for a key, as far as compaction has read */
+struct LatestRecord {
+    index : u64,
+    tombstone_at : Option<u64>, /* when a tombstone was written, None for values or if the segment has no time index */
+}
+
+/*
+ * rewrites closed segments of a partition so only the latest record for each key is left. Removed 
+ * records keep an index entry with no data so record indexes do not change, readers skip these empty
+ * records. A tombstone (key with an empty value) is dropped too once it is older than tombstone_ms.
+ *
+ * What has been read is remembered between runs, so each run reads only the records written since
+ * the last one, and rewrites only the closed segments that now hold records to remove
+ */
+pub struct Compaction {
+    config : TopicConfig,
+    folder : String,
+    latest : HashMap<Vec<u8>, LatestRecord>,
+    next_index : u64, /* first record not yet read */
+    dropping : HashMap<u64, usize>, /* records to remove in each segment, by base index */
+}
+impl Compaction {
+    pub fn new(config : TopicConfig, partition : u32) -> Compaction {
+        let folder = Topic::partition_folder(&config, partition);
+        Compaction { config, folder, latest : HashMap::new(), next_index : 0, dropping : HashMap::new() }
+    }
+
+    /* compacts every partition of the topics that ask for it on a thread of its own, so producers are never held up */
+    pub fn start(config : &Config, interval : Duration) {
+        let mut compactions : Vec<Compaction> = config.topics.iter()
+            .filter(|topic| topic.compact)
+            .flat_map(|topic| (0..topic.partitions.max(1)).map(move |partition| Compaction::new(topic.clone(), partition)))
+            .collect();
+        if compactions.is_empty() { return }
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            for compaction in compactions.iter_mut() {
+                match compaction.run() {
+                    Ok(0) => {},
+                    Ok(n) => { trace!("compaction removed {} records from {}", n, compaction.folder); },
+                    Err(e) => { log_error!("compaction failed on {} : {}", compaction.folder, e); },
+                }
+            }
+        });
+    }
+
+    /* returns how many records were removed */
+    pub fn run(&mut self) -> Result<usize, Er> {
+        let segments = Topic::segment_list(&self.folder)?;
+        let (earliest, current) = match (segments.first(), segments.last()) {
+            (Some(earliest), Some(current)) => (*earliest, *current),
+            _ => return Ok(0),
+        };
+        let segment_of = |index : u64| *segments.iter().rev().find(|base| **base <= index).unwrap_or(&earliest);
+
+        // records retention has removed are forgotten
+        self.latest.retain(|_, latest| latest.index >= earliest);
+        self.dropping.retain(|base_index, _| segments.binary_search(base_index).is_ok());
+        self.next_index = self.next_index.max(earliest);
+
+        // keys written since the last run, the current segment included as its keys replace older ones
+        for (n, base_index) in segments.iter().enumerate() {
+            if segments.get(n + 1).is_some_and(|next| *next <= self.next_index) { continue; }
+
+            let mut segment = match SegmentReader::open_at(&self.folder, *base_index, self.next_index.saturating_sub(*base_index)) {
+                Err(Er::CantOpenFile(ref e)) if e.kind() == io::ErrorKind::NotFound && *base_index == current => break, // new segment without its index yet
+                segment => segment?,
+            };
+            while let Some(record) = segment.next_record()? {
+                match record.key {
+                    Ok(Some((key, is_tombstone))) => {
+                        let tombstone_at = if is_tombstone { record.time } else { None };
+                        if let Some(replaced) = self.latest.insert(key, LatestRecord { index : record.index, tombstone_at }) {
+                            *self.dropping.entry(segment_of(replaced.index)).or_insert(0) += 1;
+                        }
+                    },
+                    Ok(None) => {},
+                    Err(e) => { log_warn!("compaction : keeping unreadable record {} : {}", record.index, e); },
+                }
+                self.next_index = record.index + 1;
+            }
+        }
+
+        // an expired tombstone is no longer the latest for its key, so it goes like a replaced record
+        let tombstone_ms = self.config.tombstone_ms.unwrap_or(DEFAULT_TOMBSTONE_MS);
+        let now = Topic::timestamp(SystemTime::now());
+        let expired : Vec<Vec<u8>> = self.latest.iter()
+            .filter(|(_, latest)| latest.index < current && latest.tombstone_at.is_some_and(|t| now.saturating_sub(t) > tombstone_ms))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(latest) = self.latest.remove(&key) {
+                *self.dropping.entry(segment_of(latest.index)).or_insert(0) += 1;
+            }
+        }
+
+        let mut removed = 0;
+        let closed : Vec<u64> = self.dropping.keys().filter(|base_index| **base_index < current).copied().collect();
+        for base_index in closed {
+            let latest = &self.latest;
+            let keep = |record : &SegmentRecord| match &record.key {
+                Ok(Some((key, _))) => latest.get(key).map(|latest| latest.index) == Some(record.index),
+                _ => true,
+            };
+            let rewritten = self.rewrite_segment(base_index, &keep)?;
+
+            let drop_count = self.dropping.remove(&base_index).unwrap_or(0);
+            if let Some((old_len, new_len)) = rewritten {
+                trace!("compact() : segment {:016x} dropped {} records, {} bytes to {}", base_index, drop_count, old_len, new_len);
+                removed += drop_count;
+            }
+        }
+        Ok(removed)
+    }
+
+    /* 
+     * writes the records keep allows to new files then renames them over the segment, returning 
+     * the old and new data lengths, or None if retention removed the segment in the meantime
+     */
+    fn rewrite_segment(&self, base_index : u64, keep : &dyn Fn(&SegmentRecord) -> bool) -> Result<Option<(u64, u64)>, Er> {
+        let f_data_name = Topic::segment_file_name('d', base_index, &self.folder);
+        let f_index_name = Topic::segment_file_name('i', base_index, &self.folder);
+
+        // keep the original modified time so age based retention is not held back by compaction
+        let modified = fs::metadata(&f_data_name)
+            .and_then(|m| m.modified())
+            .map_err(Er::CantReadFile)?;
+
+        let create = |f_name : &str| File::create(format!("{}{}", f_name, COMPACT_SUFFIX))
+            .map(io::BufWriter::new)
+            .map_err(Er::CantOpenFile);
+        // index first, so a data file on its own can only be left once the index has been switched
+        let mut index = create(&f_index_name)?;
+        let mut data = create(&f_data_name)?;
+
+        let mut segment = SegmentReader::open(&self.folder, base_index)?;
+        let mut data_len = 0u64;
+        while let Some(record) = segment.next_record()? {
+            if keep(&record) {
+                data.write_all(&record.bytes)
+                    .map_err(Er::CantWriteFile)?;
+                data_len += record.bytes.len() as u64;
+            }
+            index.write_all(&data_len.to_le_bytes())
+                .map_err(Er::CantWriteFile)?;
+        }
+
+        for f in [data, index] {
+            let f = f.into_inner()
+                .map_err(|e| Er::CantWriteFile(e.into_error()))?;
+            f.set_modified(modified)
+                .map_err(Er::CantWriteFile)?;
+            if self.config.durability != Durability::Os {
+                f.sync_all()
+                    .map_err(Er::CantWriteFile)?;
+            }
+        }
+
+        // readers open a segment's files together while holding the lock, so they never see one switched without the other
+        let _lock = Topic::lock_folder(&self.folder, true)?;
+        if !Path::new(&f_data_name).exists() {
+            trace!("rewrite_segment() : segment {:016x} removed by retention while it was compacted", base_index);
+            Topic::remove_compact_files(base_index, &self.folder)?;
+            return Ok(None)
+        }
+
+        // index first, a crash before the data follows is finished by the producer on startup
+        for f_name in [&f_index_name, &f_data_name].iter() {
+            fs::rename(format!("{}{}", f_name, COMPACT_SUFFIX), f_name)
+                .map_err(Er::CantWriteFile)?;
+        }
+        if self.config.durability != Durability::Os {
+            File::open(&self.folder)
+                .and_then(|folder| folder.sync_all())
+                .map_err(Er::CantWriteFile)?;
+        }
+        Ok(Some((segment.data_len, data_len)))
+    }
+}
 
 pub struct Topic {
     index : u64,
+    base_index : u64, /* first record index held in the current segment */
     data_file : File,  /* there are the 'current' files only */
     index_file : File, 
     data_file_name : String,  /* there are the 'current' files only */
     index_file_name : String, 
-    current_producer : Option<u32>,
     pub last_data_offset : u64,
     pub last_index_offset : u64,
     config : TopicConfig,
+    partition : u32,
+    folder : String, /* folder holding this partition's segments */
     followers : HashSet<u32>,
+    rec_crc : Crc32c, /* checksum of the record currently being written */
+    unsynced : u32, /* records written since the last fsync */
+    last_sync : Instant,
+    time_file : Option<File>, /* append timestamps of the current segment, producers only */
+    last_timestamp : u64,
 }
 impl Topic {
 
     pub fn open (config : TopicConfig, is_producer : bool) -> Result<Topic, Er>  {
+        Topic::open_partition(config, 0, is_producer)
+    }
 
-        let f_data_name = Topic::latest_file_name('d', &config)?;
-        let f_index_name = Topic::latest_file_name('i', &config)?;
+    pub fn open_partition (config : TopicConfig, partition : u32, is_producer : bool) -> Result<Topic, Er>  {
+
+        let folder = Topic::partition_folder(&config, partition);
+        Topic::prepare_folder(&folder)?;
+        if is_producer {
+            Topic::finish_compaction(&folder)?;
+        }
+
+        let f_data_name = Topic::latest_file_name('d', &folder)?;
+        let f_index_name = Topic::latest_file_name('i', &folder)?;
+        let (_, base_index) = Topic::parse_file_name(Topic::file_name_part(&f_index_name))?;
 
         let mut f_data = Self::file_opener(is_producer).open(&f_data_name)
-            .map_err(|e| Er::CantOpenFile(e))?;
+            .map_err(Er::CantOpenFile)?;
 
         let mut f_index = Self::file_opener(is_producer).open(&f_index_name)
-            .map_err(|e| Er::CantOpenFile(e))?;
+            .map_err(Er::CantOpenFile)?;
+
+        let mut f_time = None;
+        let mut last_timestamp = 0;
+        if is_producer {
+            Topic::recover(&mut f_data, &f_data_name, &mut f_index, &f_index_name)?;
+
+            let f_time_name = Topic::segment_file_name('t', base_index, &folder);
+            let mut f = Self::file_opener(is_producer).open(&f_time_name)
+                .map_err(Er::CantOpenFile)?;
+            last_timestamp = Topic::recover_time_index(&mut f, &f_time_name, &f_data, &f_index)?;
+            f_time = Some(f);
+        }
 
+        // followers are sent whole index entries, so consumers start after the last one the producer finished
         let last_index = f_index.seek(SeekFrom::End(0)).unwrap(); 
+        let last_index = f_index.seek(SeekFrom::Start(last_index - last_index % 8)).unwrap();
         let last_data = f_data.seek(SeekFrom::End(0)).unwrap(); 
-        let idx = last_index / 8;
+        let idx = base_index + last_index / 8;
 
-        Ok(Topic {
+        let mut topic = Topic {
             index : idx,
+            base_index,
             data_file : f_data,
             index_file : f_index,
             data_file_name : f_data_name,
             index_file_name : f_index_name,
-            current_producer : None,
             last_data_offset : last_data,
             last_index_offset : last_index,
             config : config,
+            partition,
+            folder,
             followers : HashSet::new(),
-        })
+            rec_crc : Crc32c::new(),
+            unsynced : 0,
+            last_sync : Instant::now(),
+            time_file : f_time,
+            last_timestamp,
+        };
+
+        // last segment may have been filled just before shutdown, so roll now rather than on the next write
+        if is_producer && idx > base_index {
+            topic.create_file_check()?;
+        }
+
+        Ok(topic)
+    }
+
+    pub fn partition(&self) -> u32 {
+        self.partition
+    }
+
+    /* what feed frames from this log are tagged with, for clients following several */
+    pub fn tag(&self) -> (u32, u32) {
+        (self.config.topic_id, self.partition)
+    }
+
+    pub fn max_record_bytes(&self) -> u64 {
+        self.config.max_record_bytes.unwrap_or(DEFAULT_MAX_RECORD_BYTES)
+    }
+
+    /* 
+     * a crash between writing data and writing its index entry leaves a torn segment, 
+     * so cut the files back to the last complete record before anything new is appended
+     */
+    fn recover(f_data : &mut File, f_data_name : &str, f_index : &mut File, f_index_name : &str) -> Result<(), Er> {
+        let data_len = f_data.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+        let index_len = f_index.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+
+        let mut index_end = index_len - (index_len % 8);
+        if index_end != index_len {
+            log_warn!("recovery : {} ends with a partial entry, truncating from {} to {} bytes", f_index_name, index_len, index_end);
+        }
+
+        // step back past any entries pointing beyond the data that actually reached the disk
+        let mut data_end = 0;
+        let mut entry = [0u8; 8];
+        while index_end > 0 {
+            f_index.seek(SeekFrom::Start(index_end - 8))
+                .map_err(Er::CantReadFile)?;
+            f_index.read_exact(&mut entry)
+                .map_err(Er::CantReadFile)?;
+
+            data_end = u64::from_le_bytes(entry);
+            if data_end <= data_len { break; }
+
+            log_warn!("recovery : {} entry at {} points to offset {} past end of data {}, removing it", f_index_name, index_end - 8, data_end, data_len);
+            index_end -= 8;
+            data_end = 0;
+        }
+
+        if index_end != index_len {
+            f_index.set_len(index_end)
+                .map_err(Er::CantWriteFile)?;
+        }
+
+        if data_end != data_len {
+            log_warn!("recovery : {} has {} bytes not covered by the index, truncating from {} to {} bytes", f_data_name, data_len - data_end, data_len, data_end);
+            f_data.set_len(data_end)
+                .map_err(Er::CantWriteFile)?;
+        }
+        Ok(())
+    }
+
+    /* 
+     * time index has one entry per index entry, trim any extra from a crash and fill any gap 
+     * (segments written before time indexes existed) with the data file modified time
+     */
+    fn recover_time_index(f_time : &mut File, f_time_name : &str, f_data : &File, f_index : &File) -> Result<u64, Er> {
+        let index_len = f_index.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+        let time_len = f_time.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+
+        if time_len > index_len {
+            log_warn!("recovery : {} has {} bytes more than its index, truncating", f_time_name, time_len - index_len);
+            f_time.set_len(index_len)
+                .map_err(Er::CantWriteFile)?;
+        }
+
+        let mut last_timestamp = 0;
+        let time_end = time_len.min(index_len);
+        let time_end = time_end - (time_end % 8);
+        if time_end > 0 {
+            let mut entry = [0u8; 8];
+            f_time.seek(SeekFrom::Start(time_end - 8))
+                .map_err(Er::CantReadFile)?;
+            f_time.read_exact(&mut entry)
+                .map_err(Er::CantReadFile)?;
+            last_timestamp = u64::from_le_bytes(entry);
+        }
+
+        if time_end < index_len {
+            let modified = f_data.metadata()
+                .and_then(|m| m.modified())
+                .map_err(Er::CantReadFile)?;
+            let fill = Topic::timestamp(modified).max(last_timestamp);
+            log_warn!("recovery : {} is missing {} entries, filling with {}", f_time_name, (index_len - time_end) / 8, fill);
+
+            f_time.set_len(time_end)
+                .map_err(Er::CantWriteFile)?;
+            for _ in 0..(index_len - time_end) / 8 {
+                f_time.write_all(&fill.to_le_bytes())
+                    .map_err(Er::CantWriteFile)?;
+            }
+            last_timestamp = fill;
+        }
+        Ok(last_timestamp)
+    }
+
+    /* milliseconds since the unix epoch, as held in the time index */
+    pub fn timestamp(time : SystemTime) -> u64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    /* first record index appended at or after timestamp_ms, None if every record is older */
+    pub fn index_for_time(&self, timestamp_ms : u64) -> Result<Option<u64>, Er> {
+        for base_index in Topic::segment_list(&self.folder)? {
+            let f_time_name = Topic::segment_file_name('t', base_index, &self.folder);
+
+            let times = match fs::read(&f_time_name) {
+                Ok(bytes) => bytes,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue, // segment has no time index
+                Err(e) => return Err(Er::CantReadFile(e)),
+            };
+
+            let entry = |n : usize| {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&times[n * 8..n * 8 + 8]);
+                u64::from_le_bytes(b)
+            };
+
+            // timestamps only ever increase, so binary search for the first one not before timestamp_ms
+            let (mut low, mut high) = (0, times.len() / 8);
+            while low < high {
+                let mid = (low + high) / 2;
+                if entry(mid) < timestamp_ms { low = mid + 1; } else { high = mid; }
+            }
+
+            if low < times.len() / 8 {
+                return Ok(Some(base_index + low as u64))
+            }
+        }
+        Ok(None)
+    }
+
+    fn topic_folder(config : &TopicConfig) -> String {
+        format!("{}/{}", &config.folder, &config.topic_name)
+    }
+
+    /* single log topics keep segments in the topic folder, partitioned ones have a p<n> folder per partition */
+    pub fn partition_folder(config : &TopicConfig, partition : u32) -> String {
+        if config.partitions > 1 {
+            format!("{}/p{}", Topic::topic_folder(config), partition)
+        } else {
+            Topic::topic_folder(config)
+        }
+    }
+
+    fn segment_file_name(prefix : char, base_index : u64, folder : &str) -> String {
+        format!("{}/{}{:016x}", folder, prefix, base_index)
+    }
+
+    fn file_name_part(path : &str) -> &str {
+        path.rsplit('/').next().unwrap_or(path)
     }
 
-    fn latest_file_name(prefix : char, config : &TopicConfig) -> Result<String, Er> {
+    /* splits a segment file name such as 'd0000000000000010' into its prefix and base record index */
+    pub fn parse_file_name(f_name : &str) -> Result<(char, u64), Er> {
+        let prefix = f_name.chars().nth(0)
+            .ok_or(Er::BadFileName)?;
 
-        let topic_folder = format!("{}/{}", &config.folder, &config.topic_name);
+        let hex_part = f_name.get(1..)
+            .filter(|h| h.len() == 16)
+            .ok_or(Er::BadFileName)?;
+
+        let base_index = u64::from_str_radix(hex_part, 16)
+            .map_err(|e| Er::BadOffset(String::from(f_name), e))?;
+
+        Ok((prefix, base_index))
+    }
+
+    fn latest_file_name(prefix : char, folder : &str) -> Result<String, Er> {
 
         let mut latest_file_number: u64 = 0;
 
-        for entry in fs::read_dir(topic_folder)
-                        .map_err(|e| Er::CantReadDir(e))? {
+        for entry in fs::read_dir(folder)
+                        .map_err(Er::CantReadDir)? {
 
             let file = entry
-                .map_err(|e| Er::CantReadFile(e))?;
+                .map_err(Er::CantReadFile)?;
 
             let file_name = file.file_name();
 
@@ -76,6 +648,8 @@ impl Topic {
             let first_char = f_name.chars().nth(0)
                 .ok_or(Er::BadFileName)?;
 
+            if f_name.ends_with(COMPACT_SUFFIX) { continue; } // segment being rewritten by compaction
+
             if first_char == prefix {
                 let file_number = u64::from_str_radix(&f_name[1..], 16)
                     .map_err(|e| Er::BadOffset(String::from(f_name), e))?;
@@ -86,79 +660,418 @@ impl Topic {
             }
         }
 
-        Ok(format!("{}/{}/{}{:016x}", config.folder, config.topic_name, prefix, latest_file_number))
+        Ok(Topic::segment_file_name(prefix, latest_file_number, folder))
+    }
+
+    /*
+     * makes folder ready to open : creates it with an empty first segment if it is new,
+     * and checks every data segment has its index. Both servers do this on startup, so
+     * each step is safe to repeat. What a crash can leave behind is put right :
+     *
+     *   - data segment without an index, older than the newest : retention was removing it
+     *   - newest data segment without an index, still empty : a new segment was being started
+     *
+     * anything else, such as an index without its data, is an error for an operator to look at
+     */
+    pub fn prepare_folder(folder : &str) -> Result<(), Er> {
+        fs::create_dir_all(folder)
+            .map_err(Er::CantOpenFile)?;
+
+        let mut data = Vec::new();
+        let mut index = HashSet::new();
+        for entry in fs::read_dir(folder).map_err(Er::CantReadDir)? {
+            let file = entry.map_err(Er::CantReadFile)?;
+            match file.file_name().to_str().map(Topic::parse_file_name) {
+                Some(Ok(('d', base_index))) => data.push(base_index),
+                Some(Ok(('i', base_index))) => { index.insert(base_index); },
+                _ => {},
+            }
+        }
+        data.sort_unstable();
+
+        if data.is_empty() && index.is_empty() {
+            trace!("prepare_folder() : creating first segment in {}", folder);
+            data.push(0);
+            Self::create_segment_file('d', 0, folder)?;
+        }
+
+        let newest = data.last().copied();
+        for base_index in &data {
+            if index.remove(base_index) {
+                continue;
+            }
+            let data_name = Topic::segment_file_name('d', *base_index, folder);
+            let data_len = fs::metadata(&data_name).map_err(Er::CantReadFile)?.len();
+
+            if Some(*base_index) != newest {
+                log_warn!("{} has no index, finishing its removal", data_name);
+                for prefix in ['t', 'd'].iter() {
+                    match fs::remove_file(Topic::segment_file_name(*prefix, *base_index, folder)) {
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                        result => result.map_err(Er::CantWriteFile)?,
+                    }
+                }
+            } else if data_len == 0 {
+                Self::create_segment_file('i', *base_index, folder)?;
+            } else {
+                return Err(Er::InconsistentSegments(format!("{} holds {} bytes but has no index", data_name, data_len)));
+            }
+        }
+
+        match index.iter().min() {
+            Some(base_index) => Err(Er::InconsistentSegments(format!("{} has no data segment", Topic::segment_file_name('i', *base_index, folder)))),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * puts right a compaction a crash cut short. The new index is renamed over the old one before 
+     * the new data is, so a new data file left on its own belongs to an index already switched and
+     * is renamed into place too. Anything else is a rewrite that never switched and is removed.
+     * Only producers do this, a compaction could be under way while a consumer starts
+     */
+    fn finish_compaction(folder : &str) -> Result<(), Er> {
+        let mut left = HashMap::new();
+        for entry in fs::read_dir(folder).map_err(Er::CantReadDir)? {
+            let file = entry.map_err(Er::CantReadFile)?;
+            let segment = file.file_name().to_str()
+                .and_then(|f_name| f_name.strip_suffix(COMPACT_SUFFIX))
+                .and_then(|f_name| Topic::parse_file_name(f_name).ok());
+            if let Some((prefix, base_index)) = segment {
+                left.entry(base_index).or_insert_with(Vec::new).push(prefix);
+            }
+        }
+
+        for (base_index, prefixes) in left {
+            if prefixes == ['d'] {
+                let f_data_name = Topic::segment_file_name('d', base_index, folder);
+                log_warn!("{} was compacted but not switched, finishing the switch", f_data_name);
+                fs::rename(format!("{}{}", f_data_name, COMPACT_SUFFIX), &f_data_name)
+                    .map_err(Er::CantWriteFile)?;
+            } else {
+                log_warn!("removing unfinished compaction of segment {:016x} in {}", base_index, folder);
+                Topic::remove_compact_files(base_index, folder)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_compact_files(base_index : u64, folder : &str) -> Result<(), Er> {
+        for prefix in ['i', 'd'].iter() {
+            match fs::remove_file(format!("{}{}", Topic::segment_file_name(*prefix, base_index, folder), COMPACT_SUFFIX)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                result => result.map_err(Er::CantWriteFile)?,
+            }
+        }
+        Ok(())
+    }
+
+    /* 
+     * the partition folder locked, shared to open a segment's files, exclusive to replace or remove
+     * them. The lock is held until the file returned is dropped
+     */
+    fn lock_folder(folder : &str, exclusive : bool) -> Result<File, Er> {
+        let lock = File::open(folder)
+            .map_err(Er::CantOpenFile)?;
+        let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        if unsafe { libc::flock(lock.as_raw_fd(), operation) } < 0 {
+            return Err(Er::CantReadFile(io::Error::last_os_error()))
+        }
+        Ok(lock)
+    }
+
+    /* empty segment file, left alone if another server has already made it */
+    fn create_segment_file(prefix : char, base_index : u64, folder : &str) -> Result<(), Er> {
+        OpenOptions::new().append(true).create(true).open(Topic::segment_file_name(prefix, base_index, folder))
+            .map(|_| ())
+            .map_err(Er::CantOpenFile)
+    }
+
+    /* base record index of every segment in the topic folder, oldest first */
+    fn segment_list(folder : &str) -> Result<Vec<u64>, Er> {
+        let mut segments = Vec::new();
+
+        for entry in fs::read_dir(folder)
+                        .map_err(Er::CantReadDir)? {
+
+            let file = entry
+                .map_err(Er::CantReadFile)?;
+
+            if let Some(f_name) = file.file_name().to_str() {
+                if let Ok(('d', base_index)) = Topic::parse_file_name(f_name) {
+                    segments.push(base_index);
+                }
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    pub fn earliest_index(&self) -> Result<u64, Er> {
+        let segments = Topic::segment_list(&self.folder)?;
+        Ok(*segments.first().unwrap_or(&self.base_index))
+    }
+
+    /* base index of the segment holding record_index, or OffsetOutOfRange if retention has already removed it */
+    pub fn segment_for(&self, record_index : u64) -> Result<u64, Er> {
+        let segments = Topic::segment_list(&self.folder)?;
+
+        match segments.iter().rev().find(|base| **base <= record_index) {
+            Some(base) => Ok(*base),
+            None => Err(Er::OffsetOutOfRange(record_index, *segments.first().unwrap_or(&self.base_index))),
+        }
+    }
+
+    fn segment_stats(&self, base_index : u64) -> Result<(u64, SystemTime), Er> {
+        let data_meta = fs::metadata(Topic::segment_file_name('d', base_index, &self.folder))
+            .map_err(Er::CantReadFile)?;
+        let index_meta = fs::metadata(Topic::segment_file_name('i', base_index, &self.folder))
+            .map_err(Er::CantReadFile)?;
+        let modified = data_meta.modified()
+            .map_err(Er::CantReadFile)?;
+
+        Ok((data_meta.len() + index_meta.len(), modified))
+    }
+
+    fn remove_segment(&self, base_index : u64) -> Result<(), Er> {
+        let _lock = Topic::lock_folder(&self.folder, true)?;
+
+        // index goes first so a reader never finds index entries without their data
+        for prefix in ['i', 't', 'd'].iter() {
+            let f_name = Topic::segment_file_name(*prefix, base_index, &self.folder);
+            trace!("remove_segment() : deleting {}", f_name);
+            match fs::remove_file(&f_name) {
+                Err(ref e) if *prefix == 't' && e.kind() == io::ErrorKind::NotFound => {}, // older segment without a time index
+                result => result.map_err(Er::CantWriteFile)?,
+            }
+        }
+        Ok(())
+    }
+
+    /* deletes the oldest segments while any retention limit is exceeded, the current segment is always kept */
+    pub fn apply_retention(&mut self) -> Result<usize, Er> {
+        let config = &self.config;
+        if config.retention_ms.is_none() && config.retention_bytes.is_none() && config.retention_segments.is_none() {
+            return Ok(0)
+        }
+
+        let segments = Topic::segment_list(&self.folder)?;
+        let mut stats = Vec::with_capacity(segments.len());
+        for base_index in segments.iter() {
+            stats.push((*base_index, self.segment_stats(*base_index)?));
+        }
+
+        let now = SystemTime::now();
+        let mut total_bytes : u64 = stats.iter().map(|(_, (bytes, _))| bytes).sum();
+        let mut count = stats.len();
+        let mut removed = 0;
+
+        for (base_index, (bytes, modified)) in stats {
+            if base_index >= self.base_index { break; }
+
+            let too_old = match self.config.retention_ms {
+                Some(max_ms) => now.duration_since(modified).map(|age| age.as_millis() as u64 > max_ms).unwrap_or(false),
+                None => false,
+            };
+            let too_big = match self.config.retention_bytes {
+                Some(max_bytes) => total_bytes > max_bytes,
+                None => false,
+            };
+            let too_many = match self.config.retention_segments {
+                Some(max_count) => count > max_count as usize,
+                None => false,
+            };
+
+            if !(too_old || too_big || too_many) { break; }
+
+            self.remove_segment(base_index)?;
+            total_bytes -= bytes;
+            count -= 1;
+            removed += 1;
+        }
+        Ok(removed)
     }
 
     fn file_opener(is_producer : bool) -> OpenOptions {
         let mut f_options = OpenOptions::new();
-        if is_producer { f_options.append(true).create(true); } else { f_options.read(true); }
+        if is_producer { f_options.append(true).read(true).create(true); } else { f_options.read(true); }
         f_options
     }
 
-    fn file_position(&self, record_index : u64) -> usize {
-        let records_per_file = 2^(self.config.file_mask as u64 * 4);
-        (record_index % records_per_file) as usize
+    fn records_per_file(&self) -> Option<u64> {
+        // a mask of 16 hex digits covers every u64 index so the segment never fills on record count
+        if self.config.file_mask >= 16 {
+            None
+        } else {
+            Some(1u64 << (self.config.file_mask as u64 * 4))
+        }
     }
 
-    fn file_number(&self, record_index : u64) -> usize {
-        let records_per_file = 2^(self.config.file_mask as u64 * 4);
-        (record_index % records_per_file) as usize
+    fn file_position(&self, record_index : u64) -> u64 {
+        match self.records_per_file() {
+            Some(records_per_file) => record_index % records_per_file,
+            None => record_index,
+        }
     }
 
+    /* called by consumers when inotify reports a new segment file, returns true if the topic moved to it */
+    pub fn switch_file(&mut self, file_name : &str) -> Result<bool, Er> {
+        let (prefix, base_index) = Topic::parse_file_name(file_name)?;
+        if prefix == 't' { return Ok(false) } // consumers look up time indexes by name when needed
 
-    pub fn switch_file(&mut self, file_name : &str) -> Result<(), Er> {
-        match file_name.chars().nth(0) {
-            Some('i') => {
-                self.index_file_name = String::from(file_name);
-                self.index_file = Self::file_opener(false).open(file_name) //false here as this is only called by consumers
-                    .map_err(|e| Er::CantOpenFile(e))?;
-                Ok(())
-            },
-            Some('d') => {
-                self.data_file_name = String::from(file_name);
-                self.data_file = Self::file_opener(false).open(file_name) //false here as this is only called by consumers
-                    .map_err(|e| Er::CantOpenFile(e))?;
-                Ok(())
-            },
-            _ => {
-                Err(Er::BadFileName)
-            },
+        let path = Topic::segment_file_name(prefix, base_index, &self.folder);
+        let (_, current_base) = Topic::parse_file_name(Topic::file_name_part(match prefix {
+            'i' => &self.index_file_name,
+            'd' => &self.data_file_name,
+            _ => return Err(Er::BadFileName),
+        }))?;
+
+        if base_index <= current_base {
+            return Ok(false)
+        }
+
+        let file = Self::file_opener(false).open(&path) //false here as this is only called by consumers
+            .map_err(Er::CantOpenFile)?;
+
+        if prefix == 'i' {
+            trace!("switch_file() : index now {}", path);
+            self.index_file_name = path;
+            self.index_file = file;
+            self.last_index_offset = 0;
+            self.base_index = base_index;
+        } else {
+            trace!("switch_file() : data now {}", path);
+            self.data_file_name = path;
+            self.data_file = file;
+            self.last_data_offset = 0;
         }
+        Ok(true)
+    }
+
+    /*
+     * a whole record. Producers hold records until they are whole, so nothing else is written
+     * part way through one, and a producer that goes mid-record leaves nothing behind
+     */
+    pub fn append(&mut self, body : &[u8], flags : u8) -> Result<u64, Er> {
+        self.write(body)?;
+        self.end_rec_with_flags(flags)
     }
 
     pub fn write(&mut self, slice : &[u8]) -> Result<usize, Er> {
-        let written = self.data_file.write(slice)
-            .map_err(|e| Er::CantWriteFile(e))?;
-        Ok(written)
+        self.data_file.write_all(slice)
+            .map_err(Er::CantWriteFile)?;
+        self.rec_crc.update(slice);
+        Ok(slice.len())
     }
 
     pub fn end_rec(&mut self) -> Result<u64, Er> {
+        self.end_rec_with_flags(0)
+    }
+
+    /* flags describe what the record body holds, see record.rs */
+    pub fn end_rec_with_flags(&mut self, flags : u8) -> Result<u64, Er> {
         let idx = self.index;
         self.index += 1;
 
-        let file_position = self.data_file.seek(SeekFrom::Current(0))
-            .map_err(|e| Er::CantReadFile(e))?;
+        let trailer = self.rec_crc.trailer(flags);
+        self.data_file.write_all(&trailer)
+            .map_err(Er::CantWriteFile)?;
+        self.rec_crc = Crc32c::new();
+
+        let file_position = self.data_file.stream_position()
+            .map_err(Er::CantReadFile)?;
 
-        let file_position_bytes = (file_position as u64).to_le_bytes();
+        let file_position_bytes = file_position.to_le_bytes();
 
-        self.index_file.write( &file_position_bytes)
-            .map_err(|e| Er::CantWriteFile(e))?;
+        // never step back in time, lookups rely on the time index being in order
+        self.last_timestamp = Topic::timestamp(SystemTime::now()).max(self.last_timestamp);
+        if let Some(time_file) = &mut self.time_file {
+            time_file.write_all(&self.last_timestamp.to_le_bytes())
+                .map_err(Er::CantWriteFile)?;
+        }
+
+        self.index_file.write_all(&file_position_bytes)
+            .map_err(Er::CantWriteFile)?;
 
-        self.current_producer = None;
+        self.unsynced += 1;
+        self.sync_check()?;
         self.create_file_check()?;
         Ok(idx)
     }
 
+    fn sync(&mut self) -> Result<(), Er> {
+        trace!("sync() : fsync {} records on {}", self.unsynced, self.data_file_name);
+        self.data_file.sync_data()
+            .map_err(Er::CantWriteFile)?;
+        self.index_file.sync_data()
+            .map_err(Er::CantWriteFile)?;
+        if let Some(time_file) = &self.time_file {
+            time_file.sync_data()
+                .map_err(Er::CantWriteFile)?;
+        }
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /* fsync if the durability policy says it is due, end_rec() calls this before the record is acknowledged */
+    pub fn sync_check(&mut self) -> Result<(), Er> {
+        if self.unsynced == 0 { return Ok(()) }
+
+        let is_due = match self.config.durability {
+            Durability::Os => false,
+            Durability::Record => true,
+            Durability::EveryRecords(n) => self.unsynced >= n,
+            Durability::EveryMs(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+        };
+
+        if is_due { self.sync() } else { Ok(()) }
+    }
+
     // only called by producers
     fn create_file_check (&mut self) -> Result<(), Er> {
-        if self.file_position(self.index) == 0 {
-            let num = self.file_number(self.index);
+        let is_full = match self.config.segment_bytes {
+            Some(max_bytes) => {
+                let data_size = self.data_file.stream_position()
+                    .map_err(Er::CantReadFile)?;
+                data_size >= max_bytes
+            },
+            None => false,
+        };
+
+        if self.index > self.base_index && (is_full || self.file_position(self.index) == 0) {
+            // named by the next record index, so segments cut on size keep their true starting index
+            let num = self.index;
+            let f_data_name = Topic::segment_file_name('d', num, &self.folder);
+            let f_index_name = Topic::segment_file_name('i', num, &self.folder);
+            trace!("create_file_check() : starting segment {}", f_data_name);
+
+            // records still waiting on a periodic fsync must not be left behind in the closed segment
+            if self.config.durability != Durability::Os && self.unsynced > 0 {
+                self.sync()?;
+            }
+
+            // data file first, consumers expect the data segment to exist when the index one appears
+            self.data_file = Self::file_opener(true).open(&f_data_name)
+                .map_err(Er::CantOpenFile)?;
+
+            self.time_file = Some(Self::file_opener(true).open(Topic::segment_file_name('t', num, &self.folder))
+                .map_err(Er::CantOpenFile)?);
 
-            self.data_file = Self::file_opener(true).open(format!("d{}", num))
-                .map_err(|e| Er::CantOpenFile(e))?;
+            self.index_file = Self::file_opener(true).open(&f_index_name)
+                .map_err(Er::CantOpenFile)?;
+
+            if self.config.durability != Durability::Os {
+                File::open(&self.folder)
+                    .and_then(|folder| folder.sync_all())
+                    .map_err(Er::CantWriteFile)?;
+            }
 
-            self.index_file = Self::file_opener(true).open(format!("i{}", num))
-                .map_err(|e| Er::CantOpenFile(e))?;
+            self.data_file_name = f_data_name;
+            self.index_file_name = f_index_name;
+            self.base_index = num;
+            self.last_data_offset = 0;
+            self.last_index_offset = 0;
         }
         Ok(())
     }
@@ -166,7 +1079,7 @@ impl Topic {
     pub fn read_index_into(&mut self, buf : &mut [u8], start : u64) -> Result<usize,Er> {
 
         self.index_file.seek(SeekFrom::Start(start))
-            .map_err(|e| Er::CantReadFile(e))?;
+            .map_err(Er::CantReadFile)?;
 
         match self.index_file.read(buf) {
             Ok(size) => { 
@@ -180,7 +1093,7 @@ impl Topic {
     pub fn read_data_into(&mut self, buf : &mut [u8], start : u64) -> Result<usize,Er> {
 
         self.data_file.seek(SeekFrom::Start(start))
-            .map_err(|e| Er::CantReadFile(e))?;
+            .map_err(Er::CantReadFile)?;
 
         match self.data_file.read(buf) {
             Ok(size) => { 
@@ -207,68 +1120,343 @@ impl Topic {
         Ok(result)
     }
 
+    /*
+     * sends everything written since the last call to all followers, as frames small enough for a client buffer.
+     * A follower whose socket still has the last frame queued is caught up from the files instead, one
+     * that fails is closed, so neither holds up the others
+     */
     pub fn send_followers (&mut self, client_list: &mut HashMap<u32, ConsumerClient>, feed_type: RecordType) -> Result<Option<usize>, Er> {
 
-        let client_count = self.followers.len();
-        let mut client_no = 0;
+        let index_pos = (&self.index_file).stream_position()
+            .map_err(Er::CantReadFile)?;
+        let data_pos = (&self.data_file).stream_position()
+            .map_err(Er::CantReadFile)?;
 
-        let mut size_to_send: Option<usize> = None;
-
-        let file = match feed_type {
-            RecordType::IndexFeed => self.index_file.as_raw_fd(),
-            RecordType::DataFeed => self.data_file.as_raw_fd(),
+        let (file, start) = match feed_type {
+            RecordType::IndexFeed => (&self.index_file, index_pos),
+            RecordType::DataFeed => (&self.data_file, data_pos),
             _ => return Err(Er::BadFileName),
         };
 
-        for client_id in self.followers.iter() {
-            if let Some(client) = client_list.get_mut(&client_id) {
-                client_no += 1;
+        let end = file.metadata()
+            .map_err(Er::CantReadFile)?
+            .len();
+
+        let (mut available, frame_size) = match feed_type {
+            RecordType::IndexFeed => {
+                let available = (end - start) as usize;
+                (available - (available % 8), MAX_FEED_SIZE - (MAX_FEED_SIZE % 8)) // never split an index entry
+            },
+            _ => ((end - start) as usize, MAX_FEED_SIZE),
+        };
+
+        // with nobody following the position still moves on, so later starters catch up from the files
+        if available == 0 {
+            return Ok(None)
+        }
 
-                let is_last:bool = client_count == client_no;
+        let mut offset = start;
+        let tag = (self.config.topic_id, self.partition);
+
+        while available > 0 {
+            let size = if available > frame_size { frame_size } else { available };
+
+            let followers : Vec<u32> = self.followers.iter().copied().collect();
+            for client_id in followers {
+                let client = match client_list.get_mut(&client_id) {
+                    Some(client) => client,
+                    None => continue,
+                };
+
+                if client.tcp.is_queued() {
+                    let (index_pos, data_pos) = match feed_type {
+                        RecordType::IndexFeed => (offset, data_pos),
+                        _ => (index_pos, offset),
+                    };
+                    trace!("consumer {} has fallen behind, catching up from {}", client_id, offset);
+                    self.followers.remove(&client_id);
+                    client.fall_behind(Catchup { topic_id : tag.0, partition : tag.1, base_index : self.base_index, index_pos, data_pos, files : None });
+                } else if let Err(e) = Self::send_frame(client, tag, file, offset, size, feed_type) {
+                    log_warn!("failed to send feed to consumer {} : {}", client_id, e);
+                    self.followers.remove(&client_id);
+                    client.close();
+                }
+            }
+            offset += size as u64;
+            available -= size;
+        }
 
-                let socket = client.tcp.as_raw_fd();
+        (&*file).seek(SeekFrom::Start(offset))
+            .map_err(Er::CantReadFile)?;
 
-                let n = Self::linux_send_file(socket, file, size_to_send, is_last)?;
+        Ok(Some((offset - start) as usize))
+    }
 
-                if size_to_send.is_none() {
-                    size_to_send = Some(n);
+    /* 
+     * one feed frame of size bytes from offset in the file. Plain sockets are sent what they take of it
+     * with sendfile. TLS has to encrypt it, so it is read into memory and written like any other frame,
+     * as is whatever the socket didn't take, which is queued
+     */
+    fn send_frame (client : &mut ConsumerClient, tag : (u32, u32), file : &File, offset : u64, size : usize, feed_type : RecordType) -> Result<(), Er> {
+        client.send_feed_header(size, feed_type, tag)?;
+
+        let mut sent = 0;
+        if let Some(socket) = client.tcp.sendfile_socket() {
+            let mut file_offset = offset as i64;
+            while sent < size {
+                match Self::linux_send_file(socket, file.as_raw_fd(), &mut file_offset, size - sent)? {
+                    0 => break,
+                    n => sent += n,
                 }
             }
         }
-        Ok(size_to_send)
+
+        if sent < size {
+            let mut content = vec![0u8; size - sent];
+            file.read_exact_at(&mut content, offset + sent as u64)
+                .map_err(Er::CantReadFile)?;
+            client.tcp.write_all(&content)
+                .map_err(Er::ClientTcpWrite)?;
+        }
+        Ok(())
     }
 
-    fn linux_send_file (socket:RawFd, file:RawFd, send_size:Option<usize>, is_last:bool) -> Result<usize, Er> {
-        trace!("calling sendfile"); 
-        let mut zero_offset = 0;
-        let null: *mut i64 = ptr::null_mut();
-
-        // zero offset and null differ as null will update the file descriptor with new position
-
-        let n = match send_size {
-            Some(n) =>  if is_last { 
-                            unsafe { libc::sendfile(socket, file, null, n) }
-                        } else {
-                            unsafe { libc::sendfile(socket, file, &mut zero_offset, n) } 
-                        },
-
-            None    =>  if is_last { 
-                            unsafe { libc::sendfile(socket, file, null, 0x7ffff000 ) }
-                        } else {
-                            unsafe { libc::sendfile(socket, file, &mut zero_offset, 0x7ffff000 ) }
-                        },
+    /* group names become file names, so are kept to characters that are safe in one */
+    pub fn check_group_name(group : &str) -> Result<(), Er> {
+        let valid = !group.is_empty() && group.len() <= MAX_GROUP_NAME
+            && group.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        match valid {
+            true => Ok(()),
+            false => Err(Er::BadGroupName(String::from(group))),
+        }
+    }
+
+    fn offsets_file_name(&self, group : &str) -> String {
+        format!("{}/{}/{}", self.folder, OFFSETS_FOLDER, group)
+    }
+
+    /* last record index the group committed as processed, None if it has never committed */
+    pub fn committed(&self, group : &str) -> Result<Option<u64>, Er> {
+        Topic::check_group_name(group)?;
+
+        match fs::read(self.offsets_file_name(group)) {
+            Ok(bytes) => {
+                let committed : [u8; 8] = bytes.as_slice().try_into()
+                    .map_err(|_| Er::BadRecordFormat(format!("offsets file for group {} is {} bytes, expected 8", group, bytes.len())))?;
+                Ok(Some(u64::from_le_bytes(committed)))
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Er::CantReadFile(e)),
+        }
+    }
+
+    /* 
+     * written to a temporary file then renamed over the last commit, so a crash leaves
+     * one or the other and never a torn offset
+     */
+    pub fn commit(&self, group : &str, record_index : u64) -> Result<(), Er> {
+        Topic::check_group_name(group)?;
+
+        fs::create_dir_all(format!("{}/{}", self.folder, OFFSETS_FOLDER))
+            .map_err(Er::CantWriteFile)?;
+
+        let f_name = self.offsets_file_name(group);
+        let tmp_name = format!("{}.tmp", f_name);
+        let mut file = File::create(&tmp_name)
+            .map_err(Er::CantOpenFile)?;
+        file.write_all(&record_index.to_le_bytes())
+            .map_err(Er::CantWriteFile)?;
+        file.sync_all()
+            .map_err(Er::CantWriteFile)?;
+
+        fs::rename(&tmp_name, &f_name)
+            .map_err(Er::CantWriteFile)
+    }
+
+    /* a group member resumes after the group's last commit, or from start if there is none */
+    pub fn group_start_index(&self, group : &str, start : Start) -> Result<Option<u64>, Er> {
+        match self.committed(group)? {
+            // records retention has removed since the commit are skipped
+            Some(committed) => Ok(Some((committed + 1).max(self.earliest_index()?))),
+            None => self.start_index(start),
+        }
+    }
+
+    /* record index a consumer asking for start begins at, None for whatever is produced next */
+    pub fn start_index(&self, start : Start) -> Result<Option<u64>, Er> {
+        match start {
+            Start::Earliest => Ok(Some(self.earliest_index()?)),
+            Start::Latest => Ok(None),
+            Start::Index(record_index) => Ok(Some(record_index)),
+            Start::Timestamp(ms) => self.index_for_time(ms),
+        }
+    }
+
+    /* 
+     * where a consumer starting at record_index picks up, None starts where followers are now.
+     * the data offset in the result is where the first record sent starts in its segment.
+     * Followers may have been sent part of a record still being written, so the live position
+     * is where the last record with an index entry ends, and the rest is caught up from there
+     */
+    pub fn catchup_from (&self, topic_id : u32, record_index : Option<u64>) -> Result<Catchup, Er> {
+        let live_index = (&self.index_file).stream_position()
+            .map_err(Er::CantReadFile)?;
+        let live_index = live_index - live_index % 8;
+        let live_data = match live_index {
+            0 => 0,
+            n => {
+                let mut entry = [0u8; 8];
+                self.index_file.read_exact_at(&mut entry, n - 8)
+                    .map_err(Er::CantReadFile)?;
+                u64::from_le_bytes(entry)
+            },
+        };
+        let live = Catchup { topic_id, partition : self.partition, base_index : self.base_index, index_pos : live_index, data_pos : live_data, files : None };
+
+        let record_index = match record_index {
+            Some(record_index) if record_index < live.record_index() => record_index,
+            _ => return Ok(live), // nothing older to send
         };
 
+        let base_index = self.segment_for(record_index)?;
+        let (index_file, data_file) = self.open_segment(base_index, record_index)?;
+        let data_pos = match record_index - base_index {
+            0 => 0,
+            n => { // records start where the one before ends
+                let mut entry = [0u8; 8];
+                index_file.read_exact_at(&mut entry, (n - 1) * 8)
+                    .map_err(Er::CantReadFile)?;
+                u64::from_le_bytes(entry)
+            },
+        };
+        Ok(Catchup { topic_id, partition : self.partition, base_index, index_pos : (record_index - base_index) * 8, data_pos, files : Some((index_file, data_file)) })
+    }
+
+    /* 
+     * index and data files of a segment, opened together so a compaction can't switch one between them.
+     * record_index is what the caller is after, for the error if retention has removed the segment
+     */
+    fn open_segment (&self, base_index : u64, record_index : u64) -> Result<(File, File), Er> {
+        let _lock = Topic::lock_folder(&self.folder, false)?;
+        let index_file = File::open(Topic::segment_file_name('i', base_index, &self.folder))
+            .map_err(|_| Er::OffsetOutOfRange(record_index, self.earliest_index().unwrap_or(self.base_index)))?;
+        let data_file = File::open(Topic::segment_file_name('d', base_index, &self.folder))
+            .map_err(Er::CantOpenFile)?;
+        Ok((index_file, data_file))
+    }
+
+    /* 
+     * sends a consumer that started in the past up to max_frames of the history it has 
+     * still to see. once it reaches the followers it becomes one, and true is returned
+     */
+    pub fn catch_up (&mut self, client : &mut ConsumerClient, state : &mut Catchup, max_frames : usize) -> Result<bool, Er> {
+        let mut frames = 0;
+
+        // between the data and index files of a new segment appearing, wait for both
+        if Topic::parse_file_name(Topic::file_name_part(&self.data_file_name))?.1 != self.base_index {
+            return Ok(false)
+        }
+
+        loop {
+            let is_live = state.base_index == self.base_index;
+            if state.files.is_none() {
+                state.files = Some(self.open_segment(state.base_index, state.record_index())?);
+            }
+            let (index_file, data_file) = state.files.as_ref().ok_or(Er::IsNone)?;
+
+            // followers have been sent the live segment up to the shared file positions, closed segments are sent whole
+            let (index_end, data_end) = if is_live {
+                ((&self.index_file).stream_position(), (&self.data_file).stream_position())
+            } else {
+                (index_file.metadata().map(|m| m.len()), data_file.metadata().map(|m| m.len()))
+            };
+            let index_end = index_end.map_err(Er::CantReadFile)?;
+            let data_end = data_end.map_err(Er::CantReadFile)?;
+            let index_end = index_end - index_end % 8;
+
+            // a socket with anything queued is sent no more until it has room
+            while state.data_pos < data_end && frames < max_frames && !client.tcp.is_queued() {
+                let size = ((data_end - state.data_pos) as usize).min(MAX_FEED_SIZE);
+                Self::send_frame(client, self.tag(), data_file, state.data_pos, size, RecordType::DataFeed)?;
+                state.data_pos += size as u64;
+                frames += 1;
+            }
+
+            while state.index_pos < index_end && frames < max_frames && !client.tcp.is_queued() {
+                let size = ((index_end - state.index_pos) as usize).min(MAX_FEED_SIZE - MAX_FEED_SIZE % 8);
+                Self::send_frame(client, self.tag(), index_file, state.index_pos, size, RecordType::IndexFeed)?;
+                state.index_pos += size as u64;
+                frames += 1;
+            }
+
+            if state.data_pos < data_end || state.index_pos < index_end {
+                return Ok(false) // more next time
+            }
+
+            if is_live {
+                self.follow(client.id());
+                return Ok(true)
+            }
+
+            // the next segment is only moved to once this topic has seen it, so history never gets ahead of followers
+            let next = Topic::segment_list(&self.folder)?.into_iter()
+                .find(|base| *base > state.base_index && *base <= self.base_index);
+            match next {
+                Some(base_index) => {
+                    client.send_tagged_feed(&base_index.to_le_bytes(), RecordType::SegmentStart, self.tag())?;
+                    state.base_index = base_index;
+                    state.index_pos = 0;
+                    state.data_pos = 0;
+                    state.files = None;
+                },
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /* tells followers the index feed has moved on to a new segment, so index entries restart from zero */
+    pub fn send_segment_start (&mut self, client_list: &mut HashMap<u32, ConsumerClient>) -> Result<(), Er> {
+        let tag = self.tag();
+        let base_index = self.base_index;
+        self.followers.retain(|client_id| match client_list.get_mut(client_id) {
+            Some(client) => match client.send_tagged_feed(&base_index.to_le_bytes(), RecordType::SegmentStart, tag) {
+                Ok(()) => true,
+                Err(e) => {
+                    log_warn!("failed to send segment start to consumer {} : {}", client_id, e);
+                    client.close();
+                    false
+                },
+            },
+            None => true,
+        });
+        Ok(())
+    }
+
+    fn linux_send_file (socket:RawFd, file:RawFd, offset:&mut i64, send_size:usize) -> Result<usize, Er> {
+        trace!("calling sendfile"); 
+
+        // offset is advanced by the kernel, the file descriptor position is left alone
+        let n = unsafe { libc::sendfile(socket, file, offset, send_size) };
+
         if n < 0 {
-            Err(Er::CantSendFile(io::Error::last_os_error()))
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                Ok(0) // socket buffer is full, the caller queues the rest
+            } else {
+                Err(Er::CantSendFile(e))
+            }
         } else { 
             Ok(n as usize)
         }
     }
 
-    pub fn follow(&mut self, client_id : u32) -> Result<(u64, u64), Er> {
+    /*
+     * followers share the file positions, which move on each time a feed is sent. Clients
+     * join once catch_up has sent them everything up to those positions
+     */
+    pub fn follow(&mut self, client_id : u32) {
         self.followers.insert(client_id);
-        Ok((self.last_index_offset, self.last_data_offset))
     }
 
     #[cfg(test)] 
@@ -286,29 +1474,30 @@ impl Topic {
 
 pub struct TopicList {
     topic_names : HashMap<String, u32>,
-    topics : HashMap<u32, Topic>,
-    pub watchers : HashMap<WatchDescriptor, u32>,
+    topics : HashMap<u32, Vec<Topic>>, /* partitions of each topic, indexed by partition number */
+    pub watchers : HashMap<WatchDescriptor, (u32, u32)>, /* topic_id, partition */
     pub notify : Inotify,
+    next_partition : HashMap<u32, u32>, /* round robin over partitions for records without a key */
 }
 impl TopicList {
 
-    pub fn init (is_producer : bool) -> Result<TopicList, Er> {
+    pub fn init (config : &Config, is_producer : bool) -> Result<TopicList, Er> {
 
         let topic_names : HashMap<String, u32> = HashMap::new();
-        let topics : HashMap<u32, Topic> = HashMap::new();
-        let watchers : HashMap<WatchDescriptor, u32> = HashMap::new();
+        let topics : HashMap<u32, Vec<Topic>> = HashMap::new();
+        let watchers : HashMap<WatchDescriptor, (u32, u32)> = HashMap::new();
         let notify = Inotify::init().expect("Inotify initialization failed - does this linux kernel support inotify?");
-        let mut config = Config::new();
 
         let mut topic_list = TopicList {
             topic_names,
             topics,
             watchers,
             notify,
+            next_partition : HashMap::new(),
         };
 
-        while let Some(topic_cfg) = config.topics.pop() {
-            topic_list.add_topic(topic_cfg, is_producer)?;
+        for topic_cfg in &config.topics {
+            topic_list.add_topic(topic_cfg.clone(), is_producer)?;
         }
 
         Ok(topic_list)
@@ -316,27 +1505,109 @@ impl TopicList {
 
     fn add_topic(&mut self, topic_cfg : TopicConfig, is_producer : bool) -> Result<(),Er>{
 
-        let t = Topic::open(topic_cfg, is_producer)?;
+        let mut partitions = Vec::new();
+
+        for partition in 0..topic_cfg.partitions.max(1) {
+            let t = Topic::open_partition(topic_cfg.clone(), partition, is_producer)?;
 
-        if !is_producer {
-            let folder = format!("{}/{}", t.config.folder, t.config.topic_name);
-            let wd = self.notify.add_watch(folder, WatchMask::MODIFY | WatchMask::CREATE)
-                .map_err(|e| Er::InotifyError(e))?;
-            self.watchers.insert(wd, t.config.topic_id);
+            if !is_producer {
+                let wd = self.notify.add_watch(&t.folder, WatchMask::MODIFY | WatchMask::CREATE)
+                    .map_err(Er::InotifyError)?;
+                self.watchers.insert(wd, (topic_cfg.topic_id, partition));
+            }
+            partitions.push(t);
         }
 
-        self.topic_names.insert(t.config.topic_name.clone(), t.config.topic_id);
-        self.topics.insert(t.config.topic_id, t);
+        self.topic_names.insert(topic_cfg.topic_name.clone(), topic_cfg.topic_id);
+        self.topics.insert(topic_cfg.topic_id, partitions);
 
         Ok(())
     }
 
-    pub fn get_topic(&self, name : &String) -> u32 {
-        *self.topic_names.get(name).unwrap()
+    pub fn topic_id(&self, name : &str) -> Result<u32, Er> {
+        self.topic_names.get(name).copied().ok_or_else(|| Er::UnknownTopic(String::from(name)))
+    }
+
+    /* ids and partition counts of the topics named, or of every topic if none are */
+    pub fn metadata(&self, names : &[String]) -> Result<Vec<TopicMetadata>, Er> {
+        let names = match names.is_empty() {
+            true => {
+                let mut all : Vec<(&String, &u32)> = self.topic_names.iter().collect();
+                all.sort_by_key(|(_, id)| **id);
+                all.into_iter().map(|(name, _)| name.clone()).collect()
+            },
+            false => names.to_vec(),
+        };
+
+        names.into_iter()
+            .map(|name| {
+                let topic_id = self.topic_id(&name)?;
+                Ok(TopicMetadata { topic_id, partitions : self.partition_count(topic_id)?, name })
+            })
+            .collect()
+    }
+
+    pub fn topic_name(&self, topic_id : u32) -> Result<&str, Er> {
+        let partitions = self.topics.get(&topic_id).ok_or(Er::TopicNotFound)?;
+        partitions.first().map(|t| t.config.topic_name.as_str()).ok_or(Er::TopicNotFound)
     }
+
+    /* first partition, which is the whole log for topics that are not partitioned */
     pub fn topic_for_id(&mut self, topic_id : u32) -> Result<&mut Topic, Er> {
-        let topic = self.topics.get_mut(&topic_id).ok_or(Er::TopicNotFound)?;
-        Ok(topic)
+        self.partition_for_id(topic_id, 0)
+    }
+
+    pub fn partition_for_id(&mut self, topic_id : u32, partition : u32) -> Result<&mut Topic, Er> {
+        let partitions = self.topics.get_mut(&topic_id).ok_or(Er::TopicNotFound)?;
+        partitions.get_mut(partition as usize).ok_or(Er::PartitionNotFound(topic_id, partition))
+    }
+
+    /* logs the topic is split over, 1 for a topic that is not partitioned */
+    pub fn partition_count(&self, topic_id : u32) -> Result<u32, Er> {
+        Ok(self.topics.get(&topic_id).ok_or(Er::TopicNotFound)?.len() as u32)
+    }
+
+    /* keyed records always go to the partition for their key, others are spread evenly */
+    pub fn route(&mut self, topic_id : u32, key : Option<&[u8]>) -> Result<u32, Er> {
+        let partitions = self.topics.get(&topic_id).ok_or(Er::TopicNotFound)?.len() as u32;
+
+        match key {
+            Some(key) => Ok(partition_for_key(key, partitions)),
+            None => {
+                let next = self.next_partition.entry(topic_id).or_insert(0);
+                let partition = *next % partitions;
+                *next = (partition + 1) % partitions;
+                Ok(partition)
+            },
+        }
+    }
+
+    /* largest record any topic accepts, offered to clients in the Hello reply */
+    pub fn max_record_bytes(&self) -> u64 {
+        self.topics.values()
+            .filter_map(|partitions| partitions.first())
+            .map(|t| t.max_record_bytes())
+            .max()
+            .unwrap_or(DEFAULT_MAX_RECORD_BYTES)
+    }
+
+    /* timed fsyncs are also due when no records arrive, so servers call this regularly */
+    pub fn sync_check(&mut self) {
+        for topic in self.topics.values_mut().flatten() {
+            if let Err(e) = topic.sync_check() {
+                log_error!("fsync failed on topic {} partition {} : {}", topic.config.topic_name, topic.partition, e);
+            }
+        }
+    }
+
+    pub fn apply_retention(&mut self) {
+        for topic in self.topics.values_mut().flatten() {
+            match topic.apply_retention() {
+                Ok(0) => {},
+                Ok(n) => { trace!("retention removed {} segments from topic {} partition {}", n, topic.config.topic_name, topic.partition); },
+                Err(e) => { log_error!("retention failed on topic {} partition {} : {}", topic.config.topic_name, topic.partition, e); },
+            }
+        }
     }
 
 }
@@ -1,11 +1,16 @@
 use super::*;
-use super::super::tcp::{RecordType};
+use super::super::tcp::{BufferState, RecordType};
 use super::super::test_support::TestEnvironment;
 use super::super::test_support::TestTopic;
 
 use std::net::{TcpListener, TcpStream};
+use std::thread;
 use std::time::Duration;
 use std::fs;
+use std::path::Path;
+use super::super::record::TRAILER_SIZE;
+use super::super::record;
+use super::super::tls::Stream;
 
 #[test]
 fn send_file() {
@@ -26,7 +31,8 @@ fn send_file() {
     let s = server_stream.as_raw_fd();
     trace!("sending file");
 
-    let n = Topic::linux_send_file (s, f, None, false);
+    let mut offset1 = 0;
+    let n = Topic::linux_send_file (s, f, &mut offset1, 11);
     assert!(n.is_ok(), "linux_send_file_failed with {}", n.unwrap());
     assert_eq!(n.unwrap(), b"Hello World".len(), "not written expected length"); 
 
@@ -34,15 +40,17 @@ fn send_file() {
     client_stream.read_to_string(&mut buffer1).ok();
     assert_eq!(buffer1, "Hello World", "testing result 1 (full string no update)");
 
-    let n2 = Topic::linux_send_file (s, f, Some(5), true);
+    let mut offset2 = 0;
+    let n2 = Topic::linux_send_file (s, f, &mut offset2, 5);
     assert!(n2.is_ok(), "linux_send_file_failed with {}", n2.unwrap());
     assert_eq!(n2.unwrap(), b"Hello".len(), "not written expected length"); 
+    assert_eq!(offset2, 5, "offset should be moved on by bytes sent"); 
     
     let mut buffer2 = String::new();
     client_stream.read_to_string(&mut buffer2);
     assert_eq!(buffer2, "Hello", "testing result 3 (5 bytes with update");
 
-    let n3 = Topic::linux_send_file (s, f, None, true);
+    let n3 = Topic::linux_send_file (s, f, &mut offset2, 6);
     assert!(n3.is_ok(), "linux_send_file_failed with {}", n3.unwrap());
     assert_eq!(n3.unwrap(), b" World".len(), "not written expected length"); 
 
@@ -71,7 +79,7 @@ fn send_follower() {
 
     let server_stream = listener.incoming().next().unwrap().unwrap();
 
-    let c = ConsumerClient::new(1, server_stream);
+    let c = ConsumerClient::new(1, Stream::Plain(server_stream));
 
     let mut client_list : HashMap<u32, ConsumerClient> = HashMap::new();
     client_list.insert(1, c);
@@ -87,9 +95,9 @@ fn send_follower() {
     }
 
     trace!("start read as client");
-    let mut buffer = String::new();
-    client_stream.read_to_string(&mut buffer);
-    assert_eq!(String::from("hello"), buffer, "strings don't match");
+    let mut buffer = Vec::new();
+    client_stream.read_to_end(&mut buffer);
+    assert_eq!(&buffer[..], b"\x0a\x00\x00\x00\x04hello", "should be framed as a data feed");
 
     t_producer.write(b"world");
 
@@ -101,10 +109,93 @@ fn send_follower() {
         Ok(None) => assert!(false, "nothing written to stream"),
     }
 
-    let mut buffer2 = String::new();
-    client_stream.read_to_string(&mut buffer2);
-    assert_eq!(String::from("world"), buffer2, "strings don't match");
+    let mut buffer2 = Vec::new();
+    client_stream.read_to_end(&mut buffer2);
+    assert_eq!(&buffer2[..], b"\x0a\x00\x00\x00\x04world", "should be framed as a data feed");
+
+}
+
+/* a small socket buffer, so the socket fills as soon as the other end stops reading */
+fn small_buffers(stream : &TcpStream) {
+    let size : libc::c_int = 4096;
+    for option in [libc::SO_SNDBUF, libc::SO_RCVBUF] {
+        unsafe {
+            libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option, &size as *const _ as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t);
+        }
+    }
+}
+
+/* the content of the data feed frames a client has been sent */
+fn data_feed(mut input : &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    while input.len() >= 5 {
+        let size = u32::from_le_bytes(input[..4].try_into().unwrap()) as usize;
+        if input[4] == RecordType::DataFeed as u8 {
+            data.extend_from_slice(&input[5..size]);
+        }
+        input = &input[size..];
+    }
+    data
+}
 
+#[test]
+fn slow_follower() {
+    let env = TestEnvironment::new("slow_follower");
+    let mut t = Topic::test_new(&env, 2, "slow", false);
+    let mut t_producer = t.test_open(true);
+    let content : Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    t_producer.write(&content).expect("trying to write to file");
+
+    // one follower reading, one that isn't and one that has gone
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client_list : HashMap<u32, ConsumerClient> = HashMap::new();
+    let mut readers = Vec::new();
+    for id in 1..=3 {
+        let reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server_stream = listener.accept().unwrap().0;
+        server_stream.set_nonblocking(true).unwrap();
+        if id == 2 {
+            small_buffers(&reader);
+            small_buffers(&server_stream);
+        }
+        client_list.insert(id, ConsumerClient::new(id, Stream::Plain(server_stream)));
+        t.follow(id);
+        readers.push(reader);
+    }
+    drop(readers.pop());
+    let mut slow = readers.pop().unwrap();
+    let mut fast = readers.pop().unwrap();
+
+    let frames = content.len().div_ceil(MAX_FEED_SIZE);
+    let size = content.len() + 5 * frames;
+    let fast = thread::spawn(move || {
+        let mut buffer = vec![0u8; size];
+        fast.read_exact(&mut buffer).expect("reading the feed");
+        data_feed(&buffer)
+    });
+
+    let r = t.send_followers(&mut client_list, RecordType::DataFeed).expect("followers that fail shouldn't fail the feed");
+    assert_eq!(r, Some(256 * 1024));
+    assert_eq!(fast.join().unwrap(), content, "followers that keep up are sent everything");
+    assert!(t.followers.contains(&1));
+    assert!(matches!(client_list[&3].state(), BufferState::Closed), "the follower that went is closed");
+    assert!(!t.followers.contains(&3));
+    assert!(!t.followers.contains(&2), "the slow follower is caught up from the files instead");
+
+    let slow = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        slow.read_to_end(&mut buffer).expect("reading the feed");
+        data_feed(&buffer)
+    });
+    let client = client_list.get_mut(&2).unwrap();
+    let mut catchups = client.take_catchups();
+    while !catchups.is_empty() || client.tcp.is_queued() {
+        client.send_pending();
+        catchups.retain_mut(|catchup| !t.catch_up(client, catchup, 64).unwrap());
+    }
+    assert!(t.followers.contains(&2), "once caught up it follows again");
+    client_list.clear();
+    assert_eq!(slow.join().unwrap(), content, "and has been sent everything");
 }
 
 #[test]
@@ -123,13 +214,14 @@ fn latest_file() {
         folder : String::from("/tmp"),
         replication : 0, 
         file_mask : 8,
+        ..Default::default()
     };
 
-    let latest_data_name = Topic::latest_file_name('d', &config);
+    let latest_data_name = Topic::latest_file_name('d', &Topic::partition_folder(&config, 0));
     assert!(latest_data_name.is_ok(), "trying to get latest index file without error"); 
     assert_eq!(latest_data_name.unwrap(), "/tmp/testx/d00000000000000a3", "trying to get latest data file");
 
-    let latest_index_name = Topic::latest_file_name('i', &config);
+    let latest_index_name = Topic::latest_file_name('i', &Topic::partition_folder(&config, 0));
     assert!(latest_index_name.is_ok(), "trying to get latest index file without error");
     assert_eq!(latest_index_name.unwrap(), "/tmp/testx/i00000000000000a3", "trying to get latest index file");
 }
@@ -142,7 +234,7 @@ fn test_topicwrite() {
     let idx = t.index;
     let written : usize = t.write(&b"hello"[..]).expect("trying to write to file");
     assert_eq!(written, 5, "5 bytes should be written to file");
-    t.end_rec();
+    t.end_rec().expect("ending record");
     assert_eq!(t.index, idx + 1, "checking index is incremented by one");
 }
 
@@ -179,8 +271,8 @@ fn test_readfirstrecord() {
 #[test]
 #[ignore]
 fn test_topiclist() {
-    let mut tl_producer = TopicList::init(true).unwrap();
-    let mut tl_consumer = TopicList::init(false).unwrap();
+    let mut tl_producer = TopicList::init(&Config::new(), true).unwrap();
+    let mut tl_consumer = TopicList::init(&Config::new(), false).unwrap();
 
     let p_topic_result = tl_producer.topic_for_id(1);
 
@@ -203,7 +295,7 @@ fn test_topiclist() {
     match events.next() {
         Some(e) => {
             match tl_consumer.watchers.get(&e.wd) {
-                Some(topic_id) => assert_eq!(*topic_id, 1u32),
+                Some((topic_id, _)) => assert_eq!(*topic_id, 1u32),
                 None => assert!(false, "topic id missing from watcher list"),
             }
             match e.name {
@@ -220,7 +312,7 @@ fn test_topiclist() {
     match events.next() {
         Some(e) => {
             match tl_consumer.watchers.get(&e.wd) {
-                Some(topic_id) => assert_eq!(*topic_id, 1u32),
+                Some((topic_id, _)) => assert_eq!(*topic_id, 1u32),
                 None => assert!(false, "topic id missing from watcher list"),
             }
             match e.name {
@@ -234,3 +326,608 @@ fn test_topiclist() {
     }
 }
 
+
+#[test]
+fn rollover_on_file_mask() {
+    let env = TestEnvironment::new("rollover_mask");
+    let config = TopicConfig {
+        topic_id : 1,
+        topic_name : String::from("roll"),
+        folder : env.folder.clone(),
+        replication : 0,
+        file_mask : 1, // 16 records per file
+        ..Default::default()
+    };
+    let mut t = Topic::test_new_with(config, true);
+
+    for _ in 0..17 {
+        t.write(b"0123456789").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+
+    assert_eq!(t.index, 17, "checking index counts across segments");
+    assert_eq!(t.get_data_file_name(), format!("{}/roll/d0000000000000010", env.folder), "should have moved to second data segment");
+    assert_eq!(t.get_index_file_name(), format!("{}/roll/i0000000000000010", env.folder), "should have moved to second index segment");
+
+    let first_index = fs::read(format!("{}/roll/i0000000000000000", env.folder)).expect("reading first index segment");
+    assert_eq!(first_index.len(), 16 * 8, "first segment should hold 16 records");
+
+    let second_index = fs::read(format!("{}/roll/i0000000000000010", env.folder)).expect("reading second index segment");
+    assert_eq!(second_index, (10 + TRAILER_SIZE as u64).to_le_bytes().to_vec(), "second segment offsets should start from zero");
+
+    let reopened = t.test_open(true);
+    assert_eq!(reopened.index, 17, "reopened topic should carry on from the latest segment");
+}
+
+#[test]
+fn rollover_on_segment_bytes() {
+    let env = TestEnvironment::new("rollover_bytes");
+    let config = TopicConfig {
+        topic_id : 1,
+        topic_name : String::from("roll"),
+        folder : env.folder.clone(),
+        replication : 0,
+        file_mask : 4,
+        segment_bytes : Some(25),
+        ..Default::default()
+    };
+    let mut t = Topic::test_new_with(config, true);
+
+    for _ in 0..3 {
+        t.write(b"0123456789").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+
+    assert_eq!(t.get_data_file_name(), format!("{}/roll/d0000000000000002", env.folder), "segment should be named from its first record");
+    assert_eq!(fs::read(format!("{}/roll/d0000000000000000", env.folder)).unwrap().len(), 2 * (10 + TRAILER_SIZE), "first segment is cut once it passes 25 bytes");
+}
+
+#[test]
+fn consumer_switch_file() {
+    let env = TestEnvironment::new("consumer_switch");
+    let config = TopicConfig {
+        topic_id : 1,
+        topic_name : String::from("roll"),
+        folder : env.folder.clone(),
+        replication : 0,
+        file_mask : 0, // 1 record per file
+        ..Default::default()
+    };
+    let mut consumer = Topic::test_new_with(config, false);
+    let mut producer = consumer.test_open(true);
+
+    producer.write(b"hello").expect("trying to write to file");
+    producer.end_rec().expect("trying to end record");
+
+    let switched = consumer.switch_file("d0000000000000001").expect("switching data file");
+    assert!(switched, "consumer should move to the new data segment");
+    consumer.switch_file("i0000000000000001").expect("switching index file");
+    assert_eq!(consumer.get_data_file_name(), format!("{}/roll/d0000000000000001", env.folder));
+    assert_eq!(consumer.get_index_file_name(), format!("{}/roll/i0000000000000001", env.folder));
+
+    let stale = consumer.switch_file("d0000000000000000").expect("switching to older data file");
+    assert!(!stale, "consumer should never move back to an older segment");
+    assert!(consumer.switch_file("d0000000000000001.tmp").is_err(), "not a segment file name");
+}
+
+fn retention_topic(env : &TestEnvironment, config : TopicConfig) -> Topic {
+    let mut t = Topic::test_new_with(TopicConfig { folder : env.folder.clone(), file_mask : 0, ..config }, true);
+    for _ in 0..5 {
+        t.write(b"0123456789").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+    t
+}
+
+#[test]
+fn retention_by_segments() {
+    let env = TestEnvironment::new("retention_segments");
+    let mut t = retention_topic(&env, TopicConfig { topic_name : String::from("ret"), retention_segments : Some(2), ..Default::default() });
+
+    assert_eq!(t.apply_retention().expect("applying retention"), 4, "should remove all but 2 segments");
+    assert_eq!(t.earliest_index().unwrap(), 4, "earliest should be the first segment left");
+    assert!(!Path::new(&format!("{}/ret/i0000000000000003", env.folder)).exists(), "index segment should be deleted");
+    assert!(Path::new(&format!("{}/ret/d0000000000000004", env.folder)).exists(), "newer data segment should be kept");
+
+    match t.segment_for(1) {
+        Err(Er::OffsetOutOfRange(1, 4)) => {},
+        _ => assert!(false, "removed record should be out of range"),
+    }
+    assert_eq!(t.segment_for(4).unwrap(), 4);
+    assert_eq!(t.segment_for(9).unwrap(), 5, "records past the end belong to the current segment");
+}
+
+#[test]
+fn retention_by_bytes() {
+    let env = TestEnvironment::new("retention_bytes");
+    let mut t = retention_topic(&env, TopicConfig { topic_name : String::from("ret"), retention_bytes : Some(60), ..Default::default() });
+
+    // each closed segment is 10 data + 10 trailer + 8 index bytes
+    assert_eq!(t.apply_retention().expect("applying retention"), 3, "should remove segments until under 60 bytes");
+    assert_eq!(t.earliest_index().unwrap(), 3);
+}
+
+#[test]
+fn retention_by_age() {
+    let env = TestEnvironment::new("retention_age");
+    let mut t = retention_topic(&env, TopicConfig { topic_name : String::from("ret"), retention_ms : Some(0), ..Default::default() });
+
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(t.apply_retention().expect("applying retention"), 5, "should remove every closed segment");
+    assert_eq!(t.earliest_index().unwrap(), 5, "current segment is never removed");
+    assert!(Path::new(&format!("{}/ret/d0000000000000005", env.folder)).exists());
+}
+
+#[test]
+fn recover_torn_write() {
+    let env = TestEnvironment::new("recover_torn");
+    let mut t = Topic::test_new(&env, 1, "torn", true);
+    t.write(b"complete").expect("trying to write to file");
+    t.end_rec().expect("trying to end record");
+
+    // crash part way through the next record and its index entry
+    t.write(b"incompl").expect("trying to write to file");
+    t.index_file.write_all(&[15, 0, 0]).expect("writing partial index entry");
+
+    let mut reopened = t.test_open(true);
+    let data_name = format!("{}/torn/d0000000000000000", env.folder);
+    let index_name = format!("{}/torn/i0000000000000000", env.folder);
+
+    let record_len = (8 + TRAILER_SIZE) as u64;
+    assert_eq!(fs::read(&data_name).unwrap().len() as u64, record_len, "unindexed data should be truncated");
+    assert_eq!(fs::read(&index_name).unwrap(), record_len.to_le_bytes().to_vec(), "partial index entry should be truncated");
+    assert_eq!(reopened.index, 1, "index should carry on after the last complete record");
+
+    reopened.write(b"next").expect("trying to write to file");
+    reopened.end_rec().expect("trying to end record");
+    assert_eq!(fs::read(&index_name).unwrap().len(), 16, "new record appended after the repaired tail");
+    assert_eq!(&fs::read(&data_name).unwrap()[record_len as usize..][..4], b"next", "new record should follow the repaired tail");
+}
+
+#[test]
+fn recover_index_past_data() {
+    let env = TestEnvironment::new("recover_index");
+    let mut t = Topic::test_new(&env, 1, "lost", true);
+    t.write(b"first").expect("trying to write to file");
+    t.end_rec().expect("trying to end record");
+    t.write(b"second").expect("trying to write to file");
+    t.end_rec().expect("trying to end record");
+
+    // data for the second record never reached the disk but its index entry did
+    let data_name = format!("{}/lost/d0000000000000000", env.folder);
+    let first_len = 5 + TRAILER_SIZE;
+    OpenOptions::new().write(true).open(&data_name).unwrap().set_len(first_len as u64 + 2).expect("truncating data");
+
+    let reopened = t.test_open(true);
+    assert_eq!(reopened.index, 1, "record with missing data should be dropped");
+    assert_eq!(fs::read(&data_name).unwrap().len(), first_len, "partial data of dropped record should be truncated");
+}
+
+#[test]
+fn durability_policy() {
+    let env = TestEnvironment::new("durability");
+    let write_records = |t : &mut Topic, n : u32| {
+        for _ in 0..n {
+            t.write(b"payment").expect("trying to write to file");
+            t.end_rec().expect("trying to end record");
+        }
+    };
+
+    let mut os = Topic::test_new_with(TopicConfig { topic_name : String::from("os"), folder : env.folder.clone(), file_mask : 4, ..Default::default() }, true);
+    write_records(&mut os, 3);
+    assert_eq!(os.unsynced, 3, "os policy should never fsync");
+
+    let mut every_record = Topic::test_new_with(TopicConfig { topic_name : String::from("record"), folder : env.folder.clone(), file_mask : 4, durability : Durability::Record, ..Default::default() }, true);
+    write_records(&mut every_record, 1);
+    assert_eq!(every_record.unsynced, 0, "record policy should fsync before end_rec returns");
+
+    let mut every_n = Topic::test_new_with(TopicConfig { topic_name : String::from("every"), folder : env.folder.clone(), file_mask : 4, durability : Durability::EveryRecords(3), ..Default::default() }, true);
+    write_records(&mut every_n, 2);
+    assert_eq!(every_n.unsynced, 2, "should wait for 3 records");
+    write_records(&mut every_n, 1);
+    assert_eq!(every_n.unsynced, 0, "should fsync on third record");
+
+    let mut timed = Topic::test_new_with(TopicConfig { topic_name : String::from("timed"), folder : env.folder.clone(), file_mask : 4, durability : Durability::EveryMs(20), ..Default::default() }, true);
+    write_records(&mut timed, 1);
+    assert_eq!(timed.unsynced, 1, "should wait for the interval");
+    std::thread::sleep(Duration::from_millis(25));
+    timed.sync_check().expect("checking sync");
+    assert_eq!(timed.unsynced, 0, "should fsync once the interval has passed without new records");
+}
+
+#[test]
+fn seek_by_time() {
+    let env = TestEnvironment::new("seek_by_time");
+    let config = TopicConfig { topic_name : String::from("timed"), folder : env.folder.clone(), file_mask : 1, ..Default::default() };
+    let mut t = Topic::test_new_with(config, true);
+
+    let start = Topic::timestamp(SystemTime::now());
+    for _ in 0..20 {
+        t.write(b"tick").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+    std::thread::sleep(Duration::from_millis(20));
+    let middle = Topic::timestamp(SystemTime::now());
+    for _ in 0..5 {
+        t.write(b"tock").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+
+    let times = fs::read(format!("{}/timed/t0000000000000000", env.folder)).expect("reading time index");
+    assert_eq!(times.len(), 16 * 8, "one timestamp per record in the first segment");
+
+    assert_eq!(t.index_for_time(0).unwrap(), Some(0), "everything is after the epoch");
+    assert_eq!(t.index_for_time(start).unwrap(), Some(0));
+    assert_eq!(t.index_for_time(middle).unwrap(), Some(20), "should find first record written after the pause, in the second segment");
+    assert_eq!(t.index_for_time(middle + 60_000).unwrap(), None, "nothing written in the future");
+}
+
+#[test]
+fn recover_time_index() {
+    let env = TestEnvironment::new("recover_time");
+    let mut t = Topic::test_new(&env, 1, "timed", true);
+    for _ in 0..3 {
+        t.write(b"tick").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+
+    let time_name = format!("{}/timed/t0000000000000000", env.folder);
+
+    // crash after the time entry is written but before its index entry
+    t.time_file.as_mut().unwrap().write_all(&[1, 2, 3, 4, 5, 6, 7, 8]).expect("writing extra time entry");
+    drop(t.test_open(true));
+    assert_eq!(fs::read(&time_name).unwrap().len(), 3 * 8, "extra time entry should be truncated");
+
+    // segment written before time indexes existed
+    fs::remove_file(&time_name).expect("removing time index");
+    let reopened = t.test_open(true);
+    assert_eq!(fs::read(&time_name).unwrap().len(), 3 * 8, "missing time entries should be filled in");
+    assert!(reopened.last_timestamp > 0, "filled with data file time");
+}
+
+fn write_keyed(t : &mut Topic, key : &[u8], value : &[u8]) -> u64 {
+    let message = Message::new(value).with_key(key);
+    t.write(&message.body().unwrap()).expect("trying to write to file");
+    t.end_rec_with_flags(message.flags()).expect("trying to end record")
+}
+
+fn segment_values(t : &Topic, base_index : u64) -> Vec<Option<(Option<Vec<u8>>, Vec<u8>)>> {
+    let mut segment = SegmentReader::open(&t.folder, base_index).expect("opening segment");
+    let mut values = Vec::new();
+    while let Some(record) = segment.next_record().expect("reading segment") {
+        if record.bytes.is_empty() { values.push(None); continue }
+        let (flags, body) = record::open_record(record.bytes).expect("valid record");
+        let message = Message::from_body(flags, body).expect("valid body");
+        values.push(Some((message.key, message.value)));
+    }
+    values
+}
+
+#[test]
+fn compaction() {
+    let env = TestEnvironment::new("compaction");
+    let config = TopicConfig { topic_name : String::from("entities"), folder : env.folder.clone(), file_mask : 1, compact : true, ..Default::default() };
+    let mut t = Topic::test_new_with(config.clone(), true);
+    let mut compaction = Compaction::new(config, 0);
+
+    write_keyed(&mut t, b"a", b"a1");
+    write_keyed(&mut t, b"b", b"b1");
+    t.write(b"no key").expect("trying to write to file");
+    t.end_rec().expect("trying to end record");
+    write_keyed(&mut t, b"a", b"a2");
+    write_keyed(&mut t, b"c", b"c1");
+    write_keyed(&mut t, b"c", b""); // tombstone
+    for n in 6..16 {
+        write_keyed(&mut t, b"filler", format!("f{}", n).as_bytes());
+    }
+    // current segment, never rewritten but its keys still replace older ones
+    write_keyed(&mut t, b"b", b"b2");
+
+    let removed = compaction.run().expect("compacting");
+    assert_eq!(removed, 12, "a1, b1, c1 and 9 fillers should go");
+
+    let values = segment_values(&t, 0);
+    assert_eq!(values.len(), 16, "every record keeps its index entry");
+    assert_eq!(values[0], None, "a1 replaced by a2");
+    assert_eq!(values[1], None, "b1 replaced by b2 in current segment");
+    assert_eq!(values[2], Some((None, b"no key".to_vec())), "unkeyed records are kept");
+    assert_eq!(values[3], Some((Some(b"a".to_vec()), b"a2".to_vec())));
+    assert_eq!(values[5], Some((Some(b"c".to_vec()), Vec::new())), "recent tombstone is kept");
+    assert_eq!(values[15], Some((Some(b"filler".to_vec()), b"f15".to_vec())));
+    assert_eq!(segment_values(&t, 16).len(), 1, "current segment untouched");
+
+    assert_eq!(compaction.run().expect("compacting again"), 0, "nothing more to remove");
+    assert_eq!(compaction.next_index, 17, "records are only read once");
+    assert!(!Path::new(&format!("{}/entities/d0000000000000000{}", env.folder, COMPACT_SUFFIX)).exists(), "temporary files renamed away");
+
+    // tombstones go once they are older than tombstone_ms
+    compaction.config.tombstone_ms = Some(0);
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(compaction.run().expect("compacting tombstone"), 1);
+    assert_eq!(segment_values(&t, 0)[5], None, "expired tombstone removed");
+
+    // a key written again replaces its record in a segment compacted before
+    write_keyed(&mut t, b"a", b"a3");
+    assert_eq!(compaction.run().expect("compacting new records"), 1);
+    assert_eq!(segment_values(&t, 0)[3], None, "a2 replaced by a3");
+    assert_eq!(compaction.next_index, 18);
+
+    let reopened = t.test_open(true);
+    assert_eq!(reopened.index, 18, "record indexes unchanged by compaction");
+}
+
+#[test]
+fn compaction_finished_on_open() {
+    let env = TestEnvironment::new("compaction_finished_on_open");
+    let config = TopicConfig { topic_name : String::from("entities"), folder : env.folder.clone(), file_mask : 1, compact : true, ..Default::default() };
+    let mut t = Topic::test_new_with(config.clone(), true);
+    for n in 0..17 {
+        write_keyed(&mut t, format!("k{}", n % 4).as_bytes(), format!("v{}", n).as_bytes());
+    }
+
+    let name = |prefix : char, suffix : &str| format!("{}/entities/{}0000000000000000{}", env.folder, prefix, suffix);
+    let old_data = fs::read(name('d', "")).unwrap();
+    assert_eq!(Compaction::new(config, 0).run().expect("compacting"), 13);
+    let new_data = fs::read(name('d', "")).unwrap();
+
+    // a rewrite that never switched is thrown away
+    fs::write(name('i', COMPACT_SUFFIX), b"partial").unwrap();
+    fs::write(name('d', COMPACT_SUFFIX), b"partial").unwrap();
+    t.test_open(true);
+    assert!(!Path::new(&name('i', COMPACT_SUFFIX)).exists() && !Path::new(&name('d', COMPACT_SUFFIX)).exists());
+    assert_eq!(fs::read(name('d', "")).unwrap(), new_data, "segment left as it was");
+
+    // a crash between switching the index and the data is finished
+    fs::write(name('d', ""), &old_data).unwrap();
+    fs::write(name('d', COMPACT_SUFFIX), &new_data).unwrap();
+    t.test_open(true);
+    assert!(!Path::new(&name('d', COMPACT_SUFFIX)).exists());
+    assert_eq!(segment_values(&t, 0)[15], Some((Some(b"k3".to_vec()), b"v15".to_vec())), "data matches the switched index");
+}
+
+#[test]
+fn compaction_bad_index() {
+    let env = TestEnvironment::new("compaction_bad_index");
+    let config = TopicConfig { topic_name : String::from("entities"), folder : env.folder.clone(), file_mask : 1, compact : true, ..Default::default() };
+    let mut t = Topic::test_new_with(config.clone(), true);
+
+    for n in 0..17 {
+        write_keyed(&mut t, b"a", format!("a{}", n).as_bytes());
+    }
+
+    // an index entry past the end of the data, as a damaged file might have
+    let index_name = format!("{}/entities/i0000000000000000", env.folder);
+    let mut index = fs::read(&index_name).unwrap();
+    index[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    fs::write(&index_name, &index).unwrap();
+
+    match Compaction::new(config, 0).run() {
+        Err(Er::BadRecordFormat(_)) => {},
+        other => panic!("expected BadRecordFormat, got {:?}", other),
+    }
+    assert!(!Path::new(&format!("{}/entities/d0000000000000000{}", env.folder, COMPACT_SUFFIX)).exists(), "segment left alone");
+}
+
+#[test]
+fn partition_for_key_is_stable() {
+    // FNV-1a of the key, so these hold for every build and platform
+    assert_eq!(partition_for_key(b"a", 8), 0xe40c_292c % 8);
+    assert_eq!(partition_for_key(b"user-1", 8), 4);
+    assert_eq!(partition_for_key(b"user-2", 8), 5);
+    assert_eq!(partition_for_key(b"user-3", 8), 2);
+
+    assert_eq!(partition_for_key(b"user-1", 0), 0, "unpartitioned topic");
+    assert_eq!(partition_for_key(b"user-1", 1), 0, "single partition");
+
+    let mut used = HashSet::new();
+    for n in 0..100 {
+        used.insert(partition_for_key(format!("key-{}", n).as_bytes(), 4));
+    }
+    assert_eq!(used.len(), 4, "keys should spread over every partition");
+}
+
+#[test]
+fn partition_folders() {
+    let env = TestEnvironment::new("partition_folders");
+    let config = TopicConfig { topic_name : String::from("orders"), folder : env.folder.clone(), file_mask : 4, partitions : 3, ..Default::default() };
+
+    let mut partitions : Vec<Topic> = (0..3)
+        .map(|p| Topic::open_partition(config.clone(), p, true).expect("opening partition"))
+        .collect();
+
+    for p in 0..3 {
+        assert!(Path::new(&format!("{}/orders/p{}/d0000000000000000", env.folder, p)).exists(), "producer creates partition {}", p);
+        assert!(Path::new(&format!("{}/orders/p{}/i0000000000000000", env.folder, p)).exists());
+    }
+
+    partitions[2].write(b"hello").expect("trying to write to partition");
+    assert_eq!(partitions[2].end_rec().expect("trying to end record"), 0, "first record of the partition");
+    assert_eq!(partitions[2].partition(), 2);
+
+    let data_len = |p : u32| fs::metadata(format!("{}/orders/p{}/d0000000000000000", env.folder, p)).unwrap().len();
+    assert_eq!(data_len(2), (5 + TRAILER_SIZE) as u64, "record only written to its partition");
+    assert_eq!(data_len(0), 0);
+    assert_eq!(data_len(1), 0);
+
+    let consumer = Topic::open_partition(config, 2, false).expect("consumer opening partition");
+    assert_eq!(consumer.index, 1, "each partition numbers its own records");
+}
+
+#[test]
+fn start_from_index() {
+    let env = TestEnvironment::new("start_from_index");
+    let config = TopicConfig { topic_name : String::from("history"), folder : env.folder.clone(), file_mask : 1, ..Default::default() };
+    let mut producer = Topic::test_new_with(config, true);
+
+    for _ in 0..20 {
+        producer.write(b"tick").expect("trying to write to file");
+        producer.end_rec().expect("trying to end record");
+    }
+    let record_size = (4 + TRAILER_SIZE) as u64;
+    let consumer = producer.test_open(false);
+
+    let latest = consumer.catchup_from(1, None).expect("starting at latest");
+    assert_eq!((latest.record_index(), latest.data_pos), (20, 4 * record_size), "latest starts after the last record");
+
+    let first = consumer.catchup_from(1, consumer.start_index(Start::Earliest).unwrap()).expect("starting at earliest");
+    assert_eq!((first.record_index(), first.data_pos), (0, 0));
+
+    let middle = consumer.catchup_from(1, Some(3)).expect("starting in the first segment");
+    assert_eq!((middle.record_index(), middle.data_pos), (3, 3 * record_size), "data starts where the record before ends");
+
+    let second = consumer.catchup_from(1, Some(17)).expect("starting in the second segment");
+    assert_eq!((second.record_index(), second.data_pos), (17, record_size), "offsets are within the segment");
+
+    let future = consumer.catchup_from(1, Some(500)).expect("starting past the end");
+    assert_eq!(future.record_index(), 20, "indexes not yet written start at latest");
+
+    // half of a record written, which followers have already been sent
+    producer.write(b"ti").expect("trying to write to file");
+    let consumer = producer.test_open(false);
+    let joining = consumer.catchup_from(1, None).expect("following part way through a record");
+    assert_eq!((joining.record_index(), joining.data_pos), (20, 4 * record_size), "followers start with the record being written, not part way through it");
+
+    match Start::from_wire(9, 0) {
+        Err(Er::BadStartMode(9)) => {},
+        _ => assert!(false, "unknown start modes should be rejected"),
+    }
+    assert_eq!(Start::from_wire(2, 17).unwrap(), Start::Index(17));
+}
+
+#[test]
+fn catch_up_across_segments() {
+    let env = TestEnvironment::new("catch_up");
+    let config = TopicConfig { topic_name : String::from("history"), folder : env.folder.clone(), file_mask : 1, ..Default::default() };
+    let mut producer = Topic::test_new_with(config, true);
+
+    for _ in 0..20 {
+        producer.write(b"tick").expect("trying to write to file");
+        producer.end_rec().expect("trying to end record");
+    }
+    let mut consumer = producer.test_open(false);
+
+    let addr = "127.0.0.1:34294"; 
+    let listener = TcpListener::bind(&addr).unwrap();
+    let mut client_stream = TcpStream::connect(&addr).unwrap();
+    client_stream.set_read_timeout(Some(Duration::new(1, 0))).expect("cant set timeout duration on client");
+    let mut client = ConsumerClient::new(1, Stream::Plain(listener.incoming().next().unwrap().unwrap()));
+
+    let mut state = consumer.catchup_from(1, Some(14)).expect("starting near the end of the first segment");
+    assert!(!consumer.catch_up(&mut client, &mut state, 1).unwrap(), "one frame is not enough to catch up");
+    assert!(consumer.catch_up(&mut client, &mut state, 100).unwrap(), "should reach the live segment");
+    assert!(consumer.followers.contains(&1), "caught up clients follow the topic");
+
+    let mut buffer = Vec::new();
+    client_stream.read_to_end(&mut buffer).ok();
+
+    let record_size = 4 + TRAILER_SIZE;
+    let mut expected = Vec::new();
+    let mut frame = |feed_type : RecordType, content : &[u8]| {
+        expected.extend_from_slice(&(5 + content.len() as u32).to_le_bytes());
+        expected.push(feed_type as u8);
+        expected.extend_from_slice(content);
+    };
+    let first_segment = fs::read(format!("{}/history/d0000000000000000", env.folder)).unwrap();
+    let first_index = fs::read(format!("{}/history/i0000000000000000", env.folder)).unwrap();
+    frame(RecordType::DataFeed, &first_segment[14 * record_size..]);
+    frame(RecordType::IndexFeed, &first_index[14 * 8..]);
+    frame(RecordType::SegmentStart, &16u64.to_le_bytes());
+    frame(RecordType::DataFeed, &fs::read(format!("{}/history/d0000000000000010", env.folder)).unwrap());
+    frame(RecordType::IndexFeed, &fs::read(format!("{}/history/i0000000000000010", env.folder)).unwrap());
+    assert_eq!(buffer, expected, "the rest of the first segment, then all of the second");
+}
+
+#[test]
+fn catch_up_through_compaction() {
+    let env = TestEnvironment::new("catch_up_through_compaction");
+    let config = TopicConfig { topic_name : String::from("entities"), folder : env.folder.clone(), file_mask : 1, compact : true, ..Default::default() };
+    let mut producer = Topic::test_new_with(config.clone(), true);
+    for n in 0..17 {
+        write_keyed(&mut producer, format!("k{}", n % 4).as_bytes(), format!("v{}", n).as_bytes());
+    }
+    let mut consumer = producer.test_open(false);
+    let first_segment = fs::read(format!("{}/entities/d0000000000000000", env.folder)).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client_stream.set_read_timeout(Some(Duration::new(1, 0))).expect("cant set timeout duration on client");
+    let mut client = ConsumerClient::new(1, Stream::Plain(listener.accept().unwrap().0));
+
+    // the segment is rewritten after the consumer has started on it
+    let mut state = consumer.catchup_from(1, Some(2)).expect("starting in the first segment");
+    let data_pos = state.data_pos as usize;
+    assert_eq!(Compaction::new(config, 0).run().expect("compacting"), 13);
+    assert!(consumer.catch_up(&mut client, &mut state, 100).unwrap(), "should reach the live segment");
+    drop(client);
+
+    let mut buffer = Vec::new();
+    client_stream.read_to_end(&mut buffer).ok();
+    let sent = data_feed(&buffer);
+    assert_eq!(&sent[..first_segment.len() - data_pos], &first_segment[data_pos..], "the segment as it was when the consumer started on it");
+}
+
+#[test]
+fn group_offsets() {
+    let env = TestEnvironment::new("group_offsets");
+    let config = TopicConfig { topic_name : String::from("grouped"), folder : env.folder.clone(), file_mask : 4, ..Default::default() };
+    let mut t = Topic::test_new_with(config, true);
+
+    for _ in 0..5 {
+        t.write(b"work").expect("trying to write to file");
+        t.end_rec().expect("trying to end record");
+    }
+
+    assert_eq!(t.committed("billing").unwrap(), None, "nothing committed yet");
+    assert_eq!(t.group_start_index("billing", Start::Earliest).unwrap(), Some(0), "groups without a commit use start");
+    assert_eq!(t.group_start_index("billing", Start::Latest).unwrap(), None);
+
+    t.commit("billing", 2).expect("committing");
+    t.commit("billing", 3).expect("committing again");
+    assert_eq!(t.committed("billing").unwrap(), Some(3), "last commit wins");
+    assert_eq!(t.group_start_index("billing", Start::Earliest).unwrap(), Some(4), "carry on after the last record committed");
+    assert_eq!(t.committed("audit").unwrap(), None, "groups keep their own offsets");
+
+    let reopened = t.test_open(false);
+    assert_eq!(reopened.committed("billing").unwrap(), Some(3), "commits outlive the topic being reopened");
+    assert!(!Path::new(&format!("{}/grouped/offsets/billing.tmp", env.folder)).exists(), "temporary file renamed into place");
+
+    for bad in ["", "../escape", "a.b", &"x".repeat(MAX_GROUP_NAME + 1)] {
+        match t.commit(bad, 0) {
+            Err(Er::BadGroupName(_)) => {},
+            _ => assert!(false, "{:?} should not be accepted as a group name", bad),
+        }
+    }
+}
+
+#[test]
+fn prepare_topic_folder() {
+    let env = TestEnvironment::new("prepare_topic_folder");
+    let config = TopicConfig { topic_name : String::from("fresh"), folder : env.folder.clone(), file_mask : 4, ..Default::default() };
+    let folder = format!("{}/fresh", env.folder);
+
+    let consumer = Topic::open(config.clone(), false).expect("consumer opening a topic nobody has made");
+    assert_eq!(consumer.index, 0);
+    assert!(Path::new(&format!("{}/d0000000000000000", folder)).exists(), "first segment created");
+    assert!(Path::new(&format!("{}/i0000000000000000", folder)).exists());
+    Topic::prepare_folder(&folder).expect("preparing again changes nothing");
+
+    // a crash while starting a segment leaves its data file without an index
+    File::create(format!("{}/d0000000000000010", folder)).unwrap();
+    Topic::prepare_folder(&folder).expect("interrupted segment start");
+    assert!(Path::new(&format!("{}/i0000000000000010", folder)).exists(), "index of the new segment created");
+
+    // a crash during retention leaves an older data file behind
+    fs::remove_file(format!("{}/i0000000000000000", folder)).unwrap();
+    Topic::prepare_folder(&folder).expect("interrupted retention");
+    assert!(!Path::new(&format!("{}/d0000000000000000", folder)).exists(), "removal finished");
+
+    fs::write(format!("{}/d0000000000000020", folder), b"lost").unwrap();
+    match Topic::prepare_folder(&folder) {
+        Err(Er::InconsistentSegments(message)) => assert!(message.contains("d0000000000000020"), "{}", message),
+        x => assert!(false, "data without an index should be refused, got {:?}", x),
+    }
+    fs::remove_file(format!("{}/d0000000000000020", folder)).unwrap();
+
+    File::create(format!("{}/i0000000000000030", folder)).unwrap();
+    assert!(matches!(Topic::prepare_folder(&folder), Err(Er::InconsistentSegments(_))), "index without its data");
+}
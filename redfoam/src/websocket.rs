@@ -0,0 +1,690 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, TryRecvError, TrySendError};
+use std::thread;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use super::buff::Buff;
+use super::client::{self, Client, Messages};
+use super::config::WebSocketConfig;
+use super::er::Er;
+use super::hello::Hello;
+use super::metadata;
+use super::record::Message;
+use super::scram::ScramClient;
+use super::tcp::{Connection, RecordType, ALL_PARTITIONS};
+use super::tls::Stream;
+use super::{trace, log_warn};
+
+/*
+ * The consumer server takes browsers on the websocket listener as well. The gateway does
+ * the HTTP upgrade, then speaks the protocol to the consumer server on the browser's
+ * behalf, so browsers authenticate, are checked against the acls and follow topics just
+ * as any other consumer. The browser sends JSON commands as text messages :
+ *
+ *   {"auth" : "ANON"}                         or "principal:secret", which is proved by SCRAM
+ *   {"follow" : ["orders", "payments"]}       every partition of each topic, from the next record
+ *   {"produce" : "orders", "value" : "..."}   with an optional "key", when the gateway produces
+ *   {"produce" : "orders"}                    binary messages that follow are records for orders
+ *
+ * and is sent one message for each record, the value as a binary message, or as text
+ * with ?format=text on the url. ?format=json sends text messages with the record's topic,
+ * partition, index, key and value. Anything else it is told is JSON text too :
+ *
+ *   {"following" : ["orders", "payments"]}
+ *   {"produced" : "orders", "index" : 12}
+ *   {"error" : 106, "message" : "..."}
+ *
+ * Records browsers produce are sent on to the producer server with a Client per topic,
+ * connected the first time a browser produces to it, using the browser's auth. Each runs
+ * on its own thread, so the consumer server never waits on the producer server, and acks
+ * are passed on as the server loop calls send_pending.
+ *
+ * Browsers send the page they are on as Origin, which has to be one of allowed_origins,
+ * so other sites can't use a visitor's browser to reach topics. Clients other than
+ * browsers send no Origin.
+ */
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_REQUEST: usize = 8192; // of the HTTP upgrade request
+const MAX_MESSAGE: usize = 1 << 20; // of a message from the browser
+const MAX_WAITING: usize = 64; // records a browser has produced that the producer server hasn't acked yet
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Binary,
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Default)]
+struct Command {
+    auth : Option<String>,
+    follow : Option<Vec<String>>,
+    produce : Option<String>,
+    key : Option<String>,
+    value : Option<String>,
+}
+
+pub struct WebSocket {
+    inner : Connection,
+    config : WebSocketConfig,
+    upgraded : bool,
+    closed : bool, /* close sent, nothing more goes to the browser */
+    format : Format,
+    input : Vec<u8>,           /* from the browser, not yet a whole frame, or the upgrade request */
+    message : Vec<u8>,         /* fragments of a message still arriving */
+    message_op : u8,
+    inbound : VecDeque<u8>,    /* requests for the consumer server, read from this stream */
+    outbound : Vec<u8>,        /* from the consumer server, not yet a whole frame */
+    seq : u8,
+    token : Option<String>,
+    scram : Option<ScramClient>,
+    following : Vec<String>,   /* names of the follow waiting on its metadata */
+    names : HashMap<u32, String>,
+    logs : HashMap<(u32, u32), Messages>,
+    producers : HashMap<String, Producer>,
+    produce_to : Option<String>, /* topic binary messages are records for */
+}
+
+/* a Client on its own thread sending records to one topic, each acked before the next is sent */
+struct Producer {
+    records : mpsc::SyncSender<Message>,
+    replies : mpsc::Receiver<Value>, /* for the browser */
+}
+impl Producer {
+    fn start(topic : String, url : String, token : String) -> Producer {
+        let (records, waiting) = mpsc::sync_channel::<Message>(MAX_WAITING);
+        let (reply, replies) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut client = match Client::connect(topic.clone(), url, token) {
+                Ok(client) => client,
+                Err(e) => {
+                    let _ = reply.send(error_json(&e));
+                    return
+                },
+            };
+            // ends when the browser goes, or the connection to the producer server does
+            for message in waiting {
+                let acked = client.send_message(&message)
+                    .map_err(Er::ClientTcpWrite)
+                    .and_then(|_| client.ack());
+                let (outcome, carry_on) = match acked {
+                    Ok(index) => (json!({ "produced" : topic, "index" : index }), true),
+                    Err(e) => (error_json(&e), !e.closes_connection()),
+                };
+                if reply.send(outcome).is_err() || !carry_on {
+                    return
+                }
+            }
+        });
+        Producer { records, replies }
+    }
+}
+
+impl WebSocket {
+    pub fn new(inner : Stream, config : WebSocketConfig) -> WebSocket {
+        WebSocket {
+            inner : Connection::new(inner),
+            config,
+            upgraded : false,
+            closed : false,
+            format : Format::Binary,
+            input : Vec::new(),
+            message : Vec::new(),
+            message_op : OP_TEXT,
+            inbound : VecDeque::new(),
+            outbound : Vec::new(),
+            seq : 0,
+            token : None,
+            scram : None,
+            following : Vec::new(),
+            names : HashMap::new(),
+            logs : HashMap::new(),
+            producers : HashMap::new(),
+            produce_to : None,
+        }
+    }
+
+    pub fn inner(&self) -> &Connection {
+        &self.inner
+    }
+
+    /* true while there are requests for the consumer server it hasn't read yet */
+    pub fn has_buffered(&mut self) -> bool {
+        !self.inbound.is_empty() || self.inner.has_buffered()
+    }
+
+    /* sends on what the socket couldn't take earlier, and tells the browser about records it produced */
+    pub fn send_pending(&mut self) -> io::Result<()> {
+        self.inner.send_pending()?;
+        self.producer_replies()
+    }
+
+    /* the HTTP upgrade, then whole frames from the browser as they arrive */
+    fn receive(&mut self) -> Result<(), Er> {
+        if !self.upgraded {
+            return self.upgrade()
+        }
+
+        while let Some(Frame { fin, op, payload, size }) = parse_frame(&self.input)? {
+            self.input.drain(..size);
+            match op {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    if op != OP_CONTINUATION {
+                        self.message_op = op;
+                        self.message.clear();
+                    }
+                    if self.message.len() + payload.len() > MAX_MESSAGE {
+                        return Err(Er::RecordTooLarge((self.message.len() + payload.len()) as u64, MAX_MESSAGE as u64))
+                    }
+                    self.message.extend_from_slice(&payload);
+                    if fin {
+                        let message = std::mem::take(&mut self.message);
+                        if let Err(e) = self.command(self.message_op, &message) {
+                            self.send_error(&e).map_err(Er::ServerTcpWrite)?;
+                        }
+                    }
+                },
+                OP_PING => self.send_frame(OP_PONG, &payload).map_err(Er::ServerTcpWrite)?,
+                OP_PONG => {},
+                OP_CLOSE => {
+                    self.send_frame(OP_CLOSE, &payload[..payload.len().min(2)]).map_err(Er::ServerTcpWrite)?;
+                    self.closed = true;
+                    return Ok(())
+                },
+                _ => return Err(Er::UnexpectedRecordType),
+            }
+        }
+        Ok(())
+    }
+
+    fn upgrade(&mut self) -> Result<(), Er> {
+        let end = match self.input.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None if self.input.len() > MAX_REQUEST => return self.refuse("400 Bad Request", "upgrade request is too long"),
+            None => return Ok(()),
+        };
+        let request = String::from_utf8_lossy(&self.input[..end]).into_owned();
+        self.input.drain(..end + 4);
+
+        let (key, format, origin) = match parse_upgrade(&request) {
+            Ok(upgrade) => upgrade,
+            Err(message) => return self.refuse("400 Bad Request", &message),
+        };
+        if let Some(origin) = origin {
+            if !self.config.allows_origin(&origin) {
+                return self.refuse("403 Forbidden", &format!("origin {} is not allowed", origin))
+            }
+        }
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key));
+        self.inner.write_all(response.as_bytes())
+            .map_err(Er::ServerTcpWrite)?;
+        self.upgraded = true;
+        self.format = format;
+        trace!("websocket upgraded, sending {:?}", format);
+
+        // tagged feeds, so one browser can follow many topics
+        let hello = Hello::local(0).request_frame(self.seq);
+        self.request(hello);
+
+        // frames sent straight after the request
+        self.receive()
+    }
+
+    fn refuse(&mut self, status : &str, message : &str) -> Result<(), Er> {
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}", status, message.len(), message);
+        let _ = self.inner.write_all(response.as_bytes());
+        self.closed = true;
+        Err(Er::ParseError(format!("websocket upgrade, {}", message)))
+    }
+
+    /* a whole message from the browser */
+    fn command(&mut self, op : u8, message : &[u8]) -> Result<(), Er> {
+        if op == OP_BINARY {
+            let topic = self.produce_to.clone().ok_or_else(|| Er::ParseError(String::from("binary message before {\"produce\" : topic}")))?;
+            return self.produce(&topic, Message::new(message))
+        }
+
+        let command : Command = serde_json::from_slice(message)
+            .map_err(|e| Er::ParseError(format!("websocket command, {}", e)))?;
+
+        if let Some(token) = command.auth {
+            self.auth(token)?;
+        }
+        if let Some(names) = command.follow {
+            if names.is_empty() {
+                return Err(Er::TopicNotFound)
+            }
+            let refs : Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+            let frame = metadata::request_frame(self.seq, &refs);
+            self.request(frame);
+            self.following = names;
+        }
+        if let Some(topic) = command.produce {
+            match command.value {
+                Some(value) => {
+                    let mut message = Message::new(value.as_bytes());
+                    message.key = command.key.map(|k| k.into_bytes());
+                    self.produce(&topic, message)?;
+                },
+                None => self.produce_to = Some(topic),
+            }
+        }
+        Ok(())
+    }
+
+    fn auth(&mut self, token : String) -> Result<(), Er> {
+        if self.token.is_some() {
+            return Err(Er::UnexpectedRecordType) // once per connection, as with any other client
+        }
+        match token.split_once(':') {
+            Some((name, secret)) => {
+                let (scram, frame) = ScramClient::start(self.seq, "", name, secret)?;
+                self.scram = Some(scram);
+                self.request(frame);
+            },
+            None => {
+                let content = format!(";{}", token);
+                let mut frame = Vec::with_capacity(6 + content.len());
+                frame.extend_from_slice(&(6 + content.len() as u32).to_le_bytes());
+                frame.push(self.seq);
+                frame.push(RecordType::Auth as u8);
+                frame.extend_from_slice(content.as_bytes());
+                self.request(frame);
+            },
+        }
+        self.token = Some(token);
+        Ok(())
+    }
+
+    /* passes the record to the topic's producer thread, the ack comes back as {"produced" ...} */
+    fn produce(&mut self, topic : &str, message : Message) -> Result<(), Er> {
+        if !self.config.produce {
+            return Err(Er::UnexpectedRecordType)
+        }
+        let token = self.token.clone().ok_or(Er::BadAuth)?;
+        let url = self.config.producer_url.clone().ok_or(Er::IsNone)?;
+
+        let producer = self.producers.entry(String::from(topic))
+            .or_insert_with(|| Producer::start(String::from(topic), url, token));
+        match producer.records.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Er::ClientTcpWrite(io::Error::new(io::ErrorKind::WouldBlock, "too many records waiting on the producer server"))),
+            Err(TrySendError::Disconnected(_)) => {
+                self.producers.remove(topic); // it has told the browser why, and is connected again next time
+                Err(Er::ClientTcpWrite(io::Error::from(io::ErrorKind::NotConnected)))
+            },
+        }
+    }
+
+    /* acks and errors the producer threads have passed back so far */
+    fn producer_replies(&mut self) -> io::Result<()> {
+        let mut replies = Vec::new();
+        self.producers.retain(|_, producer| loop {
+            match producer.replies.try_recv() {
+                Ok(reply) => replies.push(reply),
+                Err(TryRecvError::Empty) => break true,
+                Err(TryRecvError::Disconnected) => break false, // connected again next time
+            }
+        });
+        for reply in replies {
+            self.send_json(&reply)?;
+        }
+        Ok(())
+    }
+
+    /* a request for the consumer server, with the next sequence number already in it */
+    fn request(&mut self, frame : Vec<u8>) {
+        self.inbound.extend(frame);
+        self.seq = self.seq.wrapping_add(1);
+    }
+
+    /* a whole frame the consumer server has sent the browser */
+    fn reply(&mut self, frame : &[u8]) -> Result<(), Er> {
+        let mut buff = Buff::with_max(frame.len());
+        let mut unread = frame;
+        while !unread.is_empty() {
+            buff.read_data(&mut unread)?;
+        }
+        buff.rec_size = buff.read_u32();
+        let record_type = buff.read_u8().map(RecordType::from).ok_or(Er::IsNone)?;
+
+        match record_type {
+            RecordType::Error => {
+                let e = client::server_error(&mut buff);
+                self.send_error(&e).map_err(Er::ServerTcpWrite)?;
+            },
+            RecordType::ScramChallenge => {
+                let proof = self.scram.as_mut().ok_or(Er::UnexpectedRecordType)?.proof(self.seq, &mut buff)?;
+                self.request(proof);
+            },
+            RecordType::ScramOutcome => {
+                self.scram.take().ok_or(Er::UnexpectedRecordType)?.check_outcome(&mut buff)?;
+            },
+            RecordType::Metadata => {
+                let topics = metadata::from_reply(&mut buff)?;
+                let mut follow = vec![0, 0, 0, 0, self.seq, RecordType::ConsumerFollowTopics as u8];
+                for topic in topics {
+                    follow.extend_from_slice(&topic.topic_id.to_le_bytes());
+                    follow.extend_from_slice(&ALL_PARTITIONS.to_le_bytes());
+                    self.names.insert(topic.topic_id, topic.name);
+                }
+                let size = follow.len() as u32;
+                follow[0..4].copy_from_slice(&size.to_le_bytes());
+                self.request(follow);
+            },
+            RecordType::ConsumerFollowTopics => {
+                while buff.has_data() {
+                    let log = (buff.read_u32().ok_or(Er::IsNone)?, buff.read_u32().ok_or(Er::IsNone)?);
+                    let record_index = buff.read_u64().ok_or(Er::IsNone)?;
+                    let data_pos = buff.read_u64().ok_or(Er::IsNone)?;
+                    self.logs.insert(log, Messages::new(record_index, data_pos));
+                }
+                let following = std::mem::take(&mut self.following);
+                self.send_json(&json!({ "following" : following })).map_err(Er::ServerTcpWrite)?;
+            },
+            RecordType::DataFeed | RecordType::IndexFeed | RecordType::SegmentStart => {
+                let log = (buff.read_u32().ok_or(Er::IsNone)?, buff.read_u32().ok_or(Er::IsNone)?);
+                let messages = self.logs.get_mut(&log).ok_or(Er::PartitionNotFound(log.0, log.1))?;
+                match record_type {
+                    RecordType::DataFeed => messages.push_data(buff.data()),
+                    RecordType::IndexFeed => {
+                        while let Some(idx) = buff.read_u64() {
+                            messages.push_index(idx);
+                        }
+                    },
+                    _ => messages.push_segment(),
+                }
+                self.send_records(log)?;
+            },
+            // the handshake agreed what the gateway asked for
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /* every whole record the log's feeds have given so far */
+    fn send_records(&mut self, log : (u32, u32)) -> Result<(), Er> {
+        loop {
+            let messages = self.logs.get_mut(&log).ok_or(Er::PartitionNotFound(log.0, log.1))?;
+            let message = match messages.next().transpose()? {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+            let index = messages.next_index() - 1;
+
+            let sent = match self.format {
+                Format::Binary => self.send_frame(OP_BINARY, &message.value),
+                Format::Text => self.send_frame(OP_TEXT, String::from_utf8_lossy(&message.value).as_bytes()),
+                Format::Json => {
+                    let record = json!({
+                        "topic" : self.names.get(&log.0),
+                        "partition" : log.1,
+                        "index" : index,
+                        "key" : message.key.as_ref().map(|k| String::from_utf8_lossy(k)),
+                        "value" : String::from_utf8_lossy(&message.value),
+                    });
+                    self.send_json(&record)
+                },
+            };
+            sent.map_err(Er::ServerTcpWrite)?;
+        }
+    }
+
+    fn send_error(&mut self, e : &Er) -> io::Result<()> {
+        log_warn!("websocket : {}", e);
+        self.send_json(&error_json(e))
+    }
+
+    fn send_json(&mut self, value : &Value) -> io::Result<()> {
+        self.send_frame(OP_TEXT, value.to_string().as_bytes())
+    }
+
+    fn send_frame(&mut self, op : u8, payload : &[u8]) -> io::Result<()> {
+        if self.closed || !self.upgraded {
+            return Ok(())
+        }
+        self.inner.write_all(&frame(op, payload))
+    }
+}
+
+/* the consumer server reads requests from the browser, as the gateway has translated them */
+impl Read for WebSocket {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.inbound.is_empty() {
+                let size = buf.len().min(self.inbound.len());
+                for (b, byte) in buf.iter_mut().zip(self.inbound.drain(..size)) {
+                    *b = byte;
+                }
+                return Ok(size)
+            }
+            if self.closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "websocket closed"))
+            }
+
+            let mut chunk = [0u8; 4096];
+            let size = self.inner.read(&mut chunk)?;
+            if size == 0 {
+                return Ok(0)
+            }
+            self.input.extend_from_slice(&chunk[..size]);
+            self.receive()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+    }
+}
+
+/* and writes what it would send a client, which the gateway turns into messages */
+impl Write for WebSocket {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(buf.len()) // nothing more for the browser
+        }
+        self.outbound.extend_from_slice(buf);
+
+        while self.outbound.len() >= 4 {
+            let size = u32::from_le_bytes([self.outbound[0], self.outbound[1], self.outbound[2], self.outbound[3]]) as usize;
+            if self.outbound.len() < size {
+                break;
+            }
+            let frame : Vec<u8> = self.outbound.drain(..size).collect();
+            self.reply(&frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* Sec-WebSocket-Key, the format asked for and the Origin if there is one, or why the request can't be upgraded */
+fn parse_upgrade(request : &str) -> Result<(String, Format, Option<String>), String> {
+    let mut lines = request.split("\r\n");
+    let target = match lines.next().map(|l| l.split(' ').collect::<Vec<_>>()) {
+        Some(parts) if parts.len() == 3 && parts[0] == "GET" => String::from(parts[1]),
+        _ => return Err(String::from("expected a GET request")),
+    };
+
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), String::from(value.trim()));
+        }
+    }
+    let has = |name : &str, token : &str| headers.get(name)
+        .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false);
+    if !has("upgrade", "websocket") || !has("connection", "upgrade") {
+        return Err(String::from("expected Upgrade: websocket"))
+    }
+    if !has("sec-websocket-version", "13") {
+        return Err(String::from("expected Sec-WebSocket-Version: 13"))
+    }
+    let key = headers.get("sec-websocket-key").cloned().ok_or_else(|| String::from("missing Sec-WebSocket-Key"))?;
+
+    let query = target.split_once('?').map(|(_, q)| q).unwrap_or("");
+    let mut format = Format::Binary;
+    for param in query.split('&') {
+        format = match param {
+            "format=binary" => Format::Binary,
+            "format=text" => Format::Text,
+            "format=json" => Format::Json,
+            p if p.starts_with("format=") => return Err(format!("{} should be binary, text or json", p)),
+            _ => format,
+        };
+    }
+    Ok((key, format, headers.get("origin").cloned()))
+}
+
+fn accept_key(key : &str) -> String {
+    base64(&Sha1::new().chain_update(key.as_bytes()).chain_update(GUID.as_bytes()).finalize())
+}
+
+/* a frame from the browser, unmasked */
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin : bool,
+    op : u8,
+    payload : Vec<u8>,
+    size : usize, /* of the frame as it arrived */
+}
+
+/* the whole frame at the start of input, if there is one */
+fn parse_frame(input : &[u8]) -> Result<Option<Frame>, Er> {
+    if input.len() < 2 {
+        return Ok(None)
+    }
+    let fin = input[0] & 0x80 != 0;
+    let op = input[0] & 0x0f;
+    if input[1] & 0x80 == 0 {
+        return Err(Er::ParseError(String::from("websocket frames from browsers must be masked")))
+    }
+
+    let (len, mut pos) = match input[1] & 0x7f {
+        126 if input.len() >= 4 => (u16::from_be_bytes([input[2], input[3]]) as usize, 4),
+        127 if input.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&input[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        },
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > MAX_MESSAGE {
+        return Err(Er::RecordTooLarge(len as u64, MAX_MESSAGE as u64))
+    }
+    if input.len() < pos + 4 + len {
+        return Ok(None)
+    }
+
+    let mask = [input[pos], input[pos + 1], input[pos + 2], input[pos + 3]];
+    pos += 4;
+    let payload = input[pos..pos + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Ok(Some(Frame { fin, op, payload, size : pos + len }))
+}
+
+/* a whole, unmasked frame, as servers send them */
+fn frame(op : u8, payload : &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | op);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn error_json(e : &Er) -> Value {
+    match e {
+        Er::ServerError(code, message) => json!({ "error" : code, "message" : message }),
+        e => json!({ "error" : e.code(), "message" : e.to_string() }),
+    }
+}
+
+fn base64(bytes : &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* a frame as a browser sends it, masked */
+    fn masked(op : u8, payload : &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = frame(op, payload);
+        let start = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked : Vec<u8> = payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        frame.truncate(start);
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // the example in RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!((base64(b"f"), base64(b"fo"), base64(b"foo")), (String::from("Zg=="), String::from("Zm8="), String::from("Zm9v")));
+    }
+
+    #[test]
+    fn test_parse_upgrade() {
+        let request = "GET /topics?format=json HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13";
+        assert_eq!(parse_upgrade(request), Ok((String::from("dGhlIHNhbXBsZSBub25jZQ=="), Format::Json, None)));
+        assert_eq!(parse_upgrade(&request.replace("?format=json", "")).map(|(_, f, _)| f), Ok(Format::Binary));
+        let from_page = request.replace("Host: localhost", "Host: localhost\r\nOrigin: https://app.example");
+        assert_eq!(parse_upgrade(&from_page).map(|(_, _, o)| o), Ok(Some(String::from("https://app.example"))));
+
+        assert!(parse_upgrade(&request.replace("GET", "POST")).is_err());
+        assert!(parse_upgrade(&request.replace("Upgrade: websocket\r\n", "")).is_err());
+        assert!(parse_upgrade(&request.replace("Version: 13", "Version: 8")).is_err());
+        assert!(parse_upgrade(&request.replace("format=json", "format=xml")).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let long = vec![7u8; 70_000];
+        for payload in [&b"hello"[..], &[1u8; 300][..], &long[..]] {
+            let input = masked(OP_BINARY, payload);
+            assert_eq!(parse_frame(&input).unwrap(), Some(Frame { fin : true, op : OP_BINARY, payload : payload.to_vec(), size : input.len() }));
+            assert_eq!(parse_frame(&input[..input.len() - 1]).unwrap(), None, "not all there yet");
+        }
+        assert!(parse_frame(&frame(OP_TEXT, b"unmasked")).is_err(), "browsers mask what they send");
+        assert_eq!(&frame(OP_TEXT, b"hi")[..], &[0x81, 2, b'h', b'i'][..]);
+    }
+}
@@ -1,23 +1,305 @@
 #[cfg(test)]
 mod tests {
-    use redfoam::client::{Client,Listener};
+    use redfoam::client::{Client,Listener,GroupListener};
     use redfoam::tcp;
+    use redfoam::config::Config;
+    use redfoam::auth::SecretHash;
+    use redfoam::tcp::Start;
     use redfoam::er::Er;
+    use redfoam::record::Message;
     use redfoam::trace;
     use std::time::Duration;
     use std::thread;
     use std::str;
+    use std::net::TcpStream;
+    use std::io::{Read, Write};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
 
-    fn setup() {
+    /* a data folder of the test's own, removed when it is dropped */
+    struct TestFolder {
+        path : String,
+    }
+    impl TestFolder {
+        fn new(name : &str) -> TestFolder {
+            let path = std::env::temp_dir().join(format!("redfoam_{}_{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path); // left by an earlier process with the same id
+            std::fs::create_dir_all(&path).unwrap();
+            TestFolder { path : path.to_string_lossy().into_owned() }
+        }
+    }
+    impl Drop for TestFolder {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /* a local address nothing is listening on, for a test's servers */
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /* servers for the topic "test" in folder, returns the producer and consumer addresses */
+    fn setup(folder : &TestFolder) -> (String, String) {
+        let (producer_addr, consumer_addr) = (free_addr(), free_addr());
+        let config = Config::parse(&format!("node_id = 0\nproducer_addr = \"{}\"\nconsumer_addr = \"{}\"\ndata_dir = \"{}\"\n\
+            [[topics]]\ntopic_id = 1\ntopic_name = \"test\"\nreplication = 0\nfile_mask = 4\n", producer_addr, consumer_addr, folder.path), vec![]).unwrap();
+        let consumer_config = config.clone();
 
         thread::spawn(move || {
-            tcp::run_server(String::from("127.0.0.1:9090"));
+            tcp::run_server(&config);
         });
 
         thread::spawn(move || {
-            tcp::run_consumer_server(String::from("127.0.0.1:9091"));
+            tcp::run_consumer_server(&consumer_config);
         });
         thread::sleep(Duration::new(3,0));
+        (producer_addr, consumer_addr)
+    }
+
+#[test]
+    fn testacl () -> Result<(), Er> {
+        // servers of their own, with principals and rules
+        let folder = TestFolder::new("acl");
+        let folder = folder.path.as_str();
+        let (producer_addr, consumer_addr) = (free_addr(), free_addr());
+        let secret = SecretHash::new(b"pw", b"salt", 1000);
+        std::fs::write(format!("{}/credentials.toml", folder), format!("\
+            [[principals]]\nname = \"writer\"\nsecret = \"{}\"\n\
+            [[principals]]\nname = \"reader\"\nsecret = \"{}\"\n\
+            [[acls]]\nprincipal = \"writer\"\ntopic = \"secure*\"\noperations = [\"produce\"]\n\
+            [[acls]]\nprincipal = \"reader\"\ntopic = \"secured\"\noperations = [\"consume\"]\n", secret, secret)).unwrap();
+        let config = Config::parse(&format!("node_id = 0\nproducer_addr = \"{}\"\nconsumer_addr = \"{}\"\n\
+            data_dir = \"{}\"\ncredentials_file = \"{}/credentials.toml\"\n\
+            [[topics]]\ntopic_id = 1\ntopic_name = \"secured\"\nreplication = 0\nfile_mask = 4\n", producer_addr, consumer_addr, folder, folder), vec![])?;
+        let consumer_config = config.clone();
+        thread::spawn(move || tcp::run_server(&config));
+        thread::spawn(move || tcp::run_consumer_server(&consumer_config));
+        thread::sleep(Duration::new(3,0));
+
+        let mut writer = Client::new(String::from("secured"), producer_addr.clone(), String::from("writer:pw")).unwrap();
+        writer.send(String::from("allowed")).expect("sending as writer failed");
+        assert!(writer.ack().is_ok(), "writer may produce");
+
+        let denied = Er::AccessDenied(String::new(), redfoam::acl::Operation::Produce, String::new()).code();
+        let mut reader = Client::new(String::from("secured"), producer_addr.clone(), String::from("reader:pw")).unwrap();
+        reader.send(String::from("denied")).expect("sending as reader failed");
+        match reader.ack() {
+            Err(Er::ServerError(code, _)) => assert_eq!(code, denied),
+            x => assert!(false, "reader may not produce, got {:?}", x.map_err(|e| e.to_string())),
+        }
+
+        let mut listener = Listener::starting_at(String::from("secured"), 0, Start::Earliest, consumer_addr.clone(), String::from("reader:pw"))?;
+        let mut first = None;
+        for _ in 0..100 {
+            first = listener.next()?.map(|r| r.message.value);
+            if first.is_some() { break; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(first, Some(b"allowed".to_vec()), "reader may consume, and only the allowed record was written");
+
+        match Listener::new(String::from("secured"), consumer_addr.clone(), String::from("writer:pw")) {
+            Err(Er::ServerError(code, _)) => assert_eq!(code, denied),
+            _ => assert!(false, "writer may not consume"),
+        }
+        match Client::new(String::from("secured"), producer_addr.clone(), String::from("ANON")) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+            Ok(_) => assert!(false, "anonymous connections are not allowed by the credentials file"),
+        }
+        match Client::new(String::from("secured"), producer_addr.clone(), String::from("writer:wrong")) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+            Ok(_) => assert!(false, "the proof for a wrong secret is refused"),
+        }
+        Ok(())
+    }
+
+    /* a CA, then a certificate it signs for the servers and one for each client named, as PEM files in folder */
+    fn write_certificates(folder : &str, clients : &[&str]) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca = CertificateParams::new(Vec::new()).unwrap();
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca.distinguished_name.push(DnType::CommonName, "redfoam test ca");
+        let ca_cert = ca.self_signed(&ca_key).unwrap();
+        std::fs::write(format!("{}/ca.pem", folder), ca_cert.pem()).unwrap();
+
+        let server = ("server", ExtendedKeyUsagePurpose::ServerAuth);
+        for (name, purpose) in clients.iter().map(|c| (*c, ExtendedKeyUsagePurpose::ClientAuth)).chain(std::iter::once(server)) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![String::from("127.0.0.1")]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![purpose];
+            let cert = params.signed_by(&key, &ca_cert, &ca_key).unwrap();
+            std::fs::write(format!("{}/{}.pem", folder, name), cert.pem()).unwrap();
+            std::fs::write(format!("{}/{}.key", folder, name), key.serialize_pem()).unwrap();
+        }
+    }
+
+#[test]
+    fn testtls () -> Result<(), Er> {
+        // both listeners encrypted, client certificates are checked when they are offered
+        let folder = TestFolder::new("tls");
+        let folder = folder.path.as_str();
+        let (producer_addr, consumer_addr) = (free_addr(), free_addr());
+        write_certificates(folder, &["writer"]);
+        std::fs::write(format!("{}/credentials.toml", folder), format!("\
+            [[principals]]\nname = \"reader\"\nsecret = \"{}\"\n\
+            [[acls]]\nprincipal = \"writer\"\ntopic = \"encrypted\"\noperations = [\"produce\"]\n\
+            [[acls]]\nprincipal = \"reader\"\ntopic = \"encrypted\"\noperations = [\"consume\"]\n", SecretHash::new(b"pw", b"salt", 1000))).unwrap();
+        let tls = format!("cert_file = \"{0}/server.pem\"\nkey_file = \"{0}/server.key\"\nclient_ca_file = \"{0}/ca.pem\"\nclient_cert_optional = true\n", folder);
+        let config = Config::parse(&format!("node_id = 0\nproducer_addr = \"{2}\"\nconsumer_addr = \"{3}\"\n\
+            data_dir = \"{0}\"\ncredentials_file = \"{0}/credentials.toml\"\n\
+            [producer_tls]\n{1}[consumer_tls]\n{1}\
+            [[topics]]\ntopic_id = 1\ntopic_name = \"encrypted\"\nreplication = 0\nfile_mask = 4\n", folder, tls, producer_addr, consumer_addr), vec![])?;
+        let consumer_config = config.clone();
+        thread::spawn(move || tcp::run_server(&config));
+        thread::spawn(move || tcp::run_consumer_server(&consumer_config));
+        thread::sleep(Duration::new(3,0));
+
+        // the writer is who its certificate says, with no secret of its own
+        let writer_url = format!("tls://{1}?ca={0}/ca.pem&cert={0}/writer.pem&key={0}/writer.key", folder, producer_addr);
+        let mut writer = Client::new(String::from("encrypted"), writer_url, String::from("ANON")).unwrap();
+        for i in 0..50 {
+            writer.send(format!("secret message {}", i)).expect("sending over tls failed");
+        }
+        for _ in 0..50 {
+            writer.ack()?;
+        }
+
+        // the reader has no certificate and proves its secret instead, its feed can't use sendfile
+        let reader_url = format!("tls://{}?ca={}/ca.pem", consumer_addr, folder);
+        let mut listener = Listener::starting_at(String::from("encrypted"), 0, Start::Earliest, reader_url, String::from("reader:pw"))?;
+        let mut received = Vec::new();
+        for _ in 0..200 {
+            match listener.next()? {
+                Some(r) => received.push(String::from_utf8(r.message.value).unwrap()),
+                None => thread::sleep(Duration::from_millis(20)),
+            }
+            if received.len() == 50 { break; }
+        }
+        assert_eq!(received.len(), 50, "every record comes through the encrypted feed");
+        assert_eq!(received[49], "secret message 49");
+
+        // anonymous without a certificate is still refused, and plain TCP gets nowhere
+        match Client::new(String::from("encrypted"), format!("tls://{}?ca={}/ca.pem", producer_addr, folder), String::from("ANON")) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+            Ok(_) => assert!(false, "anonymous connections are not allowed by the credentials file"),
+        }
+        assert!(Client::new(String::from("encrypted"), producer_addr.clone(), String::from("reader:pw")).is_err(), "tls listeners don't speak plain tcp");
+        Ok(())
+    }
+
+    /* a websocket frame as browsers send it, masked */
+    fn ws_send(ws : &mut TcpStream, op : u8, payload : &[u8]) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | op];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => { frame.push(0x80 | 126); frame.extend_from_slice(&(len as u16).to_be_bytes()); },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        ws.write_all(&frame).unwrap();
+    }
+
+    /* the next whole frame from the gateway, opcode and payload */
+    fn ws_read(ws : &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        ws.read_exact(&mut head).unwrap();
+        let len = match head[1] {
+            126 => { let mut len = [0u8; 2]; ws.read_exact(&mut len).unwrap(); u16::from_be_bytes(len) as usize },
+            127 => { let mut len = [0u8; 8]; ws.read_exact(&mut len).unwrap(); u64::from_be_bytes(len) as usize },
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        ws.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    fn ws_json(ws : &mut TcpStream) -> serde_json::Value {
+        let (op, payload) = ws_read(ws);
+        assert_eq!(op, 1, "commands are answered with text messages");
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+#[test]
+    fn testwebsocket () -> Result<(), Er> {
+        // a browser follows a topic through the gateway, and produces to it
+        let folder = TestFolder::new("ws");
+        let (producer_addr, consumer_addr, ws_addr) = (free_addr(), free_addr(), free_addr());
+        let config = Config::parse(&format!("node_id = 0\nproducer_addr = \"{}\"\nconsumer_addr = \"{}\"\n\
+            data_dir = \"{}\"\n[websocket]\naddr = \"{}\"\nproduce = true\nallowed_origins = [\"https://app.example\"]\n\
+            [[topics]]\ntopic_id = 1\ntopic_name = \"browser\"\nreplication = 0\nfile_mask = 4\n", producer_addr, consumer_addr, folder.path, ws_addr), vec![])?;
+        let consumer_config = config.clone();
+        thread::spawn(move || tcp::run_server(&config));
+        thread::spawn(move || tcp::run_consumer_server(&consumer_config));
+        thread::sleep(Duration::new(3,0));
+
+        // the key and accept are the example in RFC 6455
+        let mut ws = TcpStream::connect(&ws_addr).unwrap();
+        ws.set_read_timeout(Some(Duration::new(10,0))).unwrap();
+        ws.write_all(format!("GET /?format=json HTTP/1.1\r\nHost: {}\r\nOrigin: https://app.example\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", ws_addr).as_bytes()).unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            ws.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", response);
+
+        // records already in the topic, which indexes carry on from
+        let mut producer = Client::new(String::from("browser"), producer_addr.clone(), String::from("ANON")).unwrap();
+        for i in 0..4 {
+            producer.send(format!("before the browser {}", i)).expect("sending to the topic failed");
+            assert_eq!(producer.ack()?, i);
+        }
+
+        ws_send(&mut ws, 1, br#"{"auth" : "ANON", "follow" : ["browser"]}"#);
+        assert_eq!(ws_json(&mut ws), serde_json::json!({ "following" : ["browser"] }));
+
+        // records from other producers, then from the browser, whose ack comes back too
+        producer.send_message(&Message::new(b"from a producer").with_key(b"p")).expect("sending to the topic failed");
+        producer.ack()?;
+        assert_eq!(ws_json(&mut ws), serde_json::json!({ "topic" : "browser", "partition" : 0, "index" : 4, "key" : "p", "value" : "from a producer" }));
+
+        ws_send(&mut ws, 1, br#"{"produce" : "browser", "value" : "from a browser"}"#);
+        let mut replies = vec![ws_json(&mut ws), ws_json(&mut ws)];
+        replies.sort_by_key(|r| r.to_string());
+        assert_eq!(replies, vec![
+            serde_json::json!({ "topic" : "browser", "partition" : 0, "index" : 5, "key" : null, "value" : "from a browser" }),
+            serde_json::json!({ "produced" : "browser", "index" : 5 }),
+        ]);
+
+        // mistakes are reported and the connection carries on
+        ws_send(&mut ws, 1, b"{not json");
+        assert_eq!(ws_json(&mut ws)["error"], Er::ParseError(String::new()).code());
+        ws_send(&mut ws, 1, br#"{"follow" : ["missing"]}"#);
+        assert!(ws_json(&mut ws)["error"].is_u64(), "unknown topics can't be followed");
+
+        ws_send(&mut ws, 9, b"still there?");
+        assert_eq!(ws_read(&mut ws), (10, b"still there?".to_vec()), "pings are answered");
+        ws_send(&mut ws, 8, &1000u16.to_be_bytes());
+        assert_eq!(ws_read(&mut ws), (8, 1000u16.to_be_bytes().to_vec()), "close is echoed");
+
+        // plain http is turned away
+        let mut http = TcpStream::connect(&ws_addr).unwrap();
+        http.set_read_timeout(Some(Duration::new(10,0))).unwrap();
+        http.write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", ws_addr).as_bytes()).unwrap();
+        let mut refused = String::new();
+        let _ = http.read_to_string(&mut refused);
+        assert!(refused.starts_with("HTTP/1.1 400"), "{}", refused);
+
+        // as are pages that aren't allowed
+        let mut elsewhere = TcpStream::connect(&ws_addr).unwrap();
+        elsewhere.set_read_timeout(Some(Duration::new(10,0))).unwrap();
+        elsewhere.write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\nOrigin: https://elsewhere.example\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", ws_addr).as_bytes()).unwrap();
+        let mut refused = String::new();
+        let _ = elsewhere.read_to_string(&mut refused);
+        assert!(refused.starts_with("HTTP/1.1 403"), "{}", refused);
+        Ok(())
     }
 
 #[ignore]
@@ -32,11 +314,12 @@ mod tests {
 #[ignore]
 #[test]
     fn readsomething () -> Result<(), Er> {
-        setup();
+        let folder = TestFolder::new("readsomething");
+        let (producer_addr, consumer_addr) = setup(&folder);
         trace!("test : creating producer");
-        let mut producer = Client::new(String::from("test"), String::from("127.0.0.1:9090"), String::from("ANON")).unwrap();
+        let mut producer = Client::new(String::from("test"), producer_addr.clone(), String::from("ANON")).unwrap();
         trace!("test : creating producer");
-        let mut consumer = Client::new(String::from("test"), String::from("127.0.0.1:9091"), String::from("ANON")).unwrap();
+        let mut consumer = Client::new(String::from("test"), consumer_addr.clone(), String::from("ANON")).unwrap();
 
         println!("test : following  topic");
         consumer.follow_topic(1);
@@ -82,10 +365,11 @@ mod tests {
 
 #[test]
     fn testlistener () -> Result<(), Er> {
-        setup();
-        let mut producer = Client::new(String::from("test"), String::from("127.0.0.1:9090"), String::from("ANON")).unwrap();
+        let folder = TestFolder::new("listener");
+        let (producer_addr, consumer_addr) = setup(&folder);
+        let mut producer = Client::new(String::from("test"), producer_addr.clone(), String::from("ANON")).unwrap();
         trace!("test : created producer");
-        let mut consumer = Listener::new(String::from("test"), String::from("127.0.0.1:9091"), String::from("ANON")).unwrap();
+        let mut consumer = Listener::new(String::from("test"), consumer_addr.clone(), String::from("ANON")).unwrap();
         trace!("test : created listener");
 
 
@@ -93,13 +377,164 @@ mod tests {
         trace!("test : sent alphabet soup");
         thread::sleep(Duration::new(1,0));
 
-        let x = consumer.next();
+        let x = consumer.next()?;
         trace!("test : got next record");
         assert!(x.is_some(), "consumer should return a record!");
         let y = &x.unwrap();
-        let message = str::from_utf8(y).unwrap();
+        assert_eq!((y.topic_id, y.partition), (1, 0), "should say where the record is from");
+        let message = str::from_utf8(&y.message.value).unwrap();
         assert_eq!(message, "alphabet soup");
 
+        let with_headers = Message::new(b"{}").with_key(b"order-1").with_header("content-type", b"application/json");
+        producer.send_message(&with_headers).expect("sending message with headers failed");
+        thread::sleep(Duration::new(1,0));
+
+        let received = consumer.next()?.map(|r| r.message);
+        assert_eq!(received, Some(with_headers), "key and headers should come back with the value");
+
+        // far larger than a client buffer, so it arrives over many feed frames
+        let document = format!("{{\"items\":[{}]}}", vec!["\"abcdefghijklmnopqrstuvwxyz\""; 7000].join(","));
+        producer.send(document.clone()).expect("sending large document failed");
+
+        let mut large = None;
+        for _ in 0..100 {
+            large = consumer.next()?.map(|r| r.message);
+            if large.is_some() { break; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(large.map(|m| m.value.len()), Some(document.len()), "large record should come back whole");
+
+        // over the default topic limit, which clients know from the handshake
+        let mut checked = Client::new(String::from("test"), producer_addr.clone(), String::from("ANON")).unwrap();
+        assert_eq!(checked.server_hello().map(|h| h.version), Some(redfoam::hello::PROTOCOL_VERSION), "handshake should have been answered");
+        match checked.send(String::from_utf8(vec![b'x'; 2 * 1024 * 1024]).unwrap()) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            Ok(_) => assert!(false, "client should refuse a record over the server's limit"),
+        }
+        checked.send(String::from("after the refused record")).expect("sending after refusal failed");
+        assert!(checked.ack().is_ok(), "connection should still take records");
+
+        // clients from before the handshake don't know the limit, so the server rejects with an error record and keeps the connection
+        let mut legacy = TcpStream::connect(&producer_addr).unwrap();
+        let frame = |seq : u8, rec_type : u8, body : &[u8]| {
+            let mut frame = (6 + body.len() as u32).to_le_bytes().to_vec();
+            frame.extend_from_slice(&[seq, rec_type]);
+            frame.extend_from_slice(body);
+            frame
+        };
+        let mut oversize = 1u32.to_le_bytes().to_vec();
+        oversize.extend_from_slice(&vec![b'x'; 2 * 1024 * 1024]);
+        legacy.write_all(&frame(0, tcp::RecordType::Auth as u8, b"test;ANON")).unwrap();
+        legacy.write_all(&frame(1, tcp::RecordType::Producer as u8, &oversize)).unwrap();
+        let mut error = [0u8; 7];
+        legacy.read_exact(&mut error).unwrap();
+        assert_eq!((error[4], u16::from_le_bytes([error[5], error[6]])), (tcp::RecordType::Error as u8, Er::RecordTooLarge(0, 0).code()));
+        let mut message = vec![0u8; u32::from_le_bytes([error[0], error[1], error[2], error[3]]) as usize - 7];
+        legacy.read_exact(&mut message).unwrap();
+
+        let mut small = 1u32.to_le_bytes().to_vec();
+        small.extend_from_slice(b"after the rejected record");
+        legacy.write_all(&frame(2, tcp::RecordType::Producer as u8, &small)).unwrap();
+        let mut ack = [0u8; 6];
+        legacy.read_exact(&mut ack).unwrap();
+        assert_eq!((ack[4], ack[5]), (tcp::RecordType::Ack as u8, 2), "connection should still take records");
+
+        // listeners starting in the past are sent the history before anything new
+        let first = |start : Start| -> Result<Option<Message>, Er> {
+            let mut replay = Listener::starting_at(String::from("test"), 0, start, consumer_addr.clone(), String::from("ANON"))?;
+            for _ in 0..100 {
+                if let Some(r) = replay.next()? { return Ok(Some(r.message)) }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Ok(None)
+        };
+        assert_eq!(first(Start::Earliest)?.map(|m| m.value), Some(b"alphabet soup".to_vec()), "earliest should replay from the first record");
+        assert_eq!(first(Start::Index(1))?, Some(Message::new(b"{}").with_key(b"order-1").with_header("content-type", b"application/json")));
+
+        // a group member that committed the first record carries on from the second when it reconnects
+        let group_next = |listener : &mut Listener| -> Result<Option<Message>, Er> {
+            for _ in 0..100 {
+                if let Some(r) = listener.next()? { return Ok(Some(r.message)) }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Ok(None)
+        };
+        let mut member = Listener::in_group(String::from("test"), 0, "readers", Start::Earliest, consumer_addr.clone(), String::from("ANON"))?;
+        assert_eq!(group_next(&mut member)?.map(|m| m.value), Some(b"alphabet soup".to_vec()), "new groups use their start");
+        member.commit()?;
+        thread::sleep(Duration::from_millis(200));
+        drop(member);
+
+        let mut member = Listener::in_group(String::from("test"), 0, "readers", Start::Earliest, consumer_addr.clone(), String::from("ANON"))?;
+        assert_eq!(group_next(&mut member)?.map(|m| m.value), Some(b"{}".to_vec()), "should resume after the commit");
+
+        // the one partition goes to the first member, and moves to the second when the first leaves
+        let join = || GroupListener::join(String::from("test"), "rebalance", Start::Earliest, Duration::from_secs(10), consumer_addr.clone(), String::from("ANON"));
+        let first_member = join()?;
+        assert_eq!(first_member.assignment(), vec![0]);
+        let mut second_member = join()?;
+        assert!(second_member.assignment().is_empty(), "nothing left for the second member");
+        drop(first_member);
+
+        let mut taken_over = None;
+        for _ in 0..100 {
+            taken_over = second_member.next()?;
+            if taken_over.is_some() { break; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(second_member.assignment(), vec![0], "partition should move once its member has gone");
+        assert_eq!(taken_over.map(|r| (r.partition, r.message.value)), Some((0, b"alphabet soup".to_vec())));
+
+        // while the group has members, commits from outside its membership are refused
+        let mut outsider = Listener::in_group(String::from("test"), 0, "rebalance", Start::Earliest, consumer_addr.clone(), String::from("ANON"))?;
+        assert!(group_next(&mut outsider)?.is_some());
+        outsider.commit()?;
+        let mut refused = None;
+        for _ in 0..100 {
+            match outsider.next() {
+                Err(Er::ServerError(code, _)) => { refused = Some(code); break; },
+                _ => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        assert_eq!(refused, Some(Er::NotPartitionOwner(0).code()));
+
+        // one connection for several topics, each record tagged with where it is from
+        let mut aggregator = Listener::for_topics(&["test"], consumer_addr.clone(), String::from("ANON"))?;
+        assert_eq!(aggregator.logs(), vec![(1, 0)], "every partition of the topic is followed");
+        producer.send(String::from("for the aggregator")).expect("sending to aggregator failed");
+        let mut tagged = None;
+        for _ in 0..100 {
+            tagged = aggregator.next()?;
+            if tagged.is_some() { break; }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(tagged.map(|r| (r.topic_id, r.message.value)), Some((1, b"for the aggregator".to_vec())));
+
+        match Listener::follow(&[(1, 0), (99, 0)], String::from("test"), consumer_addr.clone(), String::from("ANON")) {
+            Err(Er::ServerError(code, _)) => assert_eq!(code, Er::TopicNotFound.code()),
+            _ => assert!(false, "following an unknown topic should fail"),
+        }
+
+        // topics are named by clients, both servers say what they are called
+        let mut named = Client::new(String::from("test"), producer_addr.clone(), String::from("ANON")).unwrap();
+        let topics = named.metadata(&[])?;
+        assert_eq!(topics.iter().map(|t| (t.topic_id, t.partitions, t.name.as_str())).collect::<Vec<_>>(), vec![(1, 1, "test")]);
+        assert_eq!(named.topic_id(), 1, "producer sends to the topic it named");
+        match Listener::new(String::from("missing"), consumer_addr.clone(), String::from("ANON")) {
+            Err(Er::ServerError(code, _)) => assert_eq!(code, Er::UnknownTopic(String::new()).code()),
+            _ => assert!(false, "listening to a topic that does not exist should fail"),
+        }
+        match Client::new(String::from("missing"), producer_addr.clone(), String::from("ANON")) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            Ok(_) => assert!(false, "producing to a topic that does not exist should fail"),
+        }
+
+        // without a credentials file only anonymous connections are let in
+        match Client::new(String::from("test"), producer_addr.clone(), String::from("billing:hunter2")) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+            Ok(_) => assert!(false, "unknown principal should be refused"),
+        }
+
         Ok(())
     }
 }